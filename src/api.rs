@@ -1,4 +1,9 @@
-use rustc_serialize::Decodable;
+use std::i32;
+use std::ptr;
+
+use libc;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
 use rustc_serialize::json;
 
 fn maybe_extract_from_json_object<T: Decodable>(
@@ -81,6 +86,167 @@ impl CoverUrls {
         let mut decoder = json::Decoder::new(json);
         Decodable::decode(&mut decoder).ok().unwrap()
     }
+
+    /// The url of the smallest variant that is at least `pixels` wide and high, the original if
+    /// none is big enough. Variants without a url are skipped, None if there are none at all.
+    pub fn for_size(&self, pixels: i32) -> Option<&str> {
+        let variants = [(56, &self.sq56), (100, &self.sq100), (133, &self.sq133),
+                        (250, &self.sq250), (500, &self.sq500), (1024, &self.max1024),
+                        (i32::MAX, &self.original)];
+        for &(size, url) in variants.iter() {
+            if size >= pixels && !url.is_empty() {
+                return Some(&url[..]);
+            }
+        }
+        // Nothing big enough, the biggest we have has to do.
+        for &(_, url) in variants.iter().rev() {
+            if !url.is_empty() {
+                return Some(&url[..]);
+            }
+        }
+        None
+    }
+}
+
+#[derive(RustcDecodable, Clone, PartialEq, Eq, Debug)]
+pub struct Tag {
+    pub name: String,
+}
+
+impl Tag {
+    /// The smart id of the mixes with this tag.
+    pub fn smart_id(&self) -> String {
+        format!("tags:{}", self.name.replace(" ", "_"))
+    }
+}
+
+/// The tags of a mix come as one comma separated string.
+pub fn parse_tag_list(tag_list: &str) -> Vec<Tag> {
+    tag_list.split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .map(|t| Tag { name: t.to_string() })
+            .collect()
+}
+
+/// A point in time with second precision, in seconds since the Unix epoch (UTC).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Timestamp {
+    pub secs: i64,
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of days_from_civil.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                       - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp { secs: unsafe { libc::time(ptr::null_mut()) } as i64 }
+    }
+
+    /// Parses ISO 8601 timestamps like the API's `2014-05-01T12:34:56Z`. Fractional seconds
+    /// are dropped, a missing zone means UTC.
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        let s = s.trim();
+        let bytes = s.as_bytes();
+        if bytes.len() < 19 || !bytes[..19].iter().all(|&b| b < 128) {
+            return None;
+        }
+        if bytes[4] != b'-' || bytes[7] != b'-' || (bytes[10] != b'T' && bytes[10] != b' ') ||
+                bytes[13] != b':' || bytes[16] != b':' {
+            return None;
+        }
+        let number = |from: usize, to: usize| s[from..to].parse::<i64>().ok();
+        let (year, month, day, hour, minute, second) =
+            match (number(0, 4), number(5, 7), number(8, 10),
+                   number(11, 13), number(14, 16), number(17, 19)) {
+                (Some(y), Some(mo), Some(d), Some(h), Some(mi), Some(se)) => (y, mo, d, h, mi, se),
+                _ => return None,
+            };
+        if month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 || minute > 59 ||
+                second > 60 {
+            return None;
+        }
+
+        let mut rest = &s[19..];
+        if rest.starts_with(".") {
+            let digits = rest[1..].find(|c: char| !c.is_digit(10)).unwrap_or(rest.len() - 1);
+            rest = &rest[1 + digits..];
+        }
+        let offset = match rest {
+            "" | "Z" => 0,
+            _ if rest.starts_with("+") || rest.starts_with("-") => {
+                let zone = rest[1..].replace(":", "");
+                // Checked as bytes, slicing the hours off anything else could split a character.
+                if zone.len() != 4 || !zone.bytes().all(|b| b >= b'0' && b <= b'9') {
+                    return None;
+                }
+                let minutes = match (zone[..2].parse::<i64>(), zone[2..].parse::<i64>()) {
+                    (Ok(h), Ok(m)) => h * 60 + m,
+                    _ => return None,
+                };
+                if rest.starts_with("-") { -minutes * 60 } else { minutes * 60 }
+            }
+            _ => return None,
+        };
+
+        let days = days_from_civil(year, month, day);
+        Some(Timestamp { secs: days * 86400 + hour * 3600 + minute * 60 + second - offset })
+    }
+
+    fn days(&self) -> i64 {
+        if self.secs >= 0 { self.secs / 86400 } else { (self.secs - 86399) / 86400 }
+    }
+
+    /// The UTC date as `YYYY-MM-DD`.
+    pub fn date_string(&self) -> String {
+        let (year, month, day) = civil_from_days(self.days());
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+    /// ISO 8601 in UTC, like `2014-05-01T12:34:56Z`.
+    pub fn iso_string(&self) -> String {
+        let secs_of_day = self.secs - self.days() * 86400;
+        format!("{}T{:02}:{:02}:{:02}Z", self.date_string(),
+                secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+    }
+}
+
+impl Encodable for Timestamp {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_str(&self.iso_string()[..])
+    }
+}
+
+impl Decodable for Timestamp {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Timestamp, D::Error> {
+        let s = try!(d.read_str());
+        match Timestamp::parse(&s[..]) {
+            Some(t) => Ok(t),
+            None => Err(d.error(&format!("invalid timestamp `{}`", s)[..])),
+        }
+    }
 }
 
 #[derive(RustcDecodable, Clone)]
@@ -93,16 +259,17 @@ pub struct Mix {
     pub plays_count: u32,
     pub likes_count: u32,
     pub certification: Option<String>,
-    // TODO parse this...
-    pub tag_list_cache: String,
+    pub tags: Vec<Tag>,
     pub duration: u32,
     pub tracks_count: u32,
     pub nsfw: bool,
     pub liked_by_current_user: bool,
     pub cover_urls: CoverUrls,
-    // TODO parse this...
-    pub first_published_at: String,
+    // None for mixes that were never published.
+    pub first_published_at: Option<Timestamp>,
     pub user_id: u32,
+    // The creator's name, from the `user` object the mix comes with.
+    pub user_login: Option<String>,
 }
 
 impl Mix {
@@ -117,14 +284,17 @@ impl Mix {
             plays_count: extract_from_json_object(obj, &"plays_count".to_string()),
             likes_count: extract_from_json_object(obj, &"likes_count".to_string()),
             certification: maybe_extract_from_json_object(obj, &"certification".to_string()),
-            tag_list_cache: maybe_extract_from_json_object(obj, &"tags_list_cache".to_string()).unwrap_or_default(),
+            tags: parse_tag_list(&maybe_extract_from_json_object::<String>(
+                obj, &"tag_list_cache".to_string()).unwrap_or_default()[..]),
             duration: extract_from_json_object(obj, &"duration".to_string()),
             tracks_count: extract_from_json_object(obj, &"tracks_count".to_string()),
             nsfw: maybe_extract_from_json_object(obj, &"nsfw".to_string()).unwrap_or_default(),
             liked_by_current_user: maybe_extract_from_json_object(obj, &"liked_by_current_user".to_string()).unwrap_or_default(),
             cover_urls: CoverUrls::from_json(obj.get(&"cover_urls".to_string()).unwrap().clone()),
-            first_published_at: extract_from_json_object(obj, &"first_published_at".to_string()),
+            first_published_at: maybe_extract_from_json_object(obj, &"first_published_at".to_string()),
             user_id: extract_from_json_object(obj, &"user_id".to_string()),
+            user_login: obj.get(&"user".to_string()).and_then(|u| u.find("login"))
+                           .and_then(|l| l.as_string()).map(|l| l.to_string()),
         }
     }
 }
@@ -158,7 +328,7 @@ impl MixSet {
     }
 }
 
-#[derive(Clone, RustcDecodable)]
+#[derive(Clone, RustcDecodable, RustcEncodable)]
 pub struct Track {
     pub id: u32,
     pub name: String,
@@ -177,7 +347,8 @@ pub struct PlayState {
     pub at_last_track: bool,
     pub at_end: bool,
     pub skip_allowed: bool,
-    pub track: Track,
+    // Missing once we're at the end of the mix.
+    pub track: Option<Track>,
 }
 
 impl PlayState {
@@ -193,6 +364,11 @@ pub fn parse_mix_set_response(json: &json::Json) -> Response<MixSet> {
     Response::from_json(json, mix_set)
 }
 
+/// For requests where only the status matters.
+pub fn parse_status_response(json: &json::Json) -> Response<()> {
+    Response::from_json(json, Some(()))
+}
+
 pub fn parse_play_token_response(json: &json::Json) -> Response<PlayToken> {
     let obj = expect_json_object(json);
     let pt = maybe_extract_from_json_object(obj, &"play_token".to_string()).map(|pt| PlayToken { s: pt });
@@ -205,3 +381,108 @@ pub fn parse_play_state_response(json: &json::Json) -> Response<PlayState> {
     let ps = obj.get(&"set".to_string()).map(|set| PlayState::from_json(set.clone()));
     Response::from_json(json, ps)
 }
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+
+    use super::*;
+
+    #[test]
+    fn status_responses_carry_status_and_errors() {
+        let ok = json::Json::from_str(r#"{"status": "200 OK", "errors": null,
+                                         "notices": null, "api_version": 3}"#).unwrap();
+        let response = parse_status_response(&ok);
+        assert_eq!(response.status, "200 OK");
+        assert_eq!(response.errors, None);
+        assert!(!response.logged_in);
+        assert_eq!(response.api_version, 3);
+        assert!(response.contents.is_some());
+
+        let denied = json::Json::from_str(r#"{"status": "401 Unauthorized",
+                                             "errors": "You must be logged in",
+                                             "logged_in": false, "api_version": 3}"#).unwrap();
+        let response = parse_status_response(&denied);
+        assert_eq!(response.status, "401 Unauthorized");
+        assert_eq!(response.errors, Some("You must be logged in".to_string()));
+
+        // Errors that aren't a plain string are left out, the status still tells what happened.
+        let listed = json::Json::from_str(r#"{"status": "422 Unprocessable Entity",
+                                             "errors": ["bad track"], "api_version": 3}"#).unwrap();
+        assert_eq!(parse_status_response(&listed).errors, None);
+    }
+
+    #[test]
+    fn tag_list_is_split_and_trimmed() {
+        let tags = parse_tag_list("folk, indie rock,,acoustic ");
+        let names: Vec<&str> = tags.iter().map(|t| &t.name[..]).collect();
+        assert_eq!(names, vec!["folk", "indie rock", "acoustic"]);
+        assert_eq!(tags[1].smart_id(), "tags:indie_rock");
+        assert!(parse_tag_list("").is_empty());
+    }
+
+    #[test]
+    fn cover_urls_are_picked_by_size() {
+        let urls = CoverUrls {
+            sq56: "56".to_string(),
+            sq100: "100".to_string(),
+            sq133: "133".to_string(),
+            max133w: "133w".to_string(),
+            max200: "200".to_string(),
+            sq250: "250".to_string(),
+            sq500: String::new(),
+            max1024: "1024".to_string(),
+            original: "original".to_string(),
+        };
+        assert_eq!(urls.for_size(133), Some("133"));
+        assert_eq!(urls.for_size(266), Some("1024"));
+        assert_eq!(urls.for_size(250), Some("250"));
+        assert_eq!(urls.for_size(2048), Some("original"));
+        let urls = CoverUrls { original: String::new(), ..urls };
+        assert_eq!(urls.for_size(2048), Some("1024"));
+        let none = CoverUrls {
+            sq56: String::new(),
+            sq100: String::new(),
+            sq133: String::new(),
+            max133w: String::new(),
+            max200: String::new(),
+            sq250: String::new(),
+            sq500: String::new(),
+            max1024: String::new(),
+            original: String::new(),
+        };
+        assert_eq!(none.for_size(133), None);
+    }
+
+    #[test]
+    fn mixes_know_their_creator() {
+        let data = r#"{"user": {"id": 42, "login": "someone"},
+            "id": 7, "path": "/mixes/7", "web_path": "/someone/a-mix", "name": "A Mix",
+            "description": "", "plays_count": 3, "likes_count": 1, "tag_list_cache": "folk",
+            "duration": 3600, "tracks_count": 12, "first_published_at": null, "user_id": 42,
+            "cover_urls": {"sq56": "", "sq100": "", "sq133": "", "max133w": "", "max200": "",
+                           "sq250": "", "sq500": "", "max1024": "", "original": ""}}"#;
+        let mix = Mix::from_json(&json::Json::from_str(data).unwrap());
+        assert_eq!(mix.user_id, 42);
+        assert_eq!(mix.user_login, Some("someone".to_string()));
+
+        let data = data.replace(r#""user": {"id": 42, "login": "someone"},"#, "");
+        assert_eq!(Mix::from_json(&json::Json::from_str(&data[..]).unwrap()).user_login, None);
+    }
+
+    #[test]
+    fn timestamps_are_parsed_as_utc() {
+        assert_eq!(Timestamp::parse("1970-01-01T00:00:00Z"), Some(Timestamp { secs: 0 }));
+        assert_eq!(Timestamp::parse("2014-05-01T12:34:56Z"), Some(Timestamp { secs: 1398947696 }));
+        assert_eq!(Timestamp::parse("2014-05-01T14:34:56.123+02:00"),
+                   Some(Timestamp { secs: 1398947696 }));
+        assert_eq!(Timestamp::parse("2014-05-01T12:34:56Z").unwrap().date_string(), "2014-05-01");
+        assert_eq!(Timestamp::parse("2000-02-29T23:59:59-0100").unwrap().date_string(),
+                   "2000-03-01");
+        assert_eq!(Timestamp { secs: 1398947696 }.iso_string(), "2014-05-01T12:34:56Z");
+        assert_eq!(Timestamp::parse("yesterday"), None);
+        assert_eq!(Timestamp::parse("2014-13-01T00:00:00Z"), None);
+        assert_eq!(Timestamp::parse("2014-05-01T12:34:56+1\u{e9}2"), None);
+        assert_eq!(Timestamp::parse("2014-05-01T12:34:56+-100"), None);
+    }
+}
@@ -0,0 +1,1037 @@
+// The application logic, independent of any front end. AppCore owns the session (play token),
+// the mix list, the current track and the player. Front ends feed it commands, the results of
+// the network requests it asks for and the player's events, and render the view updates it
+// returns.
+
+use std::mem;
+
+use api;
+use player::{PlaybackBackend, PlayerEvent, StallAction, StreamInfo};
+use scrobbler;
+
+// A track is scrobbled after half its length, or after this many seconds.
+static SCROBBLE_AFTER: i64 = 4 * 60;
+
+/// Things the user wants.
+#[derive(Debug)]
+pub enum Command {
+    FetchPlayToken,
+    GetMixes(String),
+    /// Loads the mixes for a smart id and plays the first one.
+    PlaySmartId(String),
+    PlayMix(usize),
+    TogglePlaying,
+    NextTrack,
+    SkipTrack,
+    /// Stops the current track, the mix stays selected.
+    Stop,
+    /// Adds the current track to the user's favorites.
+    LikeTrack,
+    /// Whether mix lists leave out explicit mixes. Reloads the current mix list if it changed.
+    SetHideNsfw(bool),
+}
+
+/// Network work the front end has to do for the core, answered with `Input::Network`.
+pub enum Request {
+    PlayToken,
+    /// The mixes for a smart id, the flag asks the API for safe results only.
+    MixSet(String, bool),
+    Play(api::PlayToken, api::Mix),
+    Next(api::PlayToken, api::Mix),
+    Skip(api::PlayToken, api::Mix),
+    /// Fire and forget, there is no answer.
+    Report(api::PlayToken, u32, u32),
+    /// Favorites the track with this id, answered with `NetworkResult::Liked`.
+    Like(u32),
+    /// Fire and forget, tells the scrobble server about a track that just started.
+    NowPlaying(scrobbler::Endpoint, api::Track),
+    /// Fire and forget, the scrobbler queues it if the server can't be reached.
+    Scrobble(scrobbler::Endpoint, Listen),
+}
+
+/// Results of `Request`s.
+pub enum NetworkResult {
+    PlayToken(api::PlayToken),
+    Mixes(Vec<api::Mix>),
+    PlayState(api::PlayState),
+    Liked(u32),
+    /// The request failed, with a message for the user.
+    Error(String),
+}
+
+pub enum Input {
+    Command(Command),
+    Network(NetworkResult),
+    Player(PlayerEvent),
+}
+
+/// Tells the front end what changed, it reads the new state from the core's getters.
+#[derive(PartialEq, Eq, Debug)]
+pub enum ViewUpdate {
+    Notify(String),
+    MixesChanged,
+    /// The mix with this index started, switch to it and fetch its cover.
+    MixStarted(usize),
+    /// The current track or what we know about it changed.
+    TrackChanged,
+    /// Playing, paused or buffering changed.
+    PlaybackChanged,
+    Progress,
+}
+
+pub enum Output {
+    Request(Request),
+    View(ViewUpdate),
+    /// A track that was reported to 8tracks is over, for the listening log.
+    Listened(Listen),
+}
+
+/// A track that was played long enough to be reported, 30 seconds.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Listen {
+    /// When the track started.
+    pub played_at: api::Timestamp,
+    pub track: api::Track,
+    pub mix_id: u32,
+    /// How far we got into the track, in seconds.
+    pub listened: u32,
+    /// The track's length in seconds, if the stream told us.
+    pub duration: Option<u32>,
+}
+
+/// A copy of the state, for front ends that don't live on the GUI thread.
+#[derive(Clone)]
+pub struct Status {
+    pub playing: bool,
+    pub mix: Option<api::Mix>,
+    pub track: Option<api::Track>,
+    /// Position and duration, in nanoseconds.
+    pub progress: Option<(i64, i64)>,
+    pub skip_allowed: bool,
+    pub mixes: Vec<api::Mix>,
+    pub hide_nsfw: bool,
+}
+
+pub struct AppCore<P: PlaybackBackend> {
+    player: P,
+
+    play_token: Option<api::PlayToken>,
+    mixes: Vec<api::Mix>,
+    // Play the first mix of the next mix list we get.
+    play_first_mix: bool,
+    // The smart id of the last mix list we asked for, to reload it when the filter changes.
+    last_smart_id: Option<String>,
+    hide_nsfw: bool,
+    // A copy, the mix list may be replaced by a new search while we play.
+    current_mix: Option<api::Mix>,
+    current_track: Option<api::Track>,
+    // When the current track started, the id of its mix, whether it was reported, and the
+    // furthest position and the duration (in ns) we saw.
+    track_started_at: api::Timestamp,
+    track_mix_id: u32,
+    track_reported: bool,
+    track_scrobbled: bool,
+    track_progress: Option<(i64, i64)>,
+    // None if scrobbling isn't configured.
+    scrobble_endpoint: Option<scrobbler::Endpoint>,
+    skip_allowed: bool,
+    // What the player told us about the current track's stream.
+    stream_info: StreamInfo,
+
+    // How often the current track's stream has been restarted after an error.
+    stream_retries: u32,
+    max_stream_retries: u32,
+    // Tracks of the current mix we gave up on because of playback errors.
+    broken_tracks: u32,
+
+    outputs: Vec<Output>,
+}
+
+impl<P: PlaybackBackend> AppCore<P> {
+    pub fn new(player: P, max_stream_retries: u32) -> AppCore<P> {
+        AppCore {
+            player: player,
+            play_token: None,
+            mixes: Vec::new(),
+            play_first_mix: false,
+            last_smart_id: None,
+            hide_nsfw: false,
+            current_mix: None,
+            current_track: None,
+            track_started_at: api::Timestamp { secs: 0 },
+            track_mix_id: 0,
+            track_reported: false,
+            track_scrobbled: false,
+            track_progress: None,
+            scrobble_endpoint: None,
+            skip_allowed: true,
+            stream_info: StreamInfo::new(),
+            stream_retries: 0,
+            max_stream_retries: max_stream_retries,
+            broken_tracks: 0,
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn set_scrobble_endpoint(&mut self, endpoint: Option<scrobbler::Endpoint>) {
+        self.scrobble_endpoint = endpoint;
+    }
+
+    pub fn player(&self) -> &P {
+        &self.player
+    }
+
+    pub fn player_mut(&mut self) -> &mut P {
+        &mut self.player
+    }
+
+    pub fn mixes(&self) -> &[api::Mix] {
+        &self.mixes[..]
+    }
+
+    pub fn current_mix(&self) -> Option<&api::Mix> {
+        self.current_mix.as_ref()
+    }
+
+    pub fn current_track(&self) -> Option<&api::Track> {
+        self.current_track.as_ref()
+    }
+
+    pub fn stream_info(&self) -> &StreamInfo {
+        &self.stream_info
+    }
+
+    pub fn skip_allowed(&self) -> bool {
+        self.skip_allowed
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.player.is_playing()
+    }
+
+    pub fn hide_nsfw(&self) -> bool {
+        self.hide_nsfw
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            playing: self.is_playing(),
+            mix: self.current_mix.clone(),
+            track: self.current_track.clone(),
+            progress: self.player.get_progress_info(),
+            skip_allowed: self.skip_allowed,
+            mixes: self.mixes.clone(),
+            hide_nsfw: self.hide_nsfw,
+        }
+    }
+
+    pub fn handle(&mut self, input: Input) -> Vec<Output> {
+        match input {
+            Input::Command(c) => self.handle_command(c),
+            Input::Network(r) => self.handle_network_result(r),
+            Input::Player(e) => self.handle_player_event(e),
+        }
+        mem::replace(&mut self.outputs, Vec::new())
+    }
+
+    fn view(&mut self, update: ViewUpdate) {
+        self.outputs.push(Output::View(update));
+    }
+
+    fn notify(&mut self, message: String) {
+        info!("Notification message: {}", message);
+        self.view(ViewUpdate::Notify(message));
+    }
+
+    fn request(&mut self, request: Request) {
+        self.outputs.push(Output::Request(request));
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        debug!("handling command {:?}", command);
+        match command {
+            Command::FetchPlayToken => self.fetch_play_token(),
+            Command::GetMixes(smart_id) => {
+                self.play_first_mix = false;
+                self.get_mixes(smart_id);
+            }
+            Command::PlaySmartId(smart_id) => {
+                self.play_first_mix = true;
+                self.get_mixes(smart_id);
+            }
+            Command::PlayMix(i) => self.play_mix(i),
+            Command::TogglePlaying => self.toggle_playing(),
+            Command::NextTrack => self.next_track(),
+            Command::SkipTrack => self.skip_track(),
+            Command::Stop => self.stop_playing(),
+            Command::LikeTrack => self.like_track(),
+            Command::SetHideNsfw(hide) => self.set_hide_nsfw(hide),
+        }
+    }
+
+    fn handle_network_result(&mut self, result: NetworkResult) {
+        match result {
+            NetworkResult::PlayToken(pt) => {
+                debug!("setting play token to `{}`", pt.s);
+                self.play_token = Some(pt);
+                self.maybe_play_first_mix();
+            }
+            NetworkResult::Mixes(mixes) => self.set_mixes(mixes),
+            NetworkResult::PlayState(ps) => self.set_play_state(ps),
+            NetworkResult::Liked(track_id) => self.track_liked(track_id),
+            NetworkResult::Error(message) => self.notify(message),
+        }
+    }
+
+    fn handle_player_event(&mut self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::Error(generation, e) => self.playback_error(generation, e),
+            PlayerEvent::EndOfStream => self.next_track(),
+            PlayerEvent::AsyncDone => self.player.async_done(),
+            PlayerEvent::Playing => self.player.start_timers(),
+            PlayerEvent::Paused => self.player.pause_timers(),
+            PlayerEvent::Buffering(percent) => {
+                self.player.set_buffering(percent);
+                self.view(ViewUpdate::PlaybackChanged);
+                // The progress timer doesn't run while we're buffering, show the fill level now.
+                self.view(ViewUpdate::Progress);
+            }
+            PlayerEvent::StreamInfo(info) => {
+                self.stream_info.merge(info);
+                self.view(ViewUpdate::TrackChanged);
+            }
+            PlayerEvent::ReportDue => self.report_current_track(),
+            PlayerEvent::Progress => {
+                self.update_track_progress();
+                self.maybe_scrobble();
+                self.view(ViewUpdate::Progress);
+            }
+            PlayerEvent::WatchdogTick => self.check_stall(),
+        }
+    }
+
+    fn fetch_play_token(&mut self) {
+        if self.play_token.is_some() {
+            debug!("play token already exists, ignoring request");
+            return;
+        }
+        debug!("fetching play token");
+        self.request(Request::PlayToken);
+    }
+
+    fn get_mixes(&mut self, smart_id: String) {
+        self.last_smart_id = Some(smart_id.clone());
+        let safe_browse = self.hide_nsfw;
+        self.request(Request::MixSet(smart_id, safe_browse));
+    }
+
+    fn set_hide_nsfw(&mut self, hide: bool) {
+        if hide == self.hide_nsfw {
+            return;
+        }
+        self.hide_nsfw = hide;
+        match self.last_smart_id.clone() {
+            Some(smart_id) => {
+                self.play_first_mix = false;
+                self.get_mixes(smart_id);
+            }
+            None => (),
+        }
+    }
+
+    fn set_mixes(&mut self, mut mixes: Vec<api::Mix>) {
+        debug!("setting mixes, length {}", mixes.len());
+        // The API doesn't always honor safe browsing.
+        if self.hide_nsfw {
+            mixes.retain(|m| !m.nsfw);
+        }
+        self.mixes = mixes;
+        self.view(ViewUpdate::MixesChanged);
+        self.maybe_play_first_mix();
+    }
+
+    // Waits for both the mix list and the play token, at startup the mix list may be first.
+    fn maybe_play_first_mix(&mut self) {
+        if !self.play_first_mix || self.play_token.is_none() {
+            return;
+        }
+        self.play_first_mix = false;
+        if !self.mixes.is_empty() {
+            self.play_mix(0);
+        }
+    }
+
+    fn play_mix(&mut self, i: usize) {
+        debug!("playing mix with index {}", i);
+        if i >= self.mixes.len() {
+            warn!("index is out of bounds, ignoring message");
+            return;
+        }
+        let pt = match self.play_token.clone() {
+            Some(pt) => pt,
+            None => {
+                self.notify("Can't play yet, still waiting for a play token".to_string());
+                return;
+            }
+        };
+        let mix = self.mixes[i].clone();
+        self.current_mix = Some(mix.clone());
+        self.broken_tracks = 0;
+        self.skip_allowed = true;
+        debug!("playing mix with name `{}`", mix.name);
+        self.player.pause();
+        self.view(ViewUpdate::MixStarted(i));
+        self.request(Request::Play(pt, mix));
+    }
+
+    fn set_play_state(&mut self, ps: api::PlayState) {
+        self.skip_allowed = ps.skip_allowed;
+        match ps.track {
+            Some(track) => self.play_track(track),
+            None if ps.at_end => self.end_of_mix(),
+            None => self.notify("Could not get the next track".to_string()),
+        }
+    }
+
+    fn end_of_mix(&mut self) {
+        let name = self.current_mix().map(|m| m.name.clone()).unwrap_or_default();
+        debug!("mix `{}` is over", name);
+        self.stop_playing();
+        self.notify(format!("Mix '{}' is over", name));
+    }
+
+    fn stop_playing(&mut self) {
+        if self.current_track.is_some() {
+            self.finish_track();
+            self.player.stop();
+        }
+        self.current_track = None;
+        self.stream_info = StreamInfo::new();
+        self.view(ViewUpdate::TrackChanged);
+        self.view(ViewUpdate::PlaybackChanged);
+    }
+
+    fn play_track(&mut self, track: api::Track) {
+        debug!("playing track `{}`", track.name);
+        // After a skip the old track is still current.
+        if self.current_track.is_some() {
+            self.finish_track();
+        }
+        self.track_started_at = api::Timestamp::now();
+        // The mix can change before the track is over, see play_mix.
+        self.track_mix_id = self.current_mix().map(|m| m.id).unwrap_or(0);
+        self.track_reported = false;
+        self.track_scrobbled = false;
+        self.track_progress = None;
+        match self.scrobble_endpoint.clone() {
+            Some(endpoint) => self.request(Request::NowPlaying(endpoint, track.clone())),
+            None => (),
+        }
+        self.stream_info = StreamInfo::new();
+        self.stream_retries = 0;
+        debug!("setting uri to `{}`", track.track_file_stream_url);
+        self.player.set_uri(&track.track_file_stream_url[..]);
+        self.player.play();
+        self.current_track = Some(track);
+        self.view(ViewUpdate::TrackChanged);
+        self.view(ViewUpdate::PlaybackChanged);
+        self.view(ViewUpdate::Progress);
+    }
+
+    fn update_track_progress(&mut self) {
+        match (self.player.get_progress_info(), self.track_progress) {
+            (Some((position, _)), Some((furthest, _))) if position < furthest => (),
+            (Some(progress), _) => self.track_progress = Some(progress),
+            (None, _) => (),
+        }
+    }
+
+    // What we know about how the current track was played so far.
+    fn current_listen(&self) -> Option<Listen> {
+        let track = match self.current_track {
+            Some(ref track) => track.clone(),
+            None => return None,
+        };
+        let (listened, duration) = match self.track_progress {
+            Some((position, duration)) if duration > 0 => {
+                (position / 1000000000, Some((duration / 1000000000) as u32))
+            }
+            Some((position, _)) => (position / 1000000000, None),
+            None => (0, None),
+        };
+        Some(Listen {
+            played_at: self.track_started_at,
+            track: track,
+            mix_id: self.track_mix_id,
+            listened: listened as u32,
+            duration: duration,
+        })
+    }
+
+    // The current track is over, logs it if it counts as listened.
+    fn finish_track(&mut self) {
+        self.update_track_progress();
+        if !self.track_reported {
+            return;
+        }
+        self.track_reported = false;
+        match self.current_listen() {
+            Some(listen) => self.outputs.push(Output::Listened(listen)),
+            None => (),
+        }
+    }
+
+    fn maybe_scrobble(&mut self) {
+        let endpoint = match self.scrobble_endpoint.clone() {
+            Some(e) => e,
+            None => return,
+        };
+        if self.track_scrobbled {
+            return;
+        }
+        let due = match self.track_progress {
+            Some((position, duration)) => {
+                let secs = position / 1000000000;
+                secs >= SCROBBLE_AFTER || (duration > 0 && position * 2 >= duration)
+            }
+            None => false,
+        };
+        if !due {
+            return;
+        }
+        match self.current_listen() {
+            Some(listen) => {
+                self.track_scrobbled = true;
+                self.request(Request::Scrobble(endpoint, listen));
+            }
+            None => (),
+        }
+    }
+
+    fn report_current_track(&mut self) {
+        debug!("reporting current track");
+        let request = match (&self.play_token, self.current_mix(), &self.current_track) {
+            (&Some(ref pt), Some(mix), &Some(ref track)) => {
+                Request::Report(pt.clone(), track.id, mix.id)
+            }
+            _ => {
+                warn!("nothing to report");
+                return;
+            }
+        };
+        self.track_reported = true;
+        self.request(request);
+    }
+
+    fn like_track(&mut self) {
+        let track_id = match self.current_track {
+            Some(ref track) if track.faved_by_current_user => return,
+            Some(ref track) => track.id,
+            None => return,
+        };
+        debug!("liking track {}", track_id);
+        self.request(Request::Like(track_id));
+    }
+
+    fn track_liked(&mut self, track_id: u32) {
+        let name = match self.current_track {
+            Some(ref mut track) if track.id == track_id => {
+                track.faved_by_current_user = true;
+                track.name.clone()
+            }
+            // We moved on in the meantime.
+            _ => return,
+        };
+        self.view(ViewUpdate::TrackChanged);
+        self.notify(format!("Added '{}' to your favorites", name));
+    }
+
+    fn toggle_playing(&mut self) {
+        debug!("toggling!");
+        if self.current_track.is_none() {
+            return;
+        }
+        self.player.toggle();
+        self.view(ViewUpdate::PlaybackChanged);
+    }
+
+    fn playback_error(&mut self, generation: u32, err_msg: String) {
+        // A failing pipeline often posts several errors. Only the first one counts, the rest
+        // belong to a stream we've already retried or skipped.
+        if generation != self.player.stream_generation() {
+            debug!("ignoring error of an old stream: {}", err_msg);
+            return;
+        }
+        let track = match self.current_track.clone() {
+            Some(t) => t,
+            None => {
+                self.notify(format!("Playback error: `{}`", err_msg));
+                return;
+            }
+        };
+
+        if self.stream_retries < self.max_stream_retries {
+            self.stream_retries += 1;
+            let message = format!("Playback error: `{}`, retrying ({}/{})",
+                                  err_msg, self.stream_retries, self.max_stream_retries);
+            self.notify(message);
+            self.player.set_uri(&track.track_file_stream_url[..]);
+            self.player.play();
+            self.view(ViewUpdate::PlaybackChanged);
+            self.view(ViewUpdate::Progress);
+        } else {
+            self.broken_tracks += 1;
+            let message = format!("Giving up on '{}' by {} after {} retries (`{}`), \
+                                   {} broken track{} in this mix so far",
+                                  track.name, track.performer, self.stream_retries, err_msg,
+                                  self.broken_tracks,
+                                  if self.broken_tracks == 1 { "" } else { "s" });
+            self.notify(message);
+            self.next_track();
+        }
+    }
+
+    fn check_stall(&mut self) {
+        match self.player.check_stall() {
+            StallAction::Nothing => (),
+            StallAction::Restarted => {
+                self.notify("Stream stalled, restarting it".to_string());
+                self.view(ViewUpdate::PlaybackChanged);
+            }
+            StallAction::GiveUp => {
+                self.notify("Stream keeps stalling, skipping to the next track".to_string());
+                self.next_track();
+            }
+        }
+    }
+
+    fn current_request_args(&self) -> Option<(api::PlayToken, api::Mix)> {
+        match (&self.play_token, self.current_mix()) {
+            (&Some(ref pt), Some(mix)) => Some((pt.clone(), mix.clone())),
+            _ => None
+        }
+    }
+
+    fn next_track(&mut self) {
+        let (pt, mix) = match self.current_request_args() {
+            Some(args) => args,
+            None => {
+                warn!("no mix is playing, can't get the next track");
+                return;
+            }
+        };
+        self.stop_playing();
+
+        debug!("getting next track of mix with name `{}`", mix.name);
+        self.request(Request::Next(pt, mix));
+    }
+
+    fn skip_track(&mut self) {
+        let (pt, mix) = match self.current_request_args() {
+            Some(args) => args,
+            None => {
+                warn!("no mix is playing, can't skip");
+                return;
+            }
+        };
+        if !self.skip_allowed {
+            self.notify("8tracks doesn't allow skipping this track".to_string());
+            return;
+        }
+        self.player.pause();
+        self.view(ViewUpdate::PlaybackChanged);
+
+        debug!("skipping track of mix with name `{}`", mix.name);
+        self.request(Request::Skip(pt, mix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use fake_player::FakePlayer;
+    use fixtures::{Harness, mix, play_state, track};
+    use player::{PlaybackBackend, StallAction};
+    use scrobbler;
+
+    use super::*;
+
+    #[test]
+    fn play_mix_requests_the_play_state() {
+        let mut h = Harness::new();
+        h.input(Input::Command(Command::PlayMix(1)));
+        assert_eq!(h.requests.len(), 1);
+        match h.requests[0] {
+            Request::Play(ref pt, ref m) => {
+                assert_eq!(&pt.s[..], "pt");
+                assert_eq!(m.id, 2);
+            }
+            _ => panic!("expected a play request"),
+        }
+        assert!(h.views.contains(&ViewUpdate::MixStarted(1)));
+    }
+
+    #[test]
+    fn play_mix_needs_a_play_token() {
+        let (sender, _receiver) = mpsc::channel();
+        let mut core = AppCore::new(FakePlayer::new(sender), 1);
+        core.handle(Input::Network(NetworkResult::Mixes(vec![mix(1)])));
+        let outputs = core.handle(Input::Command(Command::PlayMix(0)));
+        assert!(outputs.iter().all(|o| match *o { Output::Request(_) => false, _ => true }));
+    }
+
+    #[test]
+    fn play_smart_id_plays_the_first_mix() {
+        let mut h = Harness::new();
+        h.input(Input::Command(Command::PlaySmartId("tags:jazz".to_string())));
+        match h.requests[0] {
+            Request::MixSet(ref smart_id, _) => assert_eq!(&smart_id[..], "tags:jazz"),
+            _ => panic!("expected a mix set request"),
+        }
+        h.input(Input::Network(NetworkResult::Mixes(vec![mix(7), mix(8)])));
+        assert!(h.views.contains(&ViewUpdate::MixStarted(0)));
+        assert_eq!(h.core.current_mix().map(|m| m.id), Some(7));
+
+        // A plain search afterwards doesn't start anything.
+        h.input(Input::Command(Command::GetMixes("tags:rock".to_string())));
+        h.input(Input::Network(NetworkResult::Mixes(vec![mix(9)])));
+        assert_eq!(h.core.current_mix().map(|m| m.id), Some(7));
+    }
+
+    #[test]
+    fn first_track_starts_playing() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(10));
+        assert_eq!(h.core.player().current_uri(), Some("http://example.com/10.mp3"));
+        assert!(h.core.is_playing());
+    }
+
+    #[test]
+    fn end_of_stream_requests_the_next_track() {
+        let mut h = Harness::new();
+        h.core.player_mut().set_track_duration(60 * 1000);
+        h.start_mix(0, 10);
+        h.advance(61 * 1000);
+        assert!(h.core.current_track().is_none());
+        assert!(h.requests.iter().any(|r| match *r {
+            Request::Next(_, ref m) => m.id == 1,
+            _ => false,
+        }));
+
+        h.input(Input::Network(NetworkResult::PlayState(play_state(Some(track(11)), false, true))));
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(11));
+    }
+
+    #[test]
+    fn track_is_reported_after_thirty_seconds() {
+        let mut h = Harness::new();
+        h.start_mix(1, 10);
+        h.advance(29 * 1000);
+        assert!(h.requests.is_empty());
+        h.advance(1000);
+        assert_eq!(h.requests.len(), 1);
+        match h.requests[0] {
+            Request::Report(_, track_id, mix_id) => {
+                assert_eq!(track_id, 10);
+                assert_eq!(mix_id, 2);
+            }
+            _ => panic!("expected a report request"),
+        }
+    }
+
+    #[test]
+    fn skip_pauses_and_requests_a_skip() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.input(Input::Command(Command::SkipTrack));
+        h.pump();
+        assert!(!h.core.is_playing());
+        assert!(h.requests.iter().any(|r| match *r { Request::Skip(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn skip_is_refused_when_not_allowed() {
+        let mut h = Harness::new();
+        h.input(Input::Command(Command::PlayMix(0)));
+        h.input(Input::Network(NetworkResult::PlayState(play_state(Some(track(10)), false, false))));
+        h.pump();
+        h.requests.clear();
+
+        h.input(Input::Command(Command::SkipTrack));
+        assert!(h.requests.is_empty());
+        assert!(h.core.is_playing());
+        assert!(!h.core.skip_allowed());
+    }
+
+    #[test]
+    fn end_of_mix_stops_playback() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.input(Input::Command(Command::NextTrack));
+        h.input(Input::Network(NetworkResult::PlayState(play_state(None, true, true))));
+        assert!(h.core.current_track().is_none());
+        assert!(!h.core.is_playing());
+        assert!(h.views.iter().any(|v| match *v {
+            ViewUpdate::Notify(ref m) => m.contains("is over"),
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn playback_errors_are_retried_then_skipped() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+
+        h.core.player_mut().fail("connection reset");
+        h.pump();
+        assert_eq!(h.core.player().loaded_uris().len(), 2);
+        assert!(h.requests.is_empty());
+
+        h.core.player_mut().fail("connection reset");
+        h.pump();
+        assert_eq!(h.core.player().loaded_uris().len(), 2);
+        assert!(h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn errors_of_one_stream_use_up_one_retry() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+
+        h.core.player_mut().fail("connection reset");
+        h.core.player_mut().fail("internal data stream error");
+        h.pump();
+        assert_eq!(h.core.player().loaded_uris().len(), 2);
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(10));
+        assert!(h.requests.is_empty());
+    }
+
+    #[test]
+    fn stream_stuck_buffering_is_restarted_and_then_skipped() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.advance(1000);
+        h.core.player_mut().buffer(60);
+        h.pump();
+
+        // One second at a time, the watchdog looks at the clock when it handles a tick.
+        for _ in 0..17 {
+            h.advance(1000);
+        }
+        assert_eq!(h.core.player().restarts(), 1);
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(10));
+        assert!(h.requests.is_empty());
+
+        for _ in 0..40 {
+            h.advance(1000);
+        }
+        assert!(h.core.current_track().is_none());
+        assert!(h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn watchdog_giving_up_moves_on() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.core.player_mut().script_stall(StallAction::GiveUp);
+        h.advance(1000);
+        assert!(h.core.current_track().is_none());
+        assert!(h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn dead_stream_is_restarted_and_then_skipped() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.advance(5 * 1000);
+        h.core.player_mut().kill_stream();
+
+        h.advance(16 * 1000);
+        assert_eq!(h.core.player().restarts(), 1);
+        // The restarted pipeline never prerolls, that's no progress either.
+        h.advance(16 * 1000);
+        assert_eq!(h.core.player().restarts(), 2);
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(10));
+        assert!(!h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+
+        h.advance(16 * 1000);
+        assert!(h.core.current_track().is_none());
+        assert!(h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn stream_recovering_only_briefly_is_still_skipped() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        for _ in 0..2 {
+            h.core.player_mut().kill_stream();
+            h.advance(16 * 1000);
+            h.core.player_mut().revive_stream();
+            h.advance(3 * 1000);
+        }
+        assert_eq!(h.core.player().restarts(), 2);
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(10));
+
+        h.core.player_mut().kill_stream();
+        h.advance(16 * 1000);
+        assert!(h.core.current_track().is_none());
+        assert!(h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn stalls_are_forgiven_after_playing_on_for_a_while() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        for _ in 0..3 {
+            h.core.player_mut().kill_stream();
+            h.advance(16 * 1000);
+            h.core.player_mut().revive_stream();
+            h.advance(20 * 1000);
+        }
+        assert_eq!(h.core.player().restarts(), 3);
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(10));
+        assert!(!h.requests.iter().any(|r| match *r { Request::Next(..) => true, _ => false }));
+    }
+
+    #[test]
+    fn new_mix_list_does_not_interrupt_the_current_mix() {
+        let mut h = Harness::new();
+        h.start_mix(1, 10);
+        h.input(Input::Network(NetworkResult::Mixes(vec![mix(4)])));
+        assert_eq!(h.core.current_mix().map(|m| m.id), Some(2));
+
+        h.input(Input::Command(Command::NextTrack));
+        assert!(h.requests.iter().any(|r| match *r {
+            Request::Next(_, ref m) => m.id == 2,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn hide_nsfw_filters_and_reloads_the_mix_list() {
+        let mut h = Harness::new();
+        h.input(Input::Command(Command::GetMixes("tags:rock".to_string())));
+        let mut explicit = mix(5);
+        explicit.nsfw = true;
+        h.input(Input::Network(NetworkResult::Mixes(vec![mix(4), explicit.clone()])));
+        assert_eq!(h.core.mixes().len(), 2);
+        h.requests.clear();
+
+        h.input(Input::Command(Command::SetHideNsfw(true)));
+        match h.requests[0] {
+            Request::MixSet(ref smart_id, safe_browse) => {
+                assert_eq!(&smart_id[..], "tags:rock");
+                assert!(safe_browse);
+            }
+            _ => panic!("expected a mix set request"),
+        }
+        h.input(Input::Network(NetworkResult::Mixes(vec![mix(4), explicit])));
+        assert_eq!(h.core.mixes().iter().map(|m| m.id).collect::<Vec<_>>(), vec![4]);
+
+        h.requests.clear();
+        h.input(Input::Command(Command::SetHideNsfw(true)));
+        assert!(h.requests.is_empty());
+    }
+
+    #[test]
+    fn reported_tracks_are_listened_when_they_end() {
+        let mut h = Harness::new();
+        h.core.player_mut().set_track_duration(60 * 1000);
+        h.start_mix(1, 10);
+        h.advance(45 * 1000);
+        assert!(h.listens.is_empty());
+
+        h.input(Input::Command(Command::NextTrack));
+        assert_eq!(h.listens.len(), 1);
+        assert_eq!(h.listens[0].track.id, 10);
+        assert_eq!(h.listens[0].mix_id, 2);
+        assert_eq!(h.listens[0].listened, 45);
+        assert_eq!(h.listens[0].duration, Some(60));
+    }
+
+    #[test]
+    fn skipped_tracks_below_the_threshold_are_not_listened() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.advance(10 * 1000);
+        h.input(Input::Command(Command::SkipTrack));
+        h.input(Input::Network(NetworkResult::PlayState(play_state(Some(track(11)), false, true))));
+        assert!(h.listens.is_empty());
+        assert_eq!(h.core.current_track().map(|t| t.id), Some(11));
+    }
+
+    #[test]
+    fn listens_keep_their_mix_when_another_mix_starts() {
+        let mut h = Harness::new();
+        h.start_mix(1, 10);
+        h.advance(45 * 1000);
+
+        h.input(Input::Command(Command::PlayMix(0)));
+        h.input(Input::Network(NetworkResult::PlayState(play_state(Some(track(20)), false, true))));
+        assert_eq!(h.listens.len(), 1);
+        assert_eq!(h.listens[0].track.id, 10);
+        assert_eq!(h.listens[0].mix_id, 2);
+    }
+
+    #[test]
+    fn stopping_logs_the_reported_track() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.advance(40 * 1000);
+        h.input(Input::Command(Command::Stop));
+        assert_eq!(h.listens.len(), 1);
+        assert_eq!(h.listens[0].track.id, 10);
+        assert_eq!(h.listens[0].listened, 40);
+    }
+
+    #[test]
+    fn tracks_are_scrobbled_after_half_their_length() {
+        let mut h = Harness::new();
+        h.core.set_scrobble_endpoint(Some(scrobbler::Endpoint {
+            url: "http://localhost:8100".to_string(),
+            token: "secret".to_string(),
+        }));
+        h.core.player_mut().set_track_duration(100 * 1000);
+        h.input(Input::Command(Command::PlayMix(0)));
+        h.input(Input::Network(NetworkResult::PlayState(play_state(Some(track(10)), false, true))));
+        h.pump();
+        assert!(h.requests.iter().any(|r| match *r {
+            Request::NowPlaying(_, ref t) => t.id == 10,
+            _ => false,
+        }));
+        h.requests.clear();
+
+        let scrobbles = |h: &Harness| h.requests.iter().filter(|r| match **r {
+            Request::Scrobble(..) => true,
+            _ => false,
+        }).count();
+        h.advance(49 * 1000);
+        assert_eq!(scrobbles(&h), 0);
+        h.advance(1000);
+        assert_eq!(scrobbles(&h), 1);
+        h.advance(20 * 1000);
+        assert_eq!(scrobbles(&h), 1);
+    }
+
+    #[test]
+    fn liking_favorites_the_track_or_says_why_not() {
+        let mut h = Harness::new();
+        h.start_mix(0, 10);
+        h.input(Input::Command(Command::LikeTrack));
+        assert!(h.requests.iter().any(|r| match *r {
+            Request::Like(10) => true,
+            _ => false,
+        }));
+
+        // Without an 8tracks login the server says no.
+        let message = "Could not like track: You must be logged in".to_string();
+        h.input(Input::Network(NetworkResult::Error(message.clone())));
+        assert!(h.views.contains(&ViewUpdate::Notify(message)));
+        assert!(!h.core.current_track().unwrap().faved_by_current_user);
+
+        h.input(Input::Network(NetworkResult::Liked(10)));
+        assert!(h.core.current_track().unwrap().faved_by_current_user);
+    }
+}
@@ -0,0 +1,145 @@
+// Command line client for the control socket of a running rusttracks.
+
+extern crate rustc_serialize;
+extern crate unix_socket;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::io::{BufRead, Write};
+use std::process;
+
+use rustc_serialize::json;
+use rustc_serialize::json::ToJson;
+
+use unix_socket::UnixStream;
+
+#[path = "../paths.rs"]
+mod paths;
+
+static USAGE: &'static str = "\
+Usage: rusttracks-ctl COMMAND
+
+Commands:
+    toggle              pause or resume playback
+    skip                skip the current track
+    stop                stop playback
+    like                add the current track to your favorites
+    now-playing         show what's playing
+    search SMART_ID     show the mixes for SMART_ID, e.g. tags:folk:recent
+    play SMART_ID       play the first mix for SMART_ID
+    play-mix INDEX      play the mix at INDEX in the mix list";
+
+fn usage() -> ! {
+    let _ = writeln!(io::stderr(), "{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "rusttracks-ctl: {}", message);
+    process::exit(1);
+}
+
+fn build_request(args: &[String]) -> json::Json {
+    let mut obj = BTreeMap::new();
+    let command = match (args.get(0).map(|a| &a[..]), args.get(1)) {
+        (Some("toggle"), None) => "toggle",
+        (Some("skip"), None) => "skip",
+        (Some("stop"), None) => "stop",
+        (Some("like"), None) => "like",
+        (Some("now-playing"), None) => "now_playing",
+        (Some("search"), Some(id)) => {
+            obj.insert("smart_id".to_string(), id.to_json());
+            "search"
+        }
+        (Some("play"), Some(id)) => {
+            obj.insert("smart_id".to_string(), id.to_json());
+            "play"
+        }
+        (Some("play-mix"), Some(index)) => {
+            let index: u64 = match index.parse() {
+                Ok(i) => i,
+                Err(_) => usage(),
+            };
+            obj.insert("index".to_string(), index.to_json());
+            "play_mix"
+        }
+        _ => usage(),
+    };
+    obj.insert("command".to_string(), command.to_json());
+    json::Json::Object(obj)
+}
+
+fn print_now_playing(reply: &json::Json) {
+    let track = reply.find("track").and_then(|t| t.as_object());
+    let track = match track {
+        Some(t) => t,
+        None => {
+            println!("Nothing is playing");
+            return;
+        }
+    };
+    let field = |obj: &json::Object, key: &str| {
+        obj.get(key).and_then(|v| v.as_string()).unwrap_or("").to_string()
+    };
+    let paused = !reply.find("playing").and_then(|p| p.as_boolean()).unwrap_or(false);
+    println!("'{}' by {}{}", field(track, "name"), field(track, "performer"),
+             if paused { " (paused)" } else { "" });
+    match reply.find("mix").and_then(|m| m.as_object()) {
+        Some(mix) => println!("Mix: {}", field(mix, "name")),
+        None => (),
+    }
+    let seconds = |key: &str| reply.find(key).and_then(|s| s.as_i64());
+    match (seconds("position"), seconds("duration")) {
+        (Some(pos), Some(dur)) => {
+            println!("{}:{:02} / {}:{:02}", pos / 60, pos % 60, dur / 60, dur % 60);
+        }
+        _ => (),
+    }
+}
+
+fn print_mixes(reply: &json::Json) {
+    let mixes = match reply.find("mixes").and_then(|m| m.as_array()) {
+        Some(m) => m,
+        None => fail("the answer has no mixes"),
+    };
+    if mixes.is_empty() {
+        println!("No mixes");
+    }
+    for (i, mix) in mixes.iter().enumerate() {
+        let name = mix.find("name").and_then(|n| n.as_string()).unwrap_or("");
+        let tracks = mix.find("tracks_count").and_then(|c| c.as_i64()).unwrap_or(0);
+        println!("{:3}  {} ({} tracks)", i, name, tracks);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let request = build_request(&args[..]);
+
+    let mut stream = match UnixStream::connect(&paths::control_socket()) {
+        Ok(s) => s,
+        Err(err) => fail(&format!("could not connect, is rusttracks running? ({})", err)[..]),
+    };
+    if let Err(err) = writeln!(stream, "{}", request) {
+        fail(&format!("could not send the command: {}", err)[..]);
+    }
+    let mut line = String::new();
+    if let Err(err) = io::BufReader::new(stream).read_line(&mut line) {
+        fail(&format!("no answer: {}", err)[..]);
+    }
+    let reply = match json::Json::from_str(&line[..]) {
+        Ok(r) => r,
+        Err(err) => fail(&format!("bad answer: {}", err)[..]),
+    };
+
+    if !reply.find("ok").and_then(|ok| ok.as_boolean()).unwrap_or(false) {
+        let error = reply.find("error").and_then(|e| e.as_string()).unwrap_or("unknown error");
+        fail(error);
+    }
+    match &args[0][..] {
+        "now-playing" => print_now_playing(&reply),
+        "search" => print_mixes(&reply),
+        _ => (),
+    }
+}
@@ -0,0 +1,175 @@
+// User configuration, read from $XDG_CONFIG_HOME/rusttracks/config.json.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json;
+
+use paths;
+use utils;
+
+fn maybe_extract<T: Decodable>(obj: &json::Object, id: &str) -> Option<T> {
+    let found = match obj.get(id) {
+        Some(s) => s.clone(),
+        None => return None,
+    };
+    let mut decoder = json::Decoder::new(found);
+    match Decodable::decode(&mut decoder) {
+        Ok(v) => Some(v),
+        Err(err) => {
+            warn!("ignoring config value `{}`: {:?}", id, err);
+            None
+        }
+    }
+}
+
+pub struct Config {
+    /// How often a stream is restarted after a playback error before we give up on the track.
+    pub stream_retries: u32,
+    /// Seconds without any progress in position or buffering after which a stream is
+    /// considered stalled.
+    pub stall_timeout: u32,
+    /// How often a stalled stream is restarted before we skip the track.
+    pub stall_restarts: u32,
+    /// Show a desktop notification when a new track starts.
+    pub notifications: bool,
+    /// Address for the MPD protocol server, e.g. `127.0.0.1:6600`. No server if unset.
+    pub mpd_address: Option<String>,
+    /// Address for the HTTP remote control, e.g. `127.0.0.1:8080`, or `0.0.0.0:8080` to reach
+    /// it from the LAN. It has no authentication, everybody who can connect can control the
+    /// player. No server if unset.
+    pub http_address: Option<String>,
+    /// Base url of a ListenBrainz compatible server to scrobble to, with `scrobble_token`.
+    pub scrobble_endpoint: Option<String>,
+    /// The user token for `scrobble_endpoint`.
+    pub scrobble_token: Option<String>,
+    /// Megabytes of cover pictures kept in the cache, 0 turns the cache off.
+    pub cover_cache_size: u64,
+    /// Leave explicit mixes out of mix lists.
+    pub hide_nsfw: bool,
+    /// Accelerators per shortcut action, replacing that action's defaults. See `shortcuts`.
+    pub key_bindings: BTreeMap<String, Vec<String>>,
+}
+
+impl Config {
+    pub fn default() -> Config {
+        Config {
+            stream_retries: 2,
+            stall_timeout: 15,
+            stall_restarts: 2,
+            notifications: true,
+            mpd_address: None,
+            http_address: None,
+            scrobble_endpoint: None,
+            scrobble_token: None,
+            cover_cache_size: 50,
+            hide_nsfw: false,
+            key_bindings: BTreeMap::new(),
+        }
+    }
+
+    pub fn path() -> PathBuf {
+        let mut path = paths::xdg_app_dir("XDG_CONFIG_HOME", ".config");
+        path.push("config.json");
+        path
+    }
+
+    /// Loads the config file, using defaults for everything that is missing or broken.
+    pub fn load() -> Config {
+        let mut config = Config::default();
+        let path = Config::path();
+        let mut data = String::new();
+        match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut data)) {
+            Ok(_) => (),
+            Err(err) => {
+                debug!("not reading config file `{}`: {}", path.display(), err);
+                return config;
+            }
+        }
+        let json = match json::Json::from_str(&data[..]) {
+            Ok(json::Json::Object(obj)) => obj,
+            Ok(_) | Err(_) => {
+                warn!("config file `{}` is not a json object, ignoring it", path.display());
+                return config;
+            }
+        };
+
+        match maybe_extract(&json, "stream_retries") {
+            Some(r) => config.stream_retries = r,
+            None => ()
+        }
+        match maybe_extract(&json, "stall_timeout") {
+            Some(t) => config.stall_timeout = t,
+            None => ()
+        }
+        match maybe_extract(&json, "stall_restarts") {
+            Some(r) => config.stall_restarts = r,
+            None => ()
+        }
+        match maybe_extract(&json, "notifications") {
+            Some(n) => config.notifications = n,
+            None => ()
+        }
+        match maybe_extract(&json, "mpd_address") {
+            Some(a) => config.mpd_address = Some(a),
+            None => ()
+        }
+        match maybe_extract(&json, "http_address") {
+            Some(a) => config.http_address = Some(a),
+            None => ()
+        }
+        match maybe_extract(&json, "scrobble_endpoint") {
+            Some(e) => config.scrobble_endpoint = Some(e),
+            None => ()
+        }
+        match maybe_extract(&json, "scrobble_token") {
+            Some(t) => config.scrobble_token = Some(t),
+            None => ()
+        }
+        match maybe_extract(&json, "cover_cache_size") {
+            Some(s) => config.cover_cache_size = s,
+            None => ()
+        }
+        match maybe_extract(&json, "hide_nsfw") {
+            Some(h) => config.hide_nsfw = h,
+            None => ()
+        }
+        match maybe_extract(&json, "key_bindings") {
+            Some(b) => config.key_bindings = b,
+            None => ()
+        }
+        config
+    }
+
+    /// Sets `key` to `value` in the config file and keeps everything else in it. A file that
+    /// isn't a json object is left alone.
+    pub fn store(key: &str, value: json::Json) -> io::Result<()> {
+        let path = Config::path();
+        let mut obj = match try!(read_object(&path)) {
+            Some(obj) => obj,
+            None => BTreeMap::new(),
+        };
+        obj.insert(key.to_string(), value);
+        let data = format!("{}\n", json::as_pretty_json(&json::Json::Object(obj)));
+        utils::write_atomically(&path, data.as_bytes())
+    }
+}
+
+// None if there is no config file yet.
+fn read_object(path: &Path) -> io::Result<Option<json::Object>> {
+    let mut data = String::new();
+    match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut data)) {
+        Ok(_) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    match json::Json::from_str(&data[..]) {
+        Ok(json::Json::Object(obj)) => Ok(Some(obj)),
+        Ok(_) | Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                             "the config file is not a json object")),
+    }
+}
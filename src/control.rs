@@ -0,0 +1,353 @@
+// The control socket, so that the player can be scripted (see rusttracks-ctl). It's a Unix socket
+// under $XDG_RUNTIME_DIR speaking JSON lines: every line a client sends is a request object like
+// `{"command": "play", "smart_id": "tags:folk"}`, every request gets exactly one line back,
+// `{"ok": true}` or `{"ok": false, "error": "..."}` plus whatever the command returns.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::json;
+use rustc_serialize::json::ToJson;
+
+use unix_socket::{UnixListener, UnixStream};
+
+use api;
+use app;
+use gui;
+use gui::GuiUpdateMessage;
+use paths;
+
+// How long `search` waits for the new mix list before answering with the current one.
+static SEARCH_TIMEOUT_MS: u64 = 20 * 1000;
+
+/// Listens on the control socket until dropped.
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn start(sender: mpsc::Sender<GuiUpdateMessage>) -> io::Result<ControlServer> {
+        let path = paths::control_socket();
+        match path.parent() {
+            Some(dir) => try!(fs::create_dir_all(dir)),
+            None => (),
+        }
+        // Nobody answers on it (we checked in main), so it's left over from a crash.
+        if fs::metadata(&path).is_ok() {
+            try!(fs::remove_file(&path));
+        }
+        let listener = try!(UnixListener::bind(&path));
+        debug!("listening on control socket `{}`", path.display());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        thread::spawn(move || serve_client(stream, sender));
+                    }
+                    Err(err) => warn!("control socket: could not accept client: {}", err),
+                }
+            }
+        });
+
+        Ok(ControlServer { path: path })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        // The listener thread stays blocked in accept, but nobody can connect anymore.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Hands our command line to a running instance, returns false if there is none.
+pub fn forward_to_running_instance(smart_id: Option<&str>) -> bool {
+    let mut stream = match UnixStream::connect(&paths::control_socket()) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    let request = match smart_id {
+        Some(id) => request("play", vec![("smart_id", id.to_json())]),
+        None => request("raise", vec![]),
+    };
+    match send_request(&mut stream, &request) {
+        Ok(reply) => debug!("running instance answered {}", reply),
+        Err(err) => warn!("could not talk to the running instance: {}", err),
+    }
+    true
+}
+
+pub fn request(command: &str, args: Vec<(&str, json::Json)>) -> json::Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("command".to_string(), command.to_json());
+    for (key, value) in args.into_iter() {
+        obj.insert(key.to_string(), value);
+    }
+    json::Json::Object(obj)
+}
+
+pub fn send_request(stream: &mut UnixStream, request: &json::Json) -> io::Result<json::Json> {
+    try!(writeln!(stream, "{}", request));
+    let mut line = String::new();
+    try!(io::BufReader::new(stream).read_line(&mut line));
+    json::Json::from_str(&line[..]).map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("bad reply: {}", err))
+    })
+}
+
+fn serve_client(stream: UnixStream, sender: mpsc::Sender<GuiUpdateMessage>) {
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(err) => {
+            warn!("control socket: {}", err);
+            return;
+        }
+    };
+    for line in io::BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = handle_request(&line[..], &sender);
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn error_reply(message: String) -> json::Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("ok".to_string(), false.to_json());
+    obj.insert("error".to_string(), message.to_json());
+    json::Json::Object(obj)
+}
+
+fn ok_reply(mut obj: BTreeMap<String, json::Json>) -> json::Json {
+    obj.insert("ok".to_string(), true.to_json());
+    json::Json::Object(obj)
+}
+
+fn handle_request(line: &str, sender: &mpsc::Sender<GuiUpdateMessage>) -> json::Json {
+    let obj = match json::Json::from_str(line) {
+        Ok(json::Json::Object(obj)) => obj,
+        Ok(_) => return error_reply("request is not an object".to_string()),
+        Err(err) => return error_reply(format!("request is not json: {}", err)),
+    };
+    let command = match obj.get("command").and_then(|c| c.as_string()) {
+        Some(c) => c.to_string(),
+        None => return error_reply("request has no command".to_string()),
+    };
+    let smart_id = obj.get("smart_id").and_then(|s| s.as_string()).map(|s| s.to_string());
+
+    let message = match (&command[..], smart_id) {
+        ("toggle", _) => GuiUpdateMessage::Command(app::Command::TogglePlaying),
+        ("skip", _) => GuiUpdateMessage::Command(app::Command::SkipTrack),
+        ("stop", _) => GuiUpdateMessage::Command(app::Command::Stop),
+        ("like", _) => GuiUpdateMessage::Command(app::Command::LikeTrack),
+        ("raise", _) => GuiUpdateMessage::Raise,
+        ("search", Some(id)) => return search(id, sender),
+        ("play", Some(id)) => GuiUpdateMessage::Command(app::Command::PlaySmartId(id)),
+        ("search", None) | ("play", None) => {
+            return error_reply(format!("`{}` needs a smart_id", command));
+        }
+        ("play_mix", _) => match obj.get("index").and_then(|i| i.as_u64()) {
+            Some(i) => GuiUpdateMessage::Command(app::Command::PlayMix(i as usize)),
+            None => return error_reply("`play_mix` needs an index".to_string()),
+        },
+        ("now_playing", _) => {
+            return match gui::query_status(sender) {
+                Some(status) => ok_reply(status_json(&status)),
+                None => error_reply("the player is shutting down".to_string()),
+            };
+        }
+        _ => return error_reply(format!("unknown command `{}`", command)),
+    };
+    match sender.send(message) {
+        Ok(()) => ok_reply(BTreeMap::new()),
+        Err(_) => error_reply("the player is shutting down".to_string()),
+    }
+}
+
+// Asks for the mixes of `smart_id` and answers with them once they're in.
+fn search(smart_id: String, sender: &mpsc::Sender<GuiUpdateMessage>) -> json::Json {
+    let shutting_down = || error_reply("the player is shutting down".to_string());
+    let mix_ids = |status: &app::Status| status.mixes.iter().map(|m| m.id).collect::<Vec<_>>();
+
+    let (status_sender, status_receiver) = mpsc::channel();
+    if sender.send(GuiUpdateMessage::Subscribe(status_sender)).is_err() {
+        return shutting_down();
+    }
+    // Subscribers get the current state first.
+    let before = match status_receiver.recv() {
+        Ok(status) => mix_ids(&status),
+        Err(_) => return shutting_down(),
+    };
+    let command = GuiUpdateMessage::Command(app::Command::GetMixes(smart_id));
+    if sender.send(command).is_err() {
+        return shutting_down();
+    }
+
+    // None once we've waited long enough. Both threads end by themselves after we're gone, the
+    // GUI then drops the subscription with its next update.
+    let (event_sender, events) = mpsc::channel();
+    let timeout_sender = event_sender.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(SEARCH_TIMEOUT_MS));
+        let _ = timeout_sender.send(None);
+    });
+    thread::spawn(move || {
+        for status in status_receiver.iter() {
+            if event_sender.send(Some(status)).is_err() {
+                break;
+            }
+        }
+    });
+    loop {
+        match events.recv() {
+            Ok(Some(status)) => {
+                if mix_ids(&status) != before {
+                    return ok_reply(mixes_json(&status.mixes));
+                }
+            }
+            // The same list again, or the search failed and the GUI said why.
+            Ok(None) | Err(_) => break,
+        }
+    }
+    match gui::query_status(sender) {
+        Some(status) => ok_reply(mixes_json(&status.mixes)),
+        None => shutting_down(),
+    }
+}
+
+fn mixes_json(mixes: &[api::Mix]) -> BTreeMap<String, json::Json> {
+    let mut obj = BTreeMap::new();
+    obj.insert("mixes".to_string(), json::Json::Array(mixes.iter().map(mix_json).collect()));
+    obj
+}
+
+pub fn track_json(track: &api::Track) -> json::Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("id".to_string(), track.id.to_json());
+    obj.insert("name".to_string(), track.name.to_json());
+    obj.insert("performer".to_string(), track.performer.to_json());
+    obj.insert("release_name".to_string(), track.release_name.to_json());
+    obj.insert("year".to_string(), track.year.to_json());
+    obj.insert("url".to_string(), track.url.to_json());
+    json::Json::Object(obj)
+}
+
+pub fn mix_json(mix: &api::Mix) -> json::Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("id".to_string(), mix.id.to_json());
+    obj.insert("name".to_string(), mix.name.to_json());
+    obj.insert("tracks_count".to_string(), mix.tracks_count.to_json());
+    obj.insert("nsfw".to_string(), mix.nsfw.to_json());
+    obj.insert("tags".to_string(),
+               json::Json::Array(mix.tags.iter().map(|t| t.name.to_json()).collect()));
+    obj.insert("cover_url".to_string(), mix.cover_urls.sq250.to_json());
+    json::Json::Object(obj)
+}
+
+/// What's playing; position and duration are in seconds.
+pub fn status_json(status: &app::Status) -> BTreeMap<String, json::Json> {
+    let mut obj = BTreeMap::new();
+    obj.insert("playing".to_string(), status.playing.to_json());
+    obj.insert("skip_allowed".to_string(), status.skip_allowed.to_json());
+    obj.insert("mix".to_string(), match status.mix {
+        Some(ref m) => mix_json(m),
+        None => json::Json::Null,
+    });
+    obj.insert("track".to_string(), match status.track {
+        Some(ref t) => track_json(t),
+        None => json::Json::Null,
+    });
+    let (position, duration) = match status.progress {
+        Some((pos, dur)) => ((pos / 1000000000).to_json(), (dur / 1000000000).to_json()),
+        None => (json::Json::Null, json::Json::Null),
+    };
+    obj.insert("position".to_string(), position);
+    obj.insert("duration".to_string(), duration);
+    obj
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use rustc_serialize::json;
+
+    use app;
+    use gui::GuiUpdateMessage;
+
+    use super::handle_request;
+
+    fn error(reply: &json::Json) -> Option<&str> {
+        assert_eq!(reply.find("ok").and_then(|ok| ok.as_boolean()), Some(false));
+        reply.find("error").and_then(|e| e.as_string())
+    }
+
+    #[test]
+    fn bad_requests_get_errors() {
+        let (sender, receiver) = mpsc::channel();
+        let reply = handle_request(r#"{"command": "dance"}"#, &sender);
+        assert_eq!(error(&reply), Some("unknown command `dance`"));
+        let reply = handle_request(r#"{"smart_id": "tags:folk"}"#, &sender);
+        assert_eq!(error(&reply), Some("request has no command"));
+        let reply = handle_request("[1, 2]", &sender);
+        assert_eq!(error(&reply), Some("request is not an object"));
+        let reply = handle_request("play", &sender);
+        assert!(error(&reply).unwrap().starts_with("request is not json"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn missing_or_bad_arguments_get_errors() {
+        let (sender, receiver) = mpsc::channel();
+        let reply = handle_request(r#"{"command": "play"}"#, &sender);
+        assert_eq!(error(&reply), Some("`play` needs a smart_id"));
+        let reply = handle_request(r#"{"command": "search", "smart_id": 3}"#, &sender);
+        assert_eq!(error(&reply), Some("`search` needs a smart_id"));
+        let reply = handle_request(r#"{"command": "play_mix", "index": -1}"#, &sender);
+        assert_eq!(error(&reply), Some("`play_mix` needs an index"));
+        let reply = handle_request(r#"{"command": "play_mix", "index": "2"}"#, &sender);
+        assert_eq!(error(&reply), Some("`play_mix` needs an index"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn commands_are_sent_to_the_gui() {
+        let (sender, receiver) = mpsc::channel();
+        let reply = handle_request(r#"{"command": "play_mix", "index": 2}"#, &sender);
+        assert_eq!(reply.find("ok").and_then(|ok| ok.as_boolean()), Some(true));
+        match receiver.try_recv() {
+            Ok(GuiUpdateMessage::Command(app::Command::PlayMix(2))) => (),
+            _ => panic!("expected PlayMix(2)"),
+        }
+        handle_request(r#"{"command": "play", "smart_id": "tags:folk"}"#, &sender);
+        match receiver.try_recv() {
+            Ok(GuiUpdateMessage::Command(app::Command::PlaySmartId(ref id))) => {
+                assert_eq!(&id[..], "tags:folk");
+            }
+            _ => panic!("expected PlaySmartId"),
+        }
+    }
+
+    #[test]
+    fn commands_fail_once_the_gui_is_gone() {
+        let (sender, receiver) = mpsc::channel();
+        drop(receiver);
+        let reply = handle_request(r#"{"command": "toggle"}"#, &sender);
+        assert_eq!(error(&reply), Some("the player is shutting down"));
+    }
+}
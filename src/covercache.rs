@@ -0,0 +1,274 @@
+// Cover pictures we downloaded, in $XDG_CACHE_HOME/rusttracks/covers. Files are named after a
+// hash of their url, an index next to them remembers their sizes and when they were last used
+// so that the least recently used ones can go once the cache grows over its size limit. Hits only
+// update the index in memory, it's written when entries come or go and when the cache is dropped.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hasher, SipHasher};
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use rustc_serialize::json;
+
+use hyper;
+
+use paths;
+use utils;
+use webinterface;
+
+/// The cache, shared by all threads that download pictures.
+pub type SharedCoverCache = Arc<Mutex<CoverCache>>;
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct IndexEntry {
+    size: u64,
+    // Bigger is more recent.
+    last_used: u64,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+struct Index {
+    clock: u64,
+    // By file name.
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+pub struct CoverCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Index,
+    // The index changed since it was last written.
+    dirty: bool,
+}
+
+impl Drop for CoverCache {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.save_index();
+        }
+    }
+}
+
+impl CoverCache {
+    pub fn dir() -> PathBuf {
+        let mut dir = paths::xdg_app_dir("XDG_CACHE_HOME", ".cache");
+        dir.push("covers");
+        dir
+    }
+
+    /// Opens the cache in its usual place, a `max_bytes` of 0 disables it.
+    pub fn open(max_bytes: u64) -> SharedCoverCache {
+        Arc::new(Mutex::new(CoverCache::open_in(CoverCache::dir(), max_bytes)))
+    }
+
+    fn open_in(dir: PathBuf, max_bytes: u64) -> CoverCache {
+        let index_path = dir.join("index.json");
+        let mut data = String::new();
+        let index = match fs::File::open(&index_path).and_then(|mut f| {
+            f.read_to_string(&mut data)
+        }) {
+            Ok(_) => match json::decode(&data[..]) {
+                Ok(index) => index,
+                Err(err) => {
+                    warn!("ignoring broken cover cache index `{}`: {:?}",
+                          index_path.display(), err);
+                    Index { clock: 0, entries: BTreeMap::new() }
+                }
+            },
+            Err(_) => Index { clock: 0, entries: BTreeMap::new() },
+        };
+        CoverCache { dir: dir, max_bytes: max_bytes, index: index, dirty: false }
+    }
+
+    /// The cached data for `url`, if we have it.
+    pub fn get(&mut self, url: &str) -> Option<Vec<u8>> {
+        let name = file_name(url);
+        if !self.index.entries.contains_key(&name) {
+            return None;
+        }
+        let mut data = Vec::new();
+        match fs::File::open(&self.dir.join(&name)).and_then(|mut f| f.read_to_end(&mut data)) {
+            Ok(_) => (),
+            Err(err) => {
+                debug!("dropping cover cache entry for `{}`: {}", url, err);
+                self.index.entries.remove(&name);
+                self.save_index();
+                return None;
+            }
+        }
+        self.index.clock += 1;
+        self.index.entries.get_mut(&name).unwrap().last_used = self.index.clock;
+        self.dirty = true;
+        Some(data)
+    }
+
+    /// Stores the data for `url`, evicting old entries if the cache gets too big.
+    pub fn insert(&mut self, url: &str, data: &[u8]) {
+        if data.len() as u64 > self.max_bytes {
+            return;
+        }
+        let name = file_name(url);
+        match self.write_file(&name, data) {
+            Ok(()) => (),
+            Err(err) => {
+                warn!("could not cache the picture from `{}`: {}", url, err);
+                return;
+            }
+        }
+        self.index.clock += 1;
+        self.index.entries.insert(name, IndexEntry {
+            size: data.len() as u64,
+            last_used: self.index.clock,
+        });
+        self.evict();
+        self.save_index();
+    }
+
+    /// Removes all cached pictures.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.index.entries.clear();
+        // Gone with the directory.
+        self.dirty = false;
+        match fs::remove_dir_all(&self.dir) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Bytes in the cache.
+    pub fn size(&self) -> u64 {
+        self.index.entries.values().map(|e| e.size).fold(0, |a, b| a + b)
+    }
+
+    fn write_file(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        try!(fs::create_dir_all(&self.dir));
+        let mut file = try!(fs::File::create(&self.dir.join(name)));
+        file.write_all(data)
+    }
+
+    // Drops the least recently used entries until we're below the limit.
+    fn evict(&mut self) {
+        let mut size = self.size();
+        while size > self.max_bytes {
+            let oldest = {
+                let mut oldest: Option<(&String, &IndexEntry)> = None;
+                for (name, entry) in self.index.entries.iter() {
+                    match oldest {
+                        Some((_, o)) if o.last_used <= entry.last_used => (),
+                        _ => oldest = Some((name, entry)),
+                    }
+                }
+                match oldest {
+                    Some((name, _)) => name.clone(),
+                    None => return,
+                }
+            };
+            let entry = self.index.entries.remove(&oldest).unwrap();
+            size -= entry.size;
+            match fs::remove_file(&self.dir.join(&oldest)) {
+                Ok(()) => (),
+                Err(err) => debug!("could not remove cached cover `{}`: {}", oldest, err),
+            }
+        }
+    }
+
+    fn save_index(&mut self) {
+        let result = json::encode(&self.index).map_err(utils::encoder_error).and_then(|data| {
+            utils::write_atomically(&self.dir.join("index.json"), data.as_bytes())
+        });
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(err) => warn!("could not save the cover cache index: {}", err),
+        }
+    }
+}
+
+// Two differently keyed 64 bit hashes of the url, in hex.
+fn file_name(url: &str) -> String {
+    let mut hashes = [0; 2];
+    for (i, hash) in hashes.iter_mut().enumerate() {
+        let mut hasher = SipHasher::new_with_keys(i as u64, 0x7275737474726163);
+        hasher.write(url.as_bytes());
+        *hash = hasher.finish();
+    }
+    format!("{:016x}{:016x}", hashes[0], hashes[1])
+}
+
+/// Gets the picture at `url` from the cache, downloading it if it isn't there yet. The cache is
+/// only locked while it's read or written, not during the download.
+pub fn fetch(cache: &SharedCoverCache, url: &str) -> hyper::HttpResult<Vec<u8>> {
+    match cache.lock().unwrap().get(url) {
+        Some(data) => return Ok(data),
+        None => (),
+    }
+    let data = try!(webinterface::get_data_from_url_str(url));
+    cache.lock().unwrap().insert(url, &data[..]);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::CoverCache;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rusttracks-covercache-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn entries_survive_reopening() {
+        let dir = temp_dir("reopen");
+        {
+            let mut cache = CoverCache::open_in(dir.clone(), 100);
+            cache.insert("http://example.com/a.jpg", b"aaaa");
+        }
+        let mut cache = CoverCache::open_in(dir.clone(), 100);
+        assert_eq!(cache.get("http://example.com/a.jpg"), Some(b"aaaa".to_vec()));
+        assert_eq!(cache.get("http://example.com/b.jpg"), None);
+        cache.clear().unwrap();
+        assert_eq!(cache.get("http://example.com/a.jpg"), None);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = temp_dir("evict");
+        let mut cache = CoverCache::open_in(dir.clone(), 10);
+        cache.insert("a", b"1234");
+        cache.insert("b", b"1234");
+        assert!(cache.get("a").is_some());
+        cache.insert("c", b"1234");
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.size(), 8);
+        cache.clear().unwrap();
+    }
+
+    #[test]
+    fn hits_are_written_when_the_cache_goes_away() {
+        let dir = temp_dir("hits");
+        let index = dir.join("index.json");
+        {
+            let mut cache = CoverCache::open_in(dir.clone(), 10);
+            cache.insert("a", b"1234");
+            cache.insert("b", b"1234");
+            fs::remove_file(&index).unwrap();
+            assert!(cache.get("a").is_some());
+            assert!(fs::metadata(&index).is_err());
+        }
+        // b is the least recently used now.
+        let mut cache = CoverCache::open_in(dir.clone(), 10);
+        cache.insert("c", b"1234");
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        cache.clear().unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+// A window with the current mix's cover at full size, opened by clicking the cover on the
+// Current page.
+
+use std::ffi as rffi;
+use std::mem;
+use std::ptr;
+
+use gtk::ffi::*;
+use gtk::*;
+
+use gui::Pixbuf;
+
+pub struct CoverViewer {
+    window: *mut GtkWidget,
+    image: *mut GtkWidget,
+}
+
+impl Drop for CoverViewer {
+    fn drop(&mut self) {
+        unsafe {
+            gtk_widget_destroy(self.window);
+        }
+    }
+}
+
+impl CoverViewer {
+    /// Opens the window, empty until `set_cover` is called. It is only hidden when the user
+    /// closes it and goes away with the returned value.
+    pub fn new(parent: *mut GtkWidget, mix_name: &str) -> CoverViewer {
+        unsafe {
+            let window = gtk_window_new(GTK_WINDOW_TOPLEVEL);
+            let title_c_str = rffi::CString::new(mix_name.as_bytes()).unwrap();
+            gtk_window_set_title(window as *mut GtkWindow, title_c_str.as_ptr());
+            gtk_window_set_transient_for(window as *mut GtkWindow, parent as *mut GtkWindow);
+            gtk_window_set_default_size(window as *mut GtkWindow, 600, 600);
+            let delete_c_str = rffi::CString::new("delete-event").unwrap();
+            g_signal_connect(window as gpointer,
+                             delete_c_str.as_ptr(),
+                             Some(mem::transmute(gtk_widget_hide_on_delete)),
+                             ptr::null_mut());
+
+            // Covers bigger than the window can be scrolled.
+            let scrolled_window = gtk_scrolled_window_new(ptr::null_mut(), ptr::null_mut());
+            gtk_container_add(window as *mut GtkContainer, scrolled_window);
+            let image = gtk_image_new();
+            gtk_container_add(scrolled_window as *mut GtkContainer, image);
+
+            gtk_widget_show_all(window);
+            CoverViewer {
+                window: window,
+                image: image,
+            }
+        }
+    }
+
+    pub fn set_cover(&self, cover: &Pixbuf) {
+        cover.show_in(self.image as *mut GtkImage);
+    }
+
+    pub fn present(&self) {
+        unsafe {
+            gtk_window_present(self.window as *mut GtkWindow);
+        }
+    }
+}
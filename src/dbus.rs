@@ -0,0 +1,48 @@
+// Helpers for talking D-Bus through GDBus: building GVariants and reporting GErrors. The
+// variants returned are floating, GDBus takes ownership of them.
+
+use std::ffi as rffi;
+
+use gtk::ffi::*;
+use gtk::*;
+
+use utils;
+
+pub unsafe fn take_error(err: *mut GError) -> String {
+    if err.is_null() {
+        return "unknown error".to_string();
+    }
+    let message = utils::ptr_to_string((*err).message as *const i8);
+    g_error_free(err);
+    message
+}
+
+// D-Bus strings can't contain NUL bytes, drop them.
+pub fn c_string(s: &str) -> rffi::CString {
+    rffi::CString::new(s.replace("\0", "")).unwrap()
+}
+
+pub unsafe fn bool_variant(b: bool) -> *mut GVariant {
+    g_variant_new_boolean(if b { 1 } else { 0 })
+}
+
+pub unsafe fn string_variant(s: &str) -> *mut GVariant {
+    g_variant_new_string(c_string(s).as_ptr())
+}
+
+pub unsafe fn strv_variant(strings: &[&str]) -> *mut GVariant {
+    let c_strings: Vec<rffi::CString> = strings.iter().map(|s| c_string(s)).collect();
+    let ptrs: Vec<*const gchar> = c_strings.iter().map(|s| s.as_ptr()).collect();
+    g_variant_new_strv(ptrs.as_ptr(), ptrs.len() as gssize)
+}
+
+pub unsafe fn dict_variant(entries: Vec<(&str, *mut GVariant)>) -> *mut GVariant {
+    let builder = g_variant_builder_new(b"a{sv}\0".as_ptr() as *const GVariantType);
+    for (key, value) in entries.into_iter() {
+        let entry = g_variant_new_dict_entry(string_variant(key), g_variant_new_variant(value));
+        g_variant_builder_add_value(builder, entry);
+    }
+    let dict = g_variant_builder_end(builder);
+    g_variant_builder_unref(builder);
+    dict
+}
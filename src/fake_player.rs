@@ -0,0 +1,440 @@
+// An in-memory PlaybackBackend with a virtual clock, so that everything above the player can be
+// tested without GStreamer or an audio device. Tests script what the stream does (progress,
+// buffering, errors, end of stream) and get the same PlayerEvents the real player would send.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+
+use player::{PlaybackBackend, PlayerEvent, StallAction, StallWatchdog, StreamHealth};
+
+// Same as the real player's report timer.
+static REPORT_AFTER_MS: i64 = 30 * 1000;
+
+#[derive(PartialEq, Eq, Debug)]
+enum FakeState {
+    NoUri,
+    Play,
+    Pause,
+    WaitToPlay,
+    // Reached the end of the track, like a pipeline after EOS.
+    Ended,
+}
+
+pub struct FakePlayer {
+    events: mpsc::Sender<PlayerEvent>,
+    state: FakeState,
+
+    // Virtual time, in milliseconds.
+    now: i64,
+    uri: Option<String>,
+    loaded_uris: Vec<String>,
+    // Duration given to the next track passed to set_uri.
+    next_duration: i64,
+    position: i64,
+    duration: i64,
+    buffering_percent: Option<i32>,
+    health: StreamHealth,
+
+    timers_running: bool,
+    // Time the timers ran for the current track, the report is due after 30 seconds of it.
+    timer_time: i64,
+    report_sent: bool,
+    stall_actions: VecDeque<StallAction>,
+    watchdog: StallWatchdog,
+    // The server stopped sending data: the position stands still, and a restarted stream never
+    // prerolls, i.e. doesn't know its position.
+    stream_dead: bool,
+    prerolled: bool,
+    // How often the watchdog restarted the current track.
+    restarts: u32,
+    generation: u32,
+}
+
+impl FakePlayer {
+    pub fn new(events: mpsc::Sender<PlayerEvent>) -> FakePlayer {
+        FakePlayer {
+            events: events,
+            state: FakeState::NoUri,
+            now: 0,
+            uri: None,
+            loaded_uris: Vec::new(),
+            next_duration: 3 * 60 * 1000,
+            position: 0,
+            duration: 0,
+            buffering_percent: None,
+            health: StreamHealth::new(),
+            timers_running: false,
+            timer_time: 0,
+            report_sent: false,
+            stall_actions: VecDeque::new(),
+            watchdog: StallWatchdog::new(),
+            stream_dead: false,
+            prerolled: true,
+            restarts: 0,
+            generation: 0,
+        }
+    }
+
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+
+    pub fn current_uri(&self) -> Option<&str> {
+        self.uri.as_ref().map(|u| &u[..])
+    }
+
+    /// Every uri passed to set_uri, in order.
+    pub fn loaded_uris(&self) -> &[String] {
+        &self.loaded_uris[..]
+    }
+
+    /// Duration of the tracks loaded from now on, in milliseconds.
+    pub fn set_track_duration(&mut self, ms: i64) {
+        self.next_duration = ms;
+    }
+
+    /// How often the watchdog restarted the current track.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// The stream stops delivering data until `revive_stream` is called.
+    pub fn kill_stream(&mut self) {
+        self.stream_dead = true;
+    }
+
+    pub fn revive_stream(&mut self) {
+        self.stream_dead = false;
+        self.prerolled = true;
+    }
+
+    /// Makes the next check_stall return `action` instead of asking the watchdog.
+    pub fn script_stall(&mut self, action: StallAction) {
+        self.stall_actions.push_back(action);
+    }
+
+    /// The stream reports a buffer fill level of `percent`.
+    pub fn buffer(&mut self, percent: i32) {
+        self.events.send(PlayerEvent::Buffering(percent));
+    }
+
+    /// The stream fails with `message`.
+    pub fn fail(&mut self, message: &str) {
+        self.events.send(PlayerEvent::Error(self.generation, message.to_string()));
+    }
+
+    /// The stream ends right now, no matter where we are in the track.
+    pub fn end_of_stream(&mut self) {
+        self.state = FakeState::Ended;
+        self.events.send(PlayerEvent::EndOfStream);
+    }
+
+    /// Moves the virtual clock forward, sending progress, report, watchdog and end of stream
+    /// events on the way just like the real timers and pipeline would.
+    pub fn advance(&mut self, ms: i64) {
+        let mut remaining = ms;
+        while remaining > 0 {
+            // Step to the next full second of either clock, so that progress and watchdog ticks
+            // land where they should.
+            let step = *[remaining, 1000 - self.timer_time % 1000, 1000 - self.now % 1000]
+                .iter().min().unwrap();
+            remaining -= step;
+            self.now += step;
+
+            // Like the real watchdog timer, this one also runs while we wait for the buffer.
+            if self.is_playing() && self.now % 1000 == 0 {
+                self.events.send(PlayerEvent::WatchdogTick);
+            }
+            if self.state != FakeState::Play {
+                continue;
+            }
+            if !self.stream_dead {
+                self.position += step;
+            }
+            if self.timers_running {
+                self.timer_time += step;
+                if self.timer_time % 1000 == 0 {
+                    self.events.send(PlayerEvent::Progress);
+                }
+                if !self.report_sent && self.timer_time >= REPORT_AFTER_MS {
+                    self.report_sent = true;
+                    self.events.send(PlayerEvent::ReportDue);
+                }
+            }
+            if self.position >= self.duration {
+                self.position = self.duration;
+                self.end_of_stream();
+            }
+        }
+    }
+}
+
+impl PlaybackBackend for FakePlayer {
+    fn set_uri(&mut self, uri: &str) {
+        self.stop();
+        self.uri = Some(uri.to_string());
+        self.loaded_uris.push(uri.to_string());
+        self.position = 0;
+        self.duration = self.next_duration;
+        self.buffering_percent = None;
+        self.health = StreamHealth::new();
+        self.watchdog.new_stream();
+        self.prerolled = true;
+        self.restarts = 0;
+        self.generation += 1;
+        self.state = FakeState::WaitToPlay;
+    }
+
+    fn play(&mut self) {
+        match self.state {
+            FakeState::NoUri => panic!("no uri set"),
+            FakeState::Play | FakeState::Ended => return,
+            _ => ()
+        }
+        self.state = FakeState::Play;
+        self.events.send(PlayerEvent::Playing);
+    }
+
+    fn pause(&mut self) {
+        match self.state {
+            FakeState::NoUri | FakeState::Pause | FakeState::Ended => return,
+            _ => ()
+        }
+        self.state = FakeState::Pause;
+        self.events.send(PlayerEvent::Paused);
+    }
+
+    fn stop(&mut self) {
+        self.timers_running = false;
+        self.timer_time = 0;
+        self.report_sent = false;
+        self.state = FakeState::NoUri;
+    }
+
+    fn toggle(&mut self) {
+        match self.state {
+            FakeState::NoUri | FakeState::Ended => (),
+            FakeState::Play | FakeState::WaitToPlay => self.pause(),
+            FakeState::Pause => self.play(),
+        }
+    }
+
+    fn set_buffering(&mut self, percent: i32) {
+        let is_buffering = percent < 100;
+        self.buffering_percent = if is_buffering { Some(percent) } else { None };
+        match self.state {
+            FakeState::Play if is_buffering => {
+                self.state = FakeState::WaitToPlay;
+                self.events.send(PlayerEvent::Paused);
+            }
+            FakeState::WaitToPlay if !is_buffering => {
+                self.state = FakeState::Play;
+                self.events.send(PlayerEvent::Playing);
+            }
+            _ => ()
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.state == FakeState::Play || self.state == FakeState::WaitToPlay
+    }
+
+    fn buffering_percent(&self) -> Option<i32> {
+        if self.state == FakeState::WaitToPlay {
+            self.buffering_percent
+        } else {
+            None
+        }
+    }
+
+    fn stream_health(&self) -> &StreamHealth {
+        &self.health
+    }
+
+    fn start_timers(&mut self) {
+        self.timers_running = true;
+        self.watchdog.idle(self.now * 1000);
+    }
+
+    fn pause_timers(&mut self) {
+        self.timers_running = false;
+    }
+
+    fn get_progress_info(&self) -> Option<(i64, i64)> {
+        if self.state == FakeState::NoUri || !self.prerolled {
+            None
+        } else {
+            Some((self.position * 1000000, self.duration * 1000000))
+        }
+    }
+
+    fn async_done(&mut self) {
+    }
+
+    fn check_stall(&mut self) -> StallAction {
+        match self.stall_actions.pop_front() {
+            Some(action) => return action,
+            None => (),
+        }
+        // The watchdog works in microseconds.
+        let now = self.now * 1000;
+        if !self.is_playing() {
+            self.watchdog.idle(now);
+            return StallAction::Nothing;
+        }
+        let position = self.get_progress_info().map(|(pos, _)| pos);
+        let buffering_percent = self.buffering_percent();
+        let action = self.watchdog.check(now, position, buffering_percent);
+        if action == StallAction::Restarted {
+            // Like a new pipeline for the same uri, seeking to where we were once it prerolls.
+            self.restarts += 1;
+            self.generation += 1;
+            self.prerolled = !self.stream_dead;
+        }
+        action
+    }
+
+    fn stream_generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use player::{PlaybackBackend, PlayerEvent};
+
+    use super::FakePlayer;
+
+    fn new_player() -> (FakePlayer, mpsc::Receiver<PlayerEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        (FakePlayer::new(sender), receiver)
+    }
+
+    // Handles the events a front end has to hand back to the player, returns all of them.
+    fn pump(player: &mut FakePlayer, receiver: &mpsc::Receiver<PlayerEvent>) -> Vec<PlayerEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                PlayerEvent::Playing => player.start_timers(),
+                PlayerEvent::Paused => player.pause_timers(),
+                PlayerEvent::Buffering(p) => player.set_buffering(p),
+                _ => ()
+            }
+            events.push(event);
+        }
+        events
+    }
+
+    fn count<F: Fn(&PlayerEvent) -> bool>(events: &[PlayerEvent], f: F) -> usize {
+        events.iter().filter(|e| f(*e)).count()
+    }
+
+    fn is_report(e: &PlayerEvent) -> bool {
+        match *e { PlayerEvent::ReportDue => true, _ => false }
+    }
+
+    fn is_eos(e: &PlayerEvent) -> bool {
+        match *e { PlayerEvent::EndOfStream => true, _ => false }
+    }
+
+    #[test]
+    fn report_is_due_once_after_thirty_seconds() {
+        let (mut player, receiver) = new_player();
+        player.set_uri("http://example.com/track.mp3");
+        player.play();
+        pump(&mut player, &receiver);
+
+        player.advance(29 * 1000);
+        assert_eq!(count(&pump(&mut player, &receiver)[..], is_report), 0);
+        player.advance(1000);
+        assert_eq!(count(&pump(&mut player, &receiver)[..], is_report), 1);
+        player.advance(60 * 1000);
+        assert_eq!(count(&pump(&mut player, &receiver)[..], is_report), 0);
+    }
+
+    #[test]
+    fn report_timer_stands_still_while_paused() {
+        let (mut player, receiver) = new_player();
+        player.set_uri("http://example.com/track.mp3");
+        player.play();
+        pump(&mut player, &receiver);
+        player.advance(20 * 1000);
+
+        player.toggle();
+        pump(&mut player, &receiver);
+        player.advance(60 * 1000);
+        assert_eq!(count(&pump(&mut player, &receiver)[..], is_report), 0);
+
+        player.toggle();
+        pump(&mut player, &receiver);
+        player.advance(10 * 1000);
+        assert_eq!(count(&pump(&mut player, &receiver)[..], is_report), 1);
+    }
+
+    #[test]
+    fn new_track_resets_the_report_timer() {
+        let (mut player, receiver) = new_player();
+        player.set_uri("http://example.com/1.mp3");
+        player.play();
+        pump(&mut player, &receiver);
+        player.advance(20 * 1000);
+
+        player.set_uri("http://example.com/2.mp3");
+        player.play();
+        pump(&mut player, &receiver);
+        player.advance(20 * 1000);
+        assert_eq!(count(&pump(&mut player, &receiver)[..], is_report), 0);
+        assert_eq!(player.loaded_uris().len(), 2);
+    }
+
+    #[test]
+    fn end_of_stream_when_the_track_is_over() {
+        let (mut player, receiver) = new_player();
+        player.set_track_duration(5 * 1000);
+        player.set_uri("http://example.com/track.mp3");
+        player.play();
+        pump(&mut player, &receiver);
+
+        player.advance(10 * 1000);
+        let events = pump(&mut player, &receiver);
+        assert_eq!(count(&events[..], is_eos), 1);
+        assert_eq!(player.get_progress_info(), Some((5 * 1000000000, 5 * 1000000000)));
+    }
+
+    #[test]
+    fn buffering_stops_the_clock() {
+        let (mut player, receiver) = new_player();
+        player.set_uri("http://example.com/track.mp3");
+        player.play();
+        pump(&mut player, &receiver);
+        player.advance(1000);
+
+        player.buffer(40);
+        pump(&mut player, &receiver);
+        assert!(player.is_playing());
+        assert_eq!(player.buffering_percent(), Some(40));
+        player.advance(10 * 1000);
+        assert_eq!(player.get_progress_info().map(|(pos, _)| pos), Some(1000000000));
+
+        player.buffer(100);
+        pump(&mut player, &receiver);
+        assert_eq!(player.buffering_percent(), None);
+        player.advance(1000);
+        assert_eq!(player.get_progress_info().map(|(pos, _)| pos), Some(2 * 1000000000));
+    }
+
+    #[test]
+    fn errors_are_passed_on() {
+        let (mut player, receiver) = new_player();
+        player.set_uri("http://example.com/track.mp3");
+        player.play();
+        player.fail("connection reset");
+        let events = pump(&mut player, &receiver);
+        assert!(events.iter().any(|e| match *e {
+            PlayerEvent::Error(_, ref m) => &m[..] == "connection reset",
+            _ => false,
+        }));
+    }
+}
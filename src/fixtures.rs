@@ -0,0 +1,129 @@
+// Test data and a harness around AppCore with a FakePlayer, shared by the tests of the core and
+// of the front ends built on it.
+
+use std::sync::mpsc;
+
+use api;
+use app::{AppCore, Command, Input, Listen, NetworkResult, Output, Request, ViewUpdate};
+use fake_player::FakePlayer;
+use player::PlayerEvent;
+
+/// A mix whose covers are at `http://example.com/ID/SIZE.jpg`.
+pub fn mix(id: u32) -> api::Mix {
+    let cover = |size: &str| format!("http://example.com/{}/{}.jpg", id, size);
+    api::Mix {
+        id: id,
+        path: format!("/mixes/{}", id),
+        web_path: format!("/mix-{}", id),
+        name: format!("Mix {}", id),
+        description: String::new(),
+        plays_count: 0,
+        likes_count: 0,
+        certification: None,
+        tags: Vec::new(),
+        duration: 3600,
+        tracks_count: 12,
+        nsfw: false,
+        liked_by_current_user: false,
+        cover_urls: api::CoverUrls {
+            sq56: cover("sq56"),
+            sq100: cover("sq100"),
+            sq133: cover("sq133"),
+            max133w: cover("max133w"),
+            max200: cover("max200"),
+            sq250: cover("sq250"),
+            sq500: cover("sq500"),
+            max1024: cover("max1024"),
+            original: cover("original"),
+        },
+        first_published_at: None,
+        user_id: 1,
+        user_login: None,
+    }
+}
+
+pub fn track(id: u32) -> api::Track {
+    api::Track {
+        id: id,
+        name: format!("Track {}", id),
+        performer: "Somebody".to_string(),
+        release_name: Some("Some Album".to_string()),
+        year: Some(1999),
+        track_file_stream_url: format!("http://example.com/{}.mp3", id),
+        buy_link: String::new(),
+        faved_by_current_user: false,
+        url: format!("http://example.com/tracks/{}", id),
+    }
+}
+
+pub fn play_state(track: Option<api::Track>, at_end: bool, skip_allowed: bool) -> api::PlayState {
+    api::PlayState {
+        at_beginning: false,
+        at_last_track: false,
+        at_end: at_end,
+        skip_allowed: skip_allowed,
+        track: track,
+    }
+}
+
+/// A core with a play token and mixes 1 and 2 loaded, collecting everything it outputs.
+pub struct Harness {
+    pub core: AppCore<FakePlayer>,
+    pub events: mpsc::Receiver<PlayerEvent>,
+    pub requests: Vec<Request>,
+    pub views: Vec<ViewUpdate>,
+    pub listens: Vec<Listen>,
+}
+
+impl Harness {
+    pub fn new() -> Harness {
+        let (sender, receiver) = mpsc::channel();
+        let mut h = Harness {
+            core: AppCore::new(FakePlayer::new(sender), 1),
+            events: receiver,
+            requests: Vec::new(),
+            views: Vec::new(),
+            listens: Vec::new(),
+        };
+        h.input(Input::Network(NetworkResult::PlayToken(api::PlayToken { s: "pt".to_string() })));
+        h.input(Input::Network(NetworkResult::Mixes(vec![mix(1), mix(2)])));
+        h
+    }
+
+    pub fn input(&mut self, input: Input) {
+        for output in self.core.handle(input) {
+            match output {
+                Output::Request(r) => self.requests.push(r),
+                Output::View(v) => self.views.push(v),
+                Output::Listened(l) => self.listens.push(l),
+            }
+        }
+    }
+
+    /// Hands all pending player events to the core, like the front ends do.
+    pub fn pump(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            self.input(Input::Player(event));
+        }
+    }
+
+    pub fn advance(&mut self, ms: i64) {
+        self.core.player_mut().advance(ms);
+        self.pump();
+    }
+
+    /// Plays the mix at index `i`, starting with track `first_track`, and forgets what the core
+    /// said on the way.
+    pub fn start_mix(&mut self, i: usize, first_track: u32) {
+        self.start_mix_with_skip(i, first_track, true);
+    }
+
+    pub fn start_mix_with_skip(&mut self, i: usize, first_track: u32, skip_allowed: bool) {
+        self.input(Input::Command(Command::PlayMix(i)));
+        self.input(Input::Network(NetworkResult::PlayState(
+            play_state(Some(track(first_track)), false, skip_allowed))));
+        self.pump();
+        self.requests.clear();
+        self.views.clear();
+    }
+}
@@ -0,0 +1,602 @@
+// A small safe layer over the parts of GStreamer the player needs. All raw pointers stay in
+// here, the wrappers own exactly one reference and release it when dropped.
+
+use std::cell::Cell;
+use std::ffi as rffi;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+use log;
+
+use gtk::*;
+use gtk::ffi::*;
+
+use utils;
+
+/// Initializes GStreamer and returns the arguments it didn't consume.
+pub fn init(args: Vec<String>) -> Vec<String> {
+    unsafe {
+        gst_init_with_args(args)
+    }
+}
+
+/// Must only be called once all GStreamer objects are gone.
+pub unsafe fn deinit() {
+    gst_deinit();
+}
+
+unsafe fn take_string(p: *mut gchar) -> Option<String> {
+    if p.is_null() {
+        None
+    } else {
+        let s = utils::ptr_to_string(p as *const i8);
+        g_free(p as gpointer);
+        Some(s)
+    }
+}
+
+unsafe fn object_name(obj: *mut GstObject) -> String {
+    if obj.is_null() {
+        "null-source".to_string()
+    } else {
+        take_string(gst_object_get_name(obj)).unwrap_or_else(|| "null-name".to_string())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    VoidPending,
+    Null,
+    Ready,
+    Paused,
+    Playing,
+}
+
+impl State {
+    fn from_raw(state: GstState) -> State {
+        match state {
+            GST_STATE_NULL => State::Null,
+            GST_STATE_READY => State::Ready,
+            GST_STATE_PAUSED => State::Paused,
+            GST_STATE_PLAYING => State::Playing,
+            _ => State::VoidPending,
+        }
+    }
+
+    fn to_raw(self) -> GstState {
+        match self {
+            State::VoidPending => GST_STATE_VOID_PENDING,
+            State::Null => GST_STATE_NULL,
+            State::Ready => GST_STATE_READY,
+            State::Paused => GST_STATE_PAUSED,
+            State::Playing => GST_STATE_PLAYING,
+        }
+    }
+}
+
+pub struct Element {
+    ptr: *mut GstElement,
+}
+
+impl Element {
+    /// Takes ownership of a (possibly floating) reference.
+    unsafe fn from_raw(ptr: *mut GstElement) -> Option<Element> {
+        if ptr.is_null() {
+            None
+        } else {
+            gst_object_ref_sink(ptr as gpointer);
+            Some(Element { ptr: ptr })
+        }
+    }
+
+    pub fn factory_make(factory: &str, name: Option<&str>) -> Option<Element> {
+        let factory_c_str = rffi::CString::new(factory).unwrap();
+        let name_c_str = name.map(|n| rffi::CString::new(n).unwrap());
+        unsafe {
+            let ptr = gst_element_factory_make(
+                factory_c_str.as_ptr(),
+                name_c_str.as_ref().map(|n| n.as_ptr()).unwrap_or(ptr::null()));
+            Element::from_raw(ptr)
+        }
+    }
+
+    pub fn name(&self) -> String {
+        unsafe { object_name(self.ptr as *mut GstObject) }
+    }
+
+    pub fn set_state(&self, state: State) {
+        unsafe {
+            gst_element_set_state(self.ptr, state.to_raw());
+        }
+    }
+
+    pub fn bus(&self) -> Option<Bus> {
+        unsafe {
+            let ptr = gst_element_get_bus(self.ptr);
+            if ptr.is_null() { None } else { Some(Bus { ptr: ptr }) }
+        }
+    }
+
+    pub fn set_string_property(&self, name: &str, value: &str) {
+        let name_c_str = rffi::CString::new(name).unwrap();
+        let value_c_str = rffi::CString::new(value).unwrap();
+        unsafe {
+            g_object_set(self.ptr as gpointer,
+                         name_c_str.as_ptr(), value_c_str.as_ptr(), ptr::null::<gchar>());
+        }
+    }
+
+    /// The property takes its own reference, `value` stays usable.
+    pub fn set_element_property(&self, name: &str, value: &Element) {
+        let name_c_str = rffi::CString::new(name).unwrap();
+        unsafe {
+            g_object_set(self.ptr as gpointer,
+                         name_c_str.as_ptr(), value.ptr, ptr::null::<gchar>());
+        }
+    }
+
+    pub fn set_double_property(&self, name: &str, value: f64) {
+        let name_c_str = rffi::CString::new(name).unwrap();
+        unsafe {
+            g_object_set(self.ptr as gpointer,
+                         name_c_str.as_ptr(), value as gdouble, ptr::null::<gchar>());
+        }
+    }
+
+    pub fn double_property(&self, name: &str) -> f64 {
+        let name_c_str = rffi::CString::new(name).unwrap();
+        let mut value: gdouble = 0.;
+        unsafe {
+            g_object_get(self.ptr as gpointer,
+                         name_c_str.as_ptr(), &mut value as *mut gdouble, ptr::null::<gchar>());
+        }
+        value
+    }
+
+    /// Current position in nanoseconds.
+    pub fn query_position(&self) -> Option<i64> {
+        let mut position = 0;
+        let success = unsafe {
+            gst_element_query_position(self.ptr, GST_FORMAT_TIME, &mut position)
+        };
+        if success != 0 { Some(position) } else { None }
+    }
+
+    /// Duration in nanoseconds.
+    pub fn query_duration(&self) -> Option<i64> {
+        let mut duration = 0;
+        let success = unsafe {
+            gst_element_query_duration(self.ptr, GST_FORMAT_TIME, &mut duration)
+        };
+        if success != 0 { Some(duration) } else { None }
+    }
+
+    /// Flushing seek to the key unit nearest to `position` (in nanoseconds).
+    pub fn seek(&self, position: i64) -> bool {
+        unsafe {
+            gst_element_seek_simple(self.ptr, GST_FORMAT_TIME,
+                                    GST_SEEK_FLAG_FLUSH | GST_SEEK_FLAG_KEY_UNIT,
+                                    position) != 0
+        }
+    }
+
+    /// Format of the first audio stream, only makes sense for playbin.
+    pub fn current_audio_format(&self) -> Option<AudioFormat> {
+        unsafe {
+            let mut pad: *mut GstPad = ptr::null_mut();
+            let signal_c_str = rffi::CString::new("get-audio-pad").unwrap();
+            g_signal_emit_by_name(self.ptr as gpointer, signal_c_str.as_ptr(), 0 as gint, &mut pad);
+            if pad.is_null() {
+                return None;
+            }
+            let caps = gst_pad_get_current_caps(pad);
+            gst_object_unref(pad as gpointer);
+            if caps.is_null() {
+                return None;
+            }
+
+            let structure = gst_caps_get_structure(caps, 0);
+            let get_int = |field: &str| {
+                let field_c_str = rffi::CString::new(field).unwrap();
+                let mut value = 0;
+                if gst_structure_get_int(structure, field_c_str.as_ptr(), &mut value) != 0 {
+                    Some(value)
+                } else {
+                    None
+                }
+            };
+            let format = AudioFormat {
+                sample_rate: get_int("rate"),
+                channels: get_int("channels"),
+            };
+            gst_caps_unref(caps);
+            Some(format)
+        }
+    }
+}
+
+impl Clone for Element {
+    fn clone(&self) -> Element {
+        unsafe {
+            gst_object_ref(self.ptr as gpointer);
+        }
+        Element { ptr: self.ptr }
+    }
+}
+
+impl Drop for Element {
+    fn drop(&mut self) {
+        unsafe {
+            gst_object_unref(self.ptr as gpointer);
+        }
+    }
+}
+
+// Not every binding we build against has these, declared here like tui.rs does with `signal`.
+extern {
+    fn gst_bin_new(name: *const gchar) -> *mut GstElement;
+    fn gst_bin_add(bin: *mut GstElement, element: *mut GstElement) -> gboolean;
+    fn gst_bin_remove(bin: *mut GstElement, element: *mut GstElement) -> gboolean;
+    fn gst_element_link(src: *mut GstElement, dest: *mut GstElement) -> gboolean;
+    fn gst_element_unlink(src: *mut GstElement, dest: *mut GstElement);
+    fn gst_element_get_static_pad(element: *mut GstElement, name: *const gchar) -> *mut GstPad;
+    fn gst_element_add_pad(element: *mut GstElement, pad: *mut GstPad) -> gboolean;
+    fn gst_element_sync_state_with_parent(element: *mut GstElement) -> gboolean;
+    fn gst_pad_get_peer(pad: *mut GstPad) -> *mut GstPad;
+    fn gst_pad_get_parent_element(pad: *mut GstPad) -> *mut GstElement;
+    fn gst_ghost_pad_new(name: *const gchar, target: *mut GstPad) -> *mut GstPad;
+    fn gst_pad_add_probe(pad: *mut GstPad, mask: guint,
+                         callback: extern "C" fn(*mut GstPad, gpointer, gpointer) -> guint,
+                         user_data: gpointer,
+                         destroy_data: extern "C" fn(gpointer)) -> gulong;
+}
+
+// GST_PAD_PROBE_TYPE_BLOCK | GST_PAD_PROBE_TYPE_DATA_DOWNSTREAM
+const PROBE_BLOCK_DOWNSTREAM: guint = 0x2 | 0x10 | 0x20 | 0x40;
+const PROBE_REMOVE: guint = 2;
+
+/// A bin of a queue and an exchangeable sink, for use as playbin's `audio-sink`. The sink can
+/// be replaced while the pipeline keeps running.
+pub struct SwitchSink {
+    bin: Element,
+    queue: Element,
+}
+
+impl SwitchSink {
+    pub fn new(sink: Element) -> Option<SwitchSink> {
+        let queue = match Element::factory_make("queue", None) {
+            Some(q) => q,
+            None => return None,
+        };
+        let sink_c_str = rffi::CString::new("sink").unwrap();
+        unsafe {
+            let bin = match Element::from_raw(gst_bin_new(ptr::null())) {
+                Some(b) => b,
+                None => return None,
+            };
+            // The bin takes its own references.
+            gst_bin_add(bin.ptr, queue.ptr);
+            gst_bin_add(bin.ptr, sink.ptr);
+            if gst_element_link(queue.ptr, sink.ptr) == 0 {
+                return None;
+            }
+            let target = gst_element_get_static_pad(queue.ptr, sink_c_str.as_ptr());
+            let ghost = gst_ghost_pad_new(sink_c_str.as_ptr(), target);
+            gst_object_unref(target as gpointer);
+            gst_element_add_pad(bin.ptr, ghost);
+
+            Some(SwitchSink { bin: bin, queue: queue })
+        }
+    }
+
+    pub fn element(&self) -> &Element {
+        &self.bin
+    }
+
+    /// Replaces the sink as soon as the next buffer or event reaches it. Until then, and if the
+    /// pipeline isn't running, the old sink stays in place.
+    pub fn switch_to(&self, sink: Element) {
+        let data = Box::new(SwitchData {
+            bin: self.bin.clone(),
+            queue: self.queue.clone(),
+            new_sink: sink,
+        });
+        let src_c_str = rffi::CString::new("src").unwrap();
+        unsafe {
+            let pad = gst_element_get_static_pad(self.queue.ptr, src_c_str.as_ptr());
+            gst_pad_add_probe(pad, PROBE_BLOCK_DOWNSTREAM, switch_probe,
+                              mem::transmute::<Box<SwitchData>, gpointer>(data), switch_destroy);
+            gst_object_unref(pad as gpointer);
+        }
+    }
+}
+
+// What a pending switch owns, freed by switch_destroy once the probe is gone.
+struct SwitchData {
+    bin: Element,
+    queue: Element,
+    new_sink: Element,
+}
+
+// Runs in the streaming thread while the queue's source pad is blocked, so nothing is flowing
+// into the old sink.
+extern "C" fn switch_probe(pad: *mut GstPad, _info: gpointer, data: gpointer) -> guint {
+    let data = unsafe { &*(data as *const SwitchData) };
+    unsafe {
+        let peer = gst_pad_get_peer(pad);
+        if !peer.is_null() {
+            let old_sink = gst_pad_get_parent_element(peer);
+            gst_object_unref(peer as gpointer);
+            if !old_sink.is_null() {
+                gst_element_unlink(data.queue.ptr, old_sink);
+                gst_element_set_state(old_sink, GST_STATE_NULL);
+                gst_bin_remove(data.bin.ptr, old_sink);
+                gst_object_unref(old_sink as gpointer);
+            }
+        }
+
+        gst_bin_add(data.bin.ptr, data.new_sink.ptr);
+        gst_element_link(data.queue.ptr, data.new_sink.ptr);
+        gst_element_sync_state_with_parent(data.new_sink.ptr);
+    }
+    PROBE_REMOVE
+}
+
+extern "C" fn switch_destroy(data: gpointer) {
+    unsafe {
+        mem::transmute::<gpointer, Box<SwitchData>>(data);
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AudioFormat {
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+}
+
+pub struct Device {
+    ptr: *mut GstDevice,
+}
+
+impl Device {
+    /// All audio sinks the device monitor knows about.
+    pub fn audio_sinks() -> Vec<Device> {
+        let class_c_str = rffi::CString::new("Audio/Sink").unwrap();
+        let mut devices = Vec::new();
+        unsafe {
+            let monitor = gst_device_monitor_new();
+            gst_device_monitor_add_filter(monitor, class_c_str.as_ptr(), ptr::null_mut());
+            let list = gst_device_monitor_get_devices(monitor);
+            if !list.is_null() {
+                for ptr in GListIterator::new(&*list) {
+                    // The list holds a reference on each device, which we take over.
+                    devices.push(Device { ptr: ptr as *mut GstDevice });
+                }
+                g_list_free(list);
+            }
+            gst_object_unref(monitor as gpointer);
+        }
+        devices
+    }
+
+    pub fn display_name(&self) -> String {
+        unsafe {
+            take_string(gst_device_get_display_name(self.ptr)).unwrap_or_else(String::new)
+        }
+    }
+
+    pub fn create_element(&self) -> Option<Element> {
+        unsafe {
+            Element::from_raw(gst_device_create_element(self.ptr, ptr::null()))
+        }
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            gst_object_unref(self.ptr as gpointer);
+        }
+    }
+}
+
+pub struct TagList {
+    ptr: *mut GstTagList,
+}
+
+impl TagList {
+    pub fn get_string(&self, tag: &str) -> Option<String> {
+        let tag_c_str = rffi::CString::new(tag).unwrap();
+        let mut value = ptr::null_mut();
+        unsafe {
+            if gst_tag_list_get_string(self.ptr, tag_c_str.as_ptr(), &mut value) == 0 {
+                return None;
+            }
+            take_string(value)
+        }
+    }
+
+    pub fn get_uint(&self, tag: &str) -> Option<u32> {
+        let tag_c_str = rffi::CString::new(tag).unwrap();
+        let mut value = 0;
+        let success = unsafe {
+            gst_tag_list_get_uint(self.ptr, tag_c_str.as_ptr(), &mut value)
+        };
+        if success != 0 { Some(value) } else { None }
+    }
+
+    /// The year of the `datetime` tag.
+    pub fn get_year(&self) -> Option<u32> {
+        let tag_c_str = rffi::CString::new("datetime").unwrap();
+        let mut date_time = ptr::null_mut();
+        unsafe {
+            if gst_tag_list_get_date_time(self.ptr, tag_c_str.as_ptr(), &mut date_time) == 0
+                    || date_time.is_null() {
+                return None;
+            }
+            let year = if gst_date_time_has_year(date_time) != 0 {
+                Some(gst_date_time_get_year(date_time) as u32)
+            } else {
+                None
+            };
+            gst_date_time_unref(date_time);
+            year
+        }
+    }
+}
+
+impl Drop for TagList {
+    fn drop(&mut self) {
+        unsafe {
+            gst_tag_list_unref(self.ptr);
+        }
+    }
+}
+
+pub enum Message {
+    Error { src: String, message: String, debug: Option<String> },
+    Warning { src: String, message: String, debug: Option<String> },
+    Info { src: String, message: String, debug: Option<String> },
+    Eos { src: String },
+    StateChanged { src: String, old: State, new: State },
+    Buffering { src: String, percent: i32 },
+    Tag { src: String, tags: TagList },
+    AsyncDone { src: String },
+    Other { src: String, type_name: String },
+}
+
+type ParseFn = unsafe extern "C" fn(*mut GstMessage, *mut *mut GError, *mut *mut gchar);
+
+impl Message {
+    unsafe fn from_raw(msg: *mut GstMessage) -> Message {
+        let src = object_name((*msg).src);
+
+        let parse_gerror = |parse: ParseFn| {
+            let mut err = ptr::null_mut();
+            let mut dbg_info = ptr::null_mut();
+            parse(msg, &mut err, &mut dbg_info);
+            let message = if err.is_null() {
+                String::new()
+            } else {
+                let m = utils::ptr_to_string((*err).message as *const i8);
+                g_error_free(err);
+                m
+            };
+            (message, take_string(dbg_info))
+        };
+
+        match (*msg)._type {
+            GST_MESSAGE_ERROR => {
+                let (message, debug) = parse_gerror(gst_message_parse_error);
+                Message::Error { src: src, message: message, debug: debug }
+            }
+            // Warnings and infos are only logged, don't bother parsing them if they wouldn't be.
+            GST_MESSAGE_WARNING if log_enabled!(log::WARN) => {
+                let (message, debug) = parse_gerror(gst_message_parse_warning);
+                Message::Warning { src: src, message: message, debug: debug }
+            }
+            GST_MESSAGE_INFO if log_enabled!(log::INFO) => {
+                let (message, debug) = parse_gerror(gst_message_parse_info);
+                Message::Info { src: src, message: message, debug: debug }
+            }
+            GST_MESSAGE_EOS => Message::Eos { src: src },
+            GST_MESSAGE_STATE_CHANGED => {
+                let mut old = 0;
+                let mut new = 0;
+                gst_message_parse_state_changed(msg, &mut old, &mut new, ptr::null_mut());
+                Message::StateChanged {
+                    src: src,
+                    old: State::from_raw(old),
+                    new: State::from_raw(new),
+                }
+            }
+            GST_MESSAGE_BUFFERING => {
+                let mut percent = 0;
+                gst_message_parse_buffering(msg, &mut percent);
+                Message::Buffering { src: src, percent: percent }
+            }
+            GST_MESSAGE_TAG => {
+                // We get our own reference on the tag list.
+                let mut tags = ptr::null_mut();
+                gst_message_parse_tag(msg, &mut tags);
+                if tags.is_null() {
+                    Message::Other { src: src, type_name: "tag".to_string() }
+                } else {
+                    Message::Tag { src: src, tags: TagList { ptr: tags } }
+                }
+            }
+            GST_MESSAGE_ASYNC_DONE => Message::AsyncDone { src: src },
+            other => {
+                let type_name = utils::ptr_to_string(
+                    gst_message_type_get_name(other) as *const i8);
+                Message::Other { src: src, type_name: type_name }
+            }
+        }
+    }
+}
+
+// What the bus watch's source owns.
+struct WatchData {
+    callback: Box<FnMut(Message) -> bool>,
+    // Cleared when GLib destroys the source, BusWatch must not remove it again then.
+    alive: Rc<Cell<bool>>,
+}
+
+pub struct Bus {
+    ptr: *mut GstBus,
+}
+
+impl Bus {
+    /// Calls `callback` for every message on the bus from the default main context, until it
+    /// returns false or the returned `BusWatch` is dropped.
+    pub fn add_watch<F: FnMut(Message) -> bool + 'static>(&self, callback: F) -> BusWatch {
+        let alive = Rc::new(Cell::new(true));
+        let data = Box::new(WatchData { callback: Box::new(callback), alive: alive.clone() });
+        let id = unsafe {
+            // The closure is owned by the watch and freed in bus_watch_destroy.
+            gst_bus_add_watch_full(self.ptr, G_PRIORITY_DEFAULT, Some(bus_watch_trampoline),
+                                   mem::transmute::<Box<WatchData>, gpointer>(data),
+                                   Some(bus_watch_destroy))
+        };
+        BusWatch { id: id, alive: alive }
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        unsafe {
+            gst_object_unref(self.ptr as gpointer);
+        }
+    }
+}
+
+pub struct BusWatch {
+    id: guint,
+    alive: Rc<Cell<bool>>,
+}
+
+impl Drop for BusWatch {
+    fn drop(&mut self) {
+        // Gone already if the callback returned false, and the id may belong to another source
+        // by now.
+        if self.alive.get() {
+            unsafe {
+                g_source_remove(self.id);
+            }
+        }
+    }
+}
+
+extern "C" fn bus_watch_trampoline(_bus: *mut GstBus, msg: *mut GstMessage,
+                                   data: gpointer) -> gboolean {
+    let data = unsafe { &mut *(data as *mut WatchData) };
+    let message = unsafe { Message::from_raw(msg) };
+    // Returning 0 removes the watch
+    if (data.callback)(message) { 1 } else { 0 }
+}
+
+extern "C" fn bus_watch_destroy(data: gpointer) {
+    let data = unsafe { mem::transmute::<gpointer, Box<WatchData>>(data) };
+    data.alive.set(false);
+}
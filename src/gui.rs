@@ -3,16 +3,35 @@ use libc;
 use std::ffi as rffi;
 use std::ptr;
 use std::mem;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 
 use gtk::ffi::*;
 use gtk::*;
 
+use rustc_serialize::json::ToJson;
+
 use api;
+use app;
+use config;
+use control;
+use covercache;
+use coverviewer;
+use dbus;
+use history;
+use listenlog;
+use mixdetails;
+use mpd;
+use mpris;
+use network;
+use notifications;
 use player;
+use player::PlaybackBackend;
+use remote;
+use scrobbler;
+use shortcuts;
 use utils;
-use webinterface;
 
 fn as_box<T>(in_ptr: *mut T) -> *mut GtkBox {
     in_ptr as *mut GtkBox
@@ -25,6 +44,13 @@ fn get_icon_pixbuf() -> *mut GdkPixbuf {
 }
 
 fn get_pixbuf_from_data(pic_data: &[u8]) -> *mut GdkPixbuf {
+    let pixbuf = decode_pixbuf(pic_data);
+    assert!(pixbuf != ptr::null_mut());
+    pixbuf
+}
+
+// Null if the data isn't a picture gdk-pixbuf understands.
+fn decode_pixbuf(pic_data: &[u8]) -> *mut GdkPixbuf {
     unsafe {
         let mut err = ptr::null_mut();
 
@@ -32,16 +58,120 @@ fn get_pixbuf_from_data(pic_data: &[u8]) -> *mut GdkPixbuf {
             pic_data.as_ptr() as *const libc::c_void,
             pic_data.len() as i64, None);
         let pixbuf = gdk_pixbuf_new_from_stream(stream, ptr::null_mut(), &mut err);
-        assert!(pixbuf != ptr::null_mut());
         g_input_stream_close(stream, ptr::null_mut(), &mut err);
         pixbuf
     }
 }
 
+/// A pixbuf we hold a reference to. gdk-pixbuf doesn't mind being used from other threads as
+/// long as only one of them uses it at a time, so these are decoded and scaled by the download
+/// threads and handed to the GUI thread ready to be shown.
+pub struct Pixbuf(*mut GdkPixbuf);
+
+unsafe impl Send for Pixbuf {}
+
+impl Pixbuf {
+    /// Decodes `data` and scales it to a `size` by `size` square, if there is a size. None if it
+    /// isn't a picture.
+    fn from_data(data: &[u8], size: Option<libc::c_int>) -> Option<Pixbuf> {
+        let decoded = decode_pixbuf(data);
+        if decoded == ptr::null_mut() {
+            return None;
+        }
+        let size = match size {
+            Some(s) => s,
+            None => return Some(Pixbuf(decoded)),
+        };
+        unsafe {
+            let scaled = gdk_pixbuf_scale_simple(&*decoded, size, size, GDK_INTERP_BILINEAR);
+            gdk_pixbuf_unref(decoded);
+            Some(Pixbuf(scaled))
+        }
+    }
+
+    fn as_ptr(&self) -> *mut GdkPixbuf {
+        self.0
+    }
+
+    /// Shows the pixbuf in `image` with one of its pixels per device pixel, so that on a HiDPI
+    /// screen a pixbuf of the image's size times the scale factor comes out sharp.
+    pub fn show_in(&self, image: *mut GtkImage) {
+        unsafe {
+            let widget = image as *mut GtkWidget;
+            let scale = gtk_widget_get_scale_factor(widget);
+            if scale <= 1 {
+                gtk_image_set_from_pixbuf(image, self.0);
+                return;
+            }
+            let surface = gdk_cairo_surface_create_from_pixbuf(self.0, scale,
+                                                               gtk_widget_get_window(widget));
+            gtk_image_set_from_surface(image, surface);
+            cairo_surface_destroy(surface);
+        }
+    }
+}
+
+/// The device pixels `size` pixels take up in `widget`, for picking and scaling pictures.
+fn device_pixels(widget: *mut GtkWidget, size: libc::c_int) -> libc::c_int {
+    unsafe { size * gtk_widget_get_scale_factor(widget) }
+}
+
+impl Drop for Pixbuf {
+    fn drop(&mut self) {
+        unsafe {
+            gdk_pixbuf_unref(self.0);
+        }
+    }
+}
+
+// Downloads (or takes from the cache) the picture at `url` and scales it to each of `sizes`
+// (None keeps it as it is), in a new thread. `done` gets the pixbufs unless `generation` moved
+// on from `expected` first, the pictures wouldn't be shown anymore then.
+fn fetch_cover<F>(cover_cache: &covercache::SharedCoverCache, url: String,
+                  sizes: Vec<Option<libc::c_int>>,
+                  generation: &Arc<AtomicUsize>, sender: &mpsc::Sender<GuiUpdateMessage>, done: F)
+    where F: FnOnce(Vec<Pixbuf>) -> GuiUpdateMessage + Send + 'static
+{
+    let cover_cache = cover_cache.clone();
+    let generation = generation.clone();
+    let expected = generation.load(Ordering::SeqCst);
+    let sender = sender.clone();
+    thread::spawn(move || {
+        if generation.load(Ordering::SeqCst) != expected {
+            return;
+        }
+        let pic_data = match covercache::fetch(&cover_cache, &url[..]) {
+            Ok(pd) => pd,
+            Err(io_err) => {
+                let message = format!("Could not get picture: `{}`", io_err);
+                sender.send(GuiUpdateMessage::Notify(message));
+                return;
+            }
+        };
+        if generation.load(Ordering::SeqCst) != expected {
+            return;
+        }
+        let mut pixbufs = Vec::new();
+        for &size in sizes.iter() {
+            match Pixbuf::from_data(&pic_data[..], size) {
+                Some(p) => pixbufs.push(p),
+                None => {
+                    warn!("`{}` is not a picture we can show", url);
+                    return;
+                }
+            }
+        }
+        sender.send(done(pixbufs));
+    });
+}
+
 static PLAY_ICON_NAME: &'static str = "media-playback-start";
 static PAUSE_ICON_NAME: &'static str = "media-playback-pause";
 static SKIP_ICON_NAME: &'static str = "media-skip-forward";
 
+// How much the volume shortcuts change the volume.
+static VOLUME_STEP: f64 = 0.05;
+
 enum MixesOrdering {
     Popular = 0,
     New = 1,
@@ -53,23 +183,34 @@ struct GuiGSource {
 }
 
 pub enum GuiUpdateMessage {
-    FetchPlayToken,
-    SetPlayToken(api::PlayToken),
-    GetMixes(String),
-    UpdateMixes(Vec<api::Mix>),
-    PlayMix(usize),
-    PlayTrack(api::Track),
-    ReportCurrentTrack,
-    TogglePlaying,
-    SetBuffering(bool),
-    NextTrack,
-    SkipTrack,
-    SetPic(usize, Vec<u8>),
-    SetCurrentPic(Vec<u8>),
-    UpdateProgress,
+    Command(app::Command),
+    Network(app::NetworkResult),
+    Player(player::PlayerEvent),
+    /// A mix row's cover, for the mix list of the given generation.
+    SetPic(usize, usize, Pixbuf),
+    /// The current mix's cover and the smaller one for notifications, for the given generation.
+    SetCurrentPic(usize, Pixbuf, Pixbuf),
+    /// The full size cover for the cover viewer of the given generation.
+    SetViewerPic(usize, Pixbuf),
     Notify(String),
-    StartTimers,
-    PauseTimers,
+    SetAudioDevice(Option<usize>),
+    /// Answered with a snapshot of the app's state, for front ends on other threads.
+    Query(mpsc::Sender<app::Status>),
+    /// Brings the window to the front.
+    Raise,
+    /// The sender gets a new snapshot whenever the track, the playback state or the mix list
+    /// changed.
+    Subscribe(mpsc::Sender<app::Status>),
+}
+
+/// Asks the GUI thread for a snapshot of the app's state. Blocks, so it must not be called from
+/// the GUI thread itself. None if the GUI is gone.
+pub fn query_status(sender: &mpsc::Sender<GuiUpdateMessage>) -> Option<app::Status> {
+    let (reply_sender, reply_receiver) = mpsc::channel();
+    if sender.send(GuiUpdateMessage::Query(reply_sender)).is_err() {
+        return None;
+    }
+    reply_receiver.recv().ok()
 }
 
 struct LoadingImage {
@@ -92,6 +233,15 @@ impl LoadingImage {
         }
     }
 
+    fn set_pixbuf(&mut self, pixbuf: &Pixbuf) {
+        pixbuf.show_in(self.image);
+    }
+
+    /// The size a picture for this image should have on the screen it's on.
+    fn pixel_size(&self) -> libc::c_int {
+        device_pixels(self.image as *mut GtkWidget, self.size)
+    }
+
     fn set_image_from_data(&mut self, data: &[u8]) {
         unsafe {
             let pixbuf1 = get_pixbuf_from_data(data);
@@ -112,16 +262,35 @@ struct MixEntry {
 
     widget: *mut GtkWidget,
     image: LoadingImage,
+    // Once it's downloaded.
+    cover: Option<Pixbuf>,
 }
 
 impl MixEntry {
     fn new(mix: api::Mix, mix_table_entry: &(*mut Gui, usize)) -> MixEntry {
         let (widget, image) = unsafe {
+            // Clicking anywhere but the Play button opens the details.
+            let event_box = gtk_event_box_new();
             let entry_box = gtk_box_new(GTK_ORIENTATION_HORIZONTAL, 5);
+            gtk_container_add(event_box as *mut GtkContainer, entry_box);
+            {
+                let signal = rffi::CString::new("button-press-event").unwrap();
+                g_signal_connect(event_box as gpointer,
+                                 signal.as_ptr(),
+                                 Some(mem::transmute(mix_entry_clicked)),
+                                 mem::transmute::<&(*mut Gui, usize), gpointer>(mix_table_entry));
+            }
 
             let label = {
-                let text = rffi::CString::new(mix.name.as_bytes()).unwrap();
-                gtk_label_new(text.as_ptr())
+                let mut markup = utils::markup_escape(&mix.name[..]);
+                if mix.nsfw {
+                    markup.push_str(" ");
+                    markup.push_str(&mixdetails::nsfw_badge()[..]);
+                }
+                let text = rffi::CString::new(markup).unwrap();
+                let label = gtk_label_new(ptr::null());
+                gtk_label_set_markup(label as *mut GtkLabel, text.as_ptr());
+                label
             };
             gtk_box_pack_start(as_box(entry_box), label, 1, 1, 0);
             gtk_label_set_line_wrap(label as *mut GtkLabel, 1);
@@ -148,18 +317,20 @@ impl MixEntry {
                                  mem::transmute::<&(*mut Gui, usize), gpointer>(mix_table_entry));
             }
 
-            (entry_box, image)
+            (event_box, image)
         };
 
         MixEntry {
             mix: mix,
             widget: widget,
             image: image,
+            cover: None,
         }
     }
 
-    fn set_pic_from_data(&mut self, data: &[u8]) {
-        self.image.set_image_from_data(data);
+    fn set_pic(&mut self, pixbuf: Pixbuf) {
+        self.image.set_pixbuf(&pixbuf);
+        self.cover = Some(pixbuf);
     }
 }
 
@@ -168,10 +339,30 @@ pub struct Gui {
     running: bool,
 
     mix_entries: Vec<MixEntry>,
-    play_token: Option<api::PlayToken>,
 
-    current_mix_index: Option<usize>,
-    current_track: Option<api::Track>,
+    core: app::AppCore<player::Player>,
+    config: config::Config,
+    key_bindings: shortcuts::KeyBindings,
+    mpris: Option<mpris::Mpris>,
+    notifier: Option<notifications::Notifier>,
+    control_server: Option<control::ControlServer>,
+    status_subscribers: Vec<mpsc::Sender<app::Status>>,
+    // The detail window of the mix that was clicked last, hidden when closed.
+    mix_details: Option<Box<mixdetails::MixDetails>>,
+    cover_viewer: Option<coverviewer::CoverViewer>,
+    // The track we last showed a notification for.
+    notified_track_id: Option<u32>,
+    // The current mix's cover at notification size, once it's downloaded.
+    current_cover: Option<Pixbuf>,
+    cover_cache: covercache::SharedCoverCache,
+    // Bumped whenever the mix list or the current mix changes, so that covers which are still
+    // on their way for the old ones are dropped.
+    mixes_generation: Arc<AtomicUsize>,
+    current_generation: Arc<AtomicUsize>,
+    viewer_generation: Arc<AtomicUsize>,
+    history: history::History,
+    // The track we last added to the history.
+    history_track_id: Option<u32>,
 
     main_window: *mut GtkWidget,
     main_notebook: *mut GtkWidget,
@@ -180,6 +371,7 @@ pub struct Gui {
     playlists_notebook_index: libc::c_int,
     mixes_scrolled_window: *mut GtkWidget,
     mixes_box: *mut GtkWidget,
+    smart_id_entry: *mut GtkWidget,
     status_bar: *mut GtkWidget,
     status_bar_ci: Option<guint>,
 
@@ -191,16 +383,24 @@ pub struct Gui {
     skip_button: *mut GtkWidget,
     progress_bar: *mut GtkWidget,
     info_label: *mut GtkWidget,
+    stream_info_label: *mut GtkWidget,
+    audio_device_combo: *mut GtkWidget,
+
+    // The third page, the tracks played, newest first below a header row
+    history_notebook_index: libc::c_int,
+    history_grid: *mut GtkWidget,
+    // What the row buttons' callbacks get, oldest first like the history.
+    history_rows: Vec<Box<history::Entry>>,
 
     receiver: mpsc::Receiver<GuiUpdateMessage>,
     sender: mpsc::Sender<GuiUpdateMessage>,
+    player_receiver: mpsc::Receiver<player::PlayerEvent>,
+    player_sender: mpsc::Sender<player::PlayerEvent>,
     buffered_msg: Option<GuiUpdateMessage>,
 
     gui_g_source: *mut GuiGSource,
     g_source_funcs: GSourceFuncs,
 
-    player: player::Player,
-
     // this is such a hack...
     mix_index_table: Vec<(*mut Gui, usize)>,
 }
@@ -221,19 +421,37 @@ impl Drop for Gui {
 impl Gui {
     pub fn new() -> Gui {
         let (sender, receiver) = mpsc::channel();
+        let (player_sender, player_receiver) = mpsc::channel();
+        let config = config::Config::load();
+        let cover_cache = covercache::CoverCache::open(config.cover_cache_size * 1024 * 1024);
         Gui {
             initialized: false,
             running: false,
             mix_entries: Vec::new(),
-            play_token: None,
-            current_mix_index: None,
-            current_track: None,
+            core: app::AppCore::new(player::Player::new(), config.stream_retries),
+            key_bindings: shortcuts::KeyBindings::new(&config.key_bindings),
+            config: config,
+            mpris: None,
+            notifier: None,
+            control_server: None,
+            status_subscribers: Vec::new(),
+            mix_details: None,
+            cover_viewer: None,
+            notified_track_id: None,
+            current_cover: None,
+            cover_cache: cover_cache,
+            mixes_generation: Arc::new(AtomicUsize::new(0)),
+            current_generation: Arc::new(AtomicUsize::new(0)),
+            viewer_generation: Arc::new(AtomicUsize::new(0)),
+            history: history::History::load(),
+            history_track_id: None,
             main_window: ptr::null_mut(),
             main_notebook: ptr::null_mut(),
 
             playlists_notebook_index: -1,
             mixes_scrolled_window: ptr::null_mut(),
             mixes_box: ptr::null_mut(),
+            smart_id_entry: ptr::null_mut(),
             status_bar: ptr::null_mut(),
             status_bar_ci: None,
 
@@ -243,9 +461,17 @@ impl Gui {
             skip_button: ptr::null_mut(),
             progress_bar: ptr::null_mut(),
             info_label: ptr::null_mut(),
+            stream_info_label: ptr::null_mut(),
+            audio_device_combo: ptr::null_mut(),
+
+            history_notebook_index: -1,
+            history_grid: ptr::null_mut(),
+            history_rows: Vec::new(),
 
             receiver: receiver,
             sender: sender,
+            player_receiver: player_receiver,
+            player_sender: player_sender,
             buffered_msg: None,
             gui_g_source: ptr::null_mut(),
             g_source_funcs: Struct__GSourceFuncs {
@@ -256,7 +482,6 @@ impl Gui {
                 closure_callback: None,
                 closure_marshal: None,
             },
-            player: player::Player::new(),
             mix_index_table: Vec::new(),
         }
     }
@@ -271,7 +496,7 @@ impl Gui {
     }
 
     fn update_play_button_icon(&mut self) {
-        let icon_name = if self.player.is_playing() {
+        let icon_name = if self.core.is_playing() {
             PAUSE_ICON_NAME
         } else {
             PLAY_ICON_NAME
@@ -283,18 +508,9 @@ impl Gui {
         }
     }
 
-    fn set_current_track(&mut self, track: api::Track) {
-        self.current_track = Some(track);
-        self.update_track_info();
-    }
-
-    fn remove_current_track(&mut self) {
-        self.current_track = None;
-        self.update_track_info();
-    }
-
-    fn update_track_info(&mut self) {
-        match self.current_track {
+    fn update_track_info(&self) {
+        let stream_info = self.core.stream_info();
+        match self.core.current_track() {
             None => {
                 let empty = rffi::CString::new("").unwrap();
                 unsafe {
@@ -304,10 +520,13 @@ impl Gui {
             Some(ref track) => {
                 let mut text = String::new();
                 text.push_str(&format!("'{}' by {}", track.name, track.performer)[..]);
-                match track.release_name {
+                // 8tracks often doesn't know the album, the stream tags might.
+                let release_name = track.release_name.clone().or(stream_info.album.clone());
+                let year = track.year.or(stream_info.year);
+                match release_name {
                     Some(ref rn) => {
                         text.push_str(&format!("\nAlbum: {}", *rn)[..]);
-                        match track.year {
+                        match year {
                             Some(year) => text.push_str(&format!(" ({})", year)[..]),
                             None => ()
                         }
@@ -320,6 +539,41 @@ impl Gui {
                 }
             }
         }
+
+        let info = stream_info;
+        let mut lines = Vec::new();
+        match info.codec {
+            Some(ref codec) => lines.push(format!("Codec: {}", codec)),
+            None => ()
+        }
+        match info.bitrate {
+            Some(bitrate) => lines.push(format!("Bitrate: {} kbit/s", bitrate / 1000)),
+            None => ()
+        }
+        match info.sample_rate {
+            Some(rate) => lines.push(format!("Sample rate: {} Hz", rate)),
+            None => ()
+        }
+        match info.channels {
+            Some(channels) => lines.push(format!("Channels: {}", channels)),
+            None => ()
+        }
+        match info.title {
+            Some(ref title) => lines.push(format!("Title tag: {}", title)),
+            None => ()
+        }
+        match info.artist {
+            Some(ref artist) => lines.push(format!("Artist tag: {}", artist)),
+            None => ()
+        }
+        match info.album {
+            Some(ref album) => lines.push(format!("Album tag: {}", album)),
+            None => ()
+        }
+        let stream_text_c_str = rffi::CString::new(lines.connect("\n")).unwrap();
+        unsafe {
+            gtk_label_set_text(self.stream_info_label as *mut GtkLabel, stream_text_c_str.as_ptr());
+        }
     }
 
     pub fn init(&mut self, args: Vec<String>) {
@@ -337,6 +591,17 @@ impl Gui {
                 let icon = get_icon_pixbuf();
                 gtk_window_set_icon(self.main_window as *mut GtkWindow, icon);
                 gdk_pixbuf_unref(icon);
+                let key_press = rffi::CString::new("key-press-event").unwrap();
+                g_signal_connect(self.main_window as gpointer,
+                                 key_press.as_ptr(),
+                                 Some(mem::transmute(main_window_key_pressed)),
+                                 mem::transmute::<&Gui, gpointer>(self));
+                // E.g. when the window is moved to a HiDPI screen.
+                let scale_factor = rffi::CString::new("notify::scale-factor").unwrap();
+                g_signal_connect(self.main_window as gpointer,
+                                 scale_factor.as_ptr(),
+                                 Some(mem::transmute(main_window_scale_factor_changed)),
+                                 mem::transmute::<&Gui, gpointer>(self));
 
                 self.main_notebook = gtk_notebook_new();
                 gtk_container_add(self.main_window as *mut GtkContainer, self.main_notebook);
@@ -381,16 +646,29 @@ impl Gui {
                 gtk_combo_box_set_active(smart_id_ordering_combo as *mut GtkComboBox,
                                          MixesOrdering::Popular as libc::c_int);
 
-                let smart_id_entry = gtk_entry_new();
-                gtk_box_pack_start(as_box(smart_id_box), smart_id_entry, 1, 1, 0);
+                self.smart_id_entry = gtk_entry_new();
+                gtk_box_pack_start(as_box(smart_id_box), self.smart_id_entry, 1, 1, 0);
                 {
                     let activate_c_str = rffi::CString::new("activate").unwrap();
-                    g_signal_connect(smart_id_entry as gpointer,
+                    g_signal_connect(self.smart_id_entry as gpointer,
                                      activate_c_str.as_ptr(),
                                      Some(mem::transmute(smart_id_entry_activated)),
                                      mem::transmute::<&Gui, gpointer>(self));
                 }
 
+                let hide_nsfw_c_str = rffi::CString::new("Hide NSFW").unwrap();
+                let hide_nsfw_button = gtk_check_button_new_with_label(hide_nsfw_c_str.as_ptr());
+                gtk_toggle_button_set_active(hide_nsfw_button as *mut GtkToggleButton,
+                                             if self.config.hide_nsfw { 1 } else { 0 });
+                gtk_box_pack_start(as_box(smart_id_box), hide_nsfw_button, 0, 0, 0);
+                {
+                    let toggled_c_str = rffi::CString::new("toggled").unwrap();
+                    g_signal_connect(hide_nsfw_button as gpointer,
+                                     toggled_c_str.as_ptr(),
+                                     Some(mem::transmute(hide_nsfw_button_toggled)),
+                                     mem::transmute::<&Gui, gpointer>(self));
+                }
+
                 self.status_bar = gtk_statusbar_new();
                 let rusttracks_c_str = rffi::CString::new("rusttracks").unwrap();
                 self.status_bar_ci = Some(gtk_statusbar_get_context_id(
@@ -411,9 +689,19 @@ impl Gui {
                     panic!("Adding second page to notebook failed");
                 }
 
+                // Clicking the cover shows it at full size.
+                let image_event_box = gtk_event_box_new();
+                gtk_container_add(current_box as *mut GtkContainer, image_event_box);
+                {
+                    let signal = rffi::CString::new("button-press-event").unwrap();
+                    g_signal_connect(image_event_box as gpointer,
+                                     signal.as_ptr(),
+                                     Some(mem::transmute(current_image_clicked)),
+                                     mem::transmute::<&Gui, gpointer>(self));
+                }
                 let mut image = LoadingImage::new(250);
                 image.reset();
-                gtk_container_add(current_box as *mut GtkContainer,
+                gtk_container_add(image_event_box as *mut GtkContainer,
                                   image.image as *mut GtkWidget);
                 self.current_image = Some(image);
 
@@ -451,6 +739,61 @@ impl Gui {
                 gtk_box_pack_start(as_box(current_box), self.info_label, 0, 0, 0);
                 gtk_label_set_justify(self.info_label as *mut GtkLabel, GTK_JUSTIFY_CENTER);
 
+                let stream_info_c_str = rffi::CString::new("Stream info").unwrap();
+                let stream_info_expander = gtk_expander_new(stream_info_c_str.as_ptr());
+                gtk_box_pack_start(as_box(current_box), stream_info_expander, 0, 0, 0);
+                self.stream_info_label = gtk_label_new(ptr::null());
+                gtk_misc_set_alignment(self.stream_info_label as *mut GtkMisc, 0f32, 0.5f32);
+                gtk_label_set_selectable(self.stream_info_label as *mut GtkLabel, 1);
+                gtk_container_add(stream_info_expander as *mut GtkContainer,
+                                  self.stream_info_label);
+
+                let output_box = gtk_box_new(GTK_ORIENTATION_HORIZONTAL, 5);
+                gtk_box_pack_end(as_box(current_box), output_box, 0, 0, 0);
+
+                let output_c_str = rffi::CString::new("Output:").unwrap();
+                let output_label = gtk_label_new(output_c_str.as_ptr());
+                gtk_box_pack_start(as_box(output_box), output_label, 0, 0, 0);
+
+                self.audio_device_combo = gtk_combo_box_text_new();
+                gtk_box_pack_start(as_box(output_box), self.audio_device_combo, 1, 1, 0);
+
+                // Third page: History
+                let history_scrolled_window = gtk_scrolled_window_new(ptr::null_mut(),
+                                                                      ptr::null_mut());
+                gtk_scrolled_window_set_policy(
+                    history_scrolled_window as *mut GtkScrolledWindow,
+                    GTK_POLICY_AUTOMATIC, GTK_POLICY_AUTOMATIC);
+                let history_c_str = rffi::CString::new("History").unwrap();
+                let history_label = gtk_label_new(history_c_str.as_ptr());
+                self.history_notebook_index = gtk_notebook_append_page(
+                    self.main_notebook as *mut GtkNotebook,
+                    history_scrolled_window,
+                    history_label);
+                if self.history_notebook_index < 0 {
+                    panic!("Adding third page to notebook failed");
+                }
+
+                self.history_grid = gtk_grid_new();
+                gtk_grid_set_row_spacing(self.history_grid as *mut GtkGrid, 2);
+                gtk_grid_set_column_spacing(self.history_grid as *mut GtkGrid, 10);
+                gtk_container_set_border_width(self.history_grid as *mut GtkContainer, 5);
+                gtk_container_add(history_scrolled_window as *mut GtkContainer,
+                                  self.history_grid);
+                let headers = ["Track", "Artist", "Release", "Year", "Mix"];
+                for (column, header) in headers.iter().enumerate() {
+                    let markup = rffi::CString::new(format!("<b>{}</b>", header)).unwrap();
+                    let label = gtk_label_new(ptr::null());
+                    gtk_label_set_markup(label as *mut GtkLabel, markup.as_ptr());
+                    gtk_misc_set_alignment(label as *mut GtkMisc, 0f32, 0.5f32);
+                    gtk_grid_attach(self.history_grid as *mut GtkGrid, label,
+                                    column as libc::c_int, 0, 1, 1);
+                }
+                let entries = self.history.entries().to_vec();
+                for entry in entries.into_iter() {
+                    self.add_history_row(entry);
+                }
+
                 // And finally the GSource
                 let g_source = g_source_new(&mut self.g_source_funcs as *mut GSourceFuncs,
                                             mem::size_of::<GuiGSource>() as guint);
@@ -458,8 +801,66 @@ impl Gui {
                 (*self.gui_g_source).gui_ptr = self as *mut Gui;
             }
             self.initialized = true;
-            let sender = self.get_sender().clone();
-            let _args3 = self.player.init(args2, sender);
+            self.core.set_scrobble_endpoint(scrobbler::Endpoint::from_config(&self.config));
+            let hide_nsfw = self.config.hide_nsfw;
+            self.handle(app::Input::Command(app::Command::SetHideNsfw(hide_nsfw)));
+            let player_sender = self.player_sender.clone();
+            let _args3 = self.core.player_mut().init(args2, player_sender);
+            let (stall_timeout, stall_restarts) =
+                (self.config.stall_timeout, self.config.stall_restarts);
+            self.core.player_mut().set_stall_policy(stall_timeout, stall_restarts);
+            self.fill_audio_device_combo();
+            // The Gui doesn't move once initialized and outlives the Mpris, which is dropped
+            // in quit.
+            let gui_ptr = self as *const Gui;
+            let position = Box::new(move || unsafe { mpris::position((*gui_ptr).core.player()) });
+            self.mpris = mpris::Mpris::new(self.sender.clone(), position);
+            if self.config.notifications {
+                self.notifier = notifications::Notifier::new(self.sender.clone());
+            }
+            match self.config.mpd_address.clone() {
+                Some(address) => match mpd::start(&address[..], self.sender.clone()) {
+                    Ok(()) => (),
+                    Err(err) => warn!("could not start the MPD server on {}: {}", address, err),
+                },
+                None => (),
+            }
+            match self.config.http_address.clone() {
+                Some(address) => match remote::start(&address[..], self.sender.clone()) {
+                    Ok(()) => (),
+                    Err(err) => warn!("could not start the HTTP remote on {}: {}", address, err),
+                },
+                None => (),
+            }
+            self.control_server = match control::ControlServer::start(self.sender.clone()) {
+                Ok(server) => Some(server),
+                Err(err) => {
+                    warn!("could not open the control socket: {}", err);
+                    None
+                }
+            };
+        }
+    }
+
+    fn fill_audio_device_combo(&mut self) {
+        let names = self.core.player_mut().refresh_audio_devices();
+        unsafe {
+            let combo = self.audio_device_combo as *mut GtkComboBoxText;
+            gtk_combo_box_text_remove_all(combo);
+            let default_c_str = rffi::CString::new("Default").unwrap();
+            gtk_combo_box_text_append(combo, ptr::null(), default_c_str.as_ptr());
+            for name in names.iter() {
+                let name_c_str = rffi::CString::new(name.as_bytes()).unwrap();
+                gtk_combo_box_text_append(combo, ptr::null(), name_c_str.as_ptr());
+            }
+            // Select "Default" before connecting, we don't want to switch sinks on startup.
+            gtk_combo_box_set_active(combo as *mut GtkComboBox, 0);
+
+            let changed_c_str = rffi::CString::new("changed").unwrap();
+            g_signal_connect(combo as gpointer,
+                             changed_c_str.as_ptr(),
+                             Some(mem::transmute(audio_device_combo_changed)),
+                             mem::transmute::<&Gui, gpointer>(self));
         }
     }
 
@@ -478,7 +879,12 @@ impl Gui {
 
     pub fn quit(&mut self) {
         if self.initialized {
-            self.player.stop();
+            // Through the core, so that the current track still makes it into the listening log.
+            self.handle(app::Input::Command(app::Command::Stop));
+            self.mpris = None;
+            self.control_server = None;
+            self.mix_details = None;
+            self.cover_viewer = None;
             {
                 if self.main_window != ptr::null_mut() {
                     unsafe {
@@ -513,39 +919,171 @@ impl Gui {
         }
     }
 
-    fn fetch_play_token(&self) {
-        if self.play_token.is_some() {
-            debug!("play token already exists, ignoring request");
+    fn handle(&mut self, input: app::Input) {
+        for output in self.core.handle(input) {
+            match output {
+                app::Output::Request(r) => self.run_request(r),
+                app::Output::View(v) => self.render(v),
+                app::Output::Listened(l) => listenlog::record(&l),
+            }
+        }
+    }
+
+    fn render(&mut self, update: app::ViewUpdate) {
+        match update {
+            app::ViewUpdate::Notify(m) => self.notify(&m[..]),
+            app::ViewUpdate::MixesChanged => {
+                self.set_mixes();
+                self.publish_status();
+            }
+            app::ViewUpdate::MixStarted(i) => self.show_mix(i),
+            app::ViewUpdate::TrackChanged => {
+                self.update_track_info();
+                let has_track = self.core.current_track().is_some();
+                self.control_buttons_set_sensitive(has_track);
+                self.notify_track_change();
+                self.record_history();
+                self.publish_status();
+            }
+            app::ViewUpdate::PlaybackChanged => {
+                self.update_play_button_icon();
+                self.publish_status();
+            }
+            app::ViewUpdate::Progress => self.update_progress(),
+        }
+        self.update_mpris();
+    }
+
+    /// Adds the current track to the history if it just started.
+    fn record_history(&mut self) {
+        let (track, mix) = match (self.core.current_track(), self.core.current_mix()) {
+            (Some(t), Some(m)) => (t.clone(), m.clone()),
+            _ => return,
+        };
+        if self.history_track_id == Some(track.id) {
             return;
         }
+        self.history_track_id = Some(track.id);
+        let entry = self.history.record(&track, &mix);
+        self.add_history_row(entry);
+        unsafe {
+            gtk_widget_show_all(self.history_grid);
+        }
+    }
 
-        debug!("fetching play token");
-        let sender = self.sender.clone();
-        thread::spawn(move || {
-            let pt_json = match webinterface::get_play_token() {
-                Ok(ptj) => ptj,
-                Err(io_err) => {
-                    sender.send(GuiUpdateMessage::Notify(format!("Playtoken could not be obtained: `{}`", io_err)));
-                    return;
-                }
-            };
-            let pt = api::parse_play_token_response(&pt_json);
-            match pt.contents {
-                Some(pt) => sender.send(GuiUpdateMessage::SetPlayToken(pt)),
-                None => sender.send(GuiUpdateMessage::Notify("Playtoken could not be obtained".to_string()))
-            };
-        });
+    fn add_history_row(&mut self, entry: history::Entry) {
+        let entry = Box::new(entry);
+        let grid = self.history_grid as *mut GtkGrid;
+        unsafe {
+            // Row 0 is the header.
+            gtk_grid_insert_row(grid, 1);
+            let year = entry.track.year.map(|y| y.to_string()).unwrap_or(String::new());
+            let release = entry.track.release_name.clone().unwrap_or(String::new());
+            let texts = [&entry.track.name[..], &entry.track.performer[..], &release[..],
+                         &year[..], &entry.mix_name[..]];
+            for (column, text) in texts.iter().enumerate() {
+                let text_c_str = rffi::CString::new(text.as_bytes()).unwrap();
+                let label = gtk_label_new(text_c_str.as_ptr());
+                gtk_label_set_ellipsize(label as *mut GtkLabel, PANGO_ELLIPSIZE_END);
+                gtk_label_set_max_width_chars(label as *mut GtkLabel, 30);
+                gtk_widget_set_tooltip_text(label, text_c_str.as_ptr());
+                gtk_misc_set_alignment(label as *mut GtkMisc, 0f32, 0.5f32);
+                gtk_grid_attach(grid, label, column as libc::c_int, 1, 1, 1);
+            }
+
+            let button_box = gtk_box_new(GTK_ORIENTATION_HORIZONTAL, 2);
+            gtk_grid_attach(grid, button_box, 5, 1, 1, 1);
+            let buttons: [(&str, &str, extern "C" fn(*mut GtkButton, gpointer), bool); 3] = [
+                ("Open", "Open the track's page", history_open_clicked,
+                 !entry.track.url.is_empty()),
+                ("Buy", "Open the buy link", history_buy_clicked,
+                 !entry.track.buy_link.is_empty()),
+                ("Copy", "Copy \"artist \u{2013} title\"", history_copy_clicked, true),
+            ];
+            let clicked_c_str = rffi::CString::new("clicked").unwrap();
+            for &(label, tooltip, callback, sensitive) in buttons.iter() {
+                let label_c_str = rffi::CString::new(label).unwrap();
+                let button = gtk_button_new_with_label(label_c_str.as_ptr());
+                let tooltip_c_str = rffi::CString::new(tooltip).unwrap();
+                gtk_widget_set_tooltip_text(button, tooltip_c_str.as_ptr());
+                gtk_widget_set_sensitive(button, if sensitive { 1 } else { 0 });
+                gtk_box_pack_start(as_box(button_box), button, 0, 0, 0);
+                g_signal_connect(button as gpointer,
+                                 clicked_c_str.as_ptr(),
+                                 Some(mem::transmute(callback)),
+                                 mem::transmute::<&history::Entry, gpointer>(&*entry));
+            }
+
+            self.history_rows.push(entry);
+            // The history forgets its oldest entries, so do we.
+            if self.history_rows.len() > history::MAX_ENTRIES {
+                gtk_grid_remove_row(grid, self.history_rows.len() as libc::c_int);
+                self.history_rows.remove(0);
+            }
+        }
     }
 
-    fn set_play_token(&mut self, pt: api::PlayToken) {
-        debug!("setting play token to `{}`", pt.s);
-        self.play_token = Some(pt.clone());
+    /// Shows a desktop notification if a new track started.
+    fn notify_track_change(&mut self) {
+        let track = match self.core.current_track() {
+            Some(t) => t.clone(),
+            None => return,
+        };
+        if self.notified_track_id == Some(track.id) {
+            return;
+        }
+        self.notified_track_id = Some(track.id);
+
+        let mut body = format!("by {}", track.performer);
+        match (track.release_name, track.year) {
+            (Some(ref rn), Some(year)) => body.push_str(&format!("\n{} ({})", rn, year)[..]),
+            (Some(ref rn), None) => body.push_str(&format!("\n{}", rn)[..]),
+            (None, Some(year)) => body.push_str(&format!("\n{}", year)[..]),
+            (None, None) => (),
+        }
+        let notifier = match self.notifier {
+            Some(ref mut n) => n,
+            None => return,
+        };
+        let image = self.current_cover.as_ref().map(|p| p.as_ptr());
+        notifier.show(&track.name[..], &body[..], image);
     }
 
-    fn set_mixes(&mut self, mixes: Vec<api::Mix>) {
+    fn publish_status(&mut self) {
+        if self.status_subscribers.is_empty() {
+            return;
+        }
+        let status = self.core.status();
+        // Subscribers that went away are dropped.
+        let subscribers = mem::replace(&mut self.status_subscribers, Vec::new());
+        self.status_subscribers = subscribers.into_iter().filter(|s| {
+            s.send(status.clone()).is_ok()
+        }).collect();
+    }
+
+    fn update_mpris(&mut self) {
+        let state = mpris::PlayerState::from_core(&self.core);
+        match self.mpris {
+            Some(ref mut mpris) => mpris.update(state),
+            None => (),
+        }
+    }
+
+    fn run_request(&self, request: app::Request) {
+        let sender = self.sender.clone();
+        network::run(request, move |result| {
+            sender.send(GuiUpdateMessage::Network(result));
+        });
+    }
+
+    fn set_mixes(&mut self) {
+        let mixes = self.core.mixes().to_vec();
         let self_ptr = self as *mut Gui;
         self.mix_index_table = (0..mixes.len()).map(|i| (self_ptr, i)).collect();
         self.mix_entries.clear();
+        // Its Play button would play whatever mix now has its index.
+        self.mix_details = None;
+        let generation = self.mixes_generation.fetch_add(1, Ordering::SeqCst) + 1;
         debug!("setting mixes, length {}", mixes.len());
         unsafe {
             clear_gtk_container(self.mixes_box as *mut GtkContainer);
@@ -554,20 +1092,7 @@ impl Gui {
                 gtk_box_pack_start(as_box(self.mixes_box),
                     mix_entry.widget, 0, 1, 0);
                 self.mix_entries.push(mix_entry);
-
-                // Fetch cover pic
-                let sender = self.sender.clone();
-                let pic_url_str = mixes[i].cover_urls.sq133.clone();
-                thread::spawn(move || {
-                    let pic_data = match webinterface::get_data_from_url_str(&pic_url_str[..]) {
-                        Ok(pd) => pd,
-                        Err(io_err) => {
-                            sender.send(GuiUpdateMessage::Notify(format!("Could not get picture: `{}`", io_err)));
-                            return;
-                        }
-                    };
-                    sender.send(GuiUpdateMessage::SetPic(i, pic_data));
-                });
+                self.fetch_mix_cover(generation, i);
             }
             gtk_widget_show_all(self.mixes_box);
             let adj = gtk_scrolled_window_get_vadjustment(
@@ -577,188 +1102,128 @@ impl Gui {
         }
     }
 
-    fn get_mixes(&self, smart_id: String) {
-        debug!("getting mixes for smart id '{}'", smart_id);
-        let sender = self.get_sender().clone();
-        thread::spawn(move || {
-            let mix_set_json = match webinterface::get_mix_set(&smart_id[..]) {
-                Ok(msj) => msj,
-                Err(io_err) => {
-                    sender.send(GuiUpdateMessage::Notify(format!("Could not get mix list: `{}`", io_err)));
-                    return;
-                }
-            };
-            let mix_set = api::parse_mix_set_response(&mix_set_json);
-            match mix_set.contents {
-                Some(ms) => sender.send(GuiUpdateMessage::UpdateMixes(ms.mixes)),
-                None => sender.send(GuiUpdateMessage::Notify("Mix list could not be obtained".to_string()))
-            };
+    // The cover of the i-th mix entry, unless the mix has none.
+    fn fetch_mix_cover(&self, generation: usize, i: usize) {
+        let size = self.mix_entries[i].image.pixel_size();
+        let url = match self.mix_entries[i].mix.cover_urls.for_size(size) {
+            Some(url) => url.to_string(),
+            None => return,
+        };
+        fetch_cover(&self.cover_cache, url, vec![Some(size)],
+                    &self.mixes_generation, &self.sender, move |mut pixbufs| {
+            GuiUpdateMessage::SetPic(generation, i, pixbufs.remove(0))
         });
     }
 
-    fn play_mix(&mut self, i: usize) {
-        debug!("playing mix with index {}", i);
-        if i >= self.mix_entries.len() {
-            warn!("index is out of bounds, ignoring message");
-        } else {
-            self.current_mix_index = Some(i);
-            let mix = self.mix_entries[i].mix.clone();
-            debug!("playing mix with name `{}`", mix.name);
-            let pt = self.play_token.as_ref().unwrap().clone();
-            self.player.pause();
-
-            // Fetch cover pic
-            self.current_image.as_mut().unwrap().reset();
-            let sender = self.sender.clone();
-            let pic_url_str = mix.cover_urls.sq250.clone();
-            thread::spawn(move || {
-                let pic_data = match webinterface::get_data_from_url_str(&pic_url_str[..]) {
-                    Ok(pd) => pd,
-                    Err(io_err) => {
-                        sender.send(GuiUpdateMessage::Notify(format!("Could not get picture: `{}`", io_err)));
-                        return;
-                    }
-                };
-                sender.send(GuiUpdateMessage::SetCurrentPic(pic_data));
-            });
-
-            // Actually play
-            let sender = self.sender.clone();
-            thread::spawn(move || {
-                let play_state_json = match webinterface::get_play_state(&pt, &mix) {
-                    Ok(psj) => psj,
-                    Err(io_err) => {
-                        sender.send(GuiUpdateMessage::Notify(format!("Could not start playing mix: `{}`", io_err)));
-                        return;
-                    }
-                };
-                let play_state = api::parse_play_state_response(&play_state_json);
-                match play_state.contents {
-                    Some(ps) => sender.send(GuiUpdateMessage::PlayTrack(ps.track)),
-                    None => sender.send(GuiUpdateMessage::Notify("Could not start playing mix".to_string()))
-                };
-            });
-
-            unsafe {
-                gtk_notebook_set_current_page(self.main_notebook as *mut GtkNotebook,
-                                              self.current_notebook_index);
-            }
-        }
-    }
-
-    fn play_track(&mut self, track: api::Track) {
-        debug!("playing track `{}`", track.name);
-        self.set_current_track(track.clone());
-        debug!("setting uri to `{}`", track.track_file_stream_url);
-        self.player.set_uri(&track.track_file_stream_url[..]);
-        self.player.play();
-        self.update_play_button_icon();
-        self.control_buttons_set_sensitive(true);
-        self.set_progress(None);
-    }
-
-    fn report_current_track(&self) {
-        debug!("reporting current track");
-        let (pt, ti, mi) =
-            (
-                self.play_token.as_ref().unwrap().clone(),
-                self.mix_entries[*self.current_mix_index.as_ref().unwrap()].mix.id,
-                self.current_track.as_ref().unwrap().id,
-            );
-        thread::spawn(move || {
-            webinterface::report_track(&pt, ti, mi);
+    // The current mix's cover for the Current page and the notifications.
+    fn fetch_current_cover(&mut self) {
+        let size = self.current_image.as_ref().unwrap().pixel_size();
+        let url = self.core.current_mix().and_then(|m| m.cover_urls.for_size(size))
+                                         .map(|u| u.to_string());
+        let generation = self.current_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let url = match url {
+            Some(url) => url,
+            None => return,
+        };
+        fetch_cover(&self.cover_cache, url, vec![Some(size), Some(128)],
+                    &self.current_generation, &self.sender, move |mut pixbufs| {
+            let notification = pixbufs.pop().unwrap();
+            GuiUpdateMessage::SetCurrentPic(generation, pixbufs.pop().unwrap(), notification)
         });
     }
 
-    fn toggle_playing(&mut self) {
-        debug!("toggling!");
-        self.player.toggle();
-        self.update_play_button_icon();
-    }
-
-    fn set_buffering(&mut self, is_buffering: bool) {
-        debug!("set_buffering({})", is_buffering);
-        self.player.set_buffering(is_buffering);
-        self.update_play_button_icon();
+    // Covers were picked for the old scale factor, they'd be blurry or too big now.
+    fn scale_factor_changed(&mut self) {
+        let generation = self.mixes_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        for i in 0..self.mix_entries.len() {
+            self.fetch_mix_cover(generation, i);
+        }
+        if self.current_image.is_some() {
+            self.fetch_current_cover();
+        }
     }
 
-    fn next_track(&mut self) {
-        self.player.stop();
-        self.remove_current_track();
-        self.control_buttons_set_sensitive(false);
+    fn show_mix(&mut self, i: usize) {
+        debug!("showing mix {}", i);
+        self.current_image.as_mut().unwrap().reset();
+        self.current_cover = None;
+        self.fetch_current_cover();
 
-        let i = self.current_mix_index.unwrap();
-        let mix = self.mix_entries[i].mix.clone();
-        debug!("getting next track of mix with name `{}`", mix.name);
-        let sender = self.sender.clone();
-        let pt = self.play_token.as_ref().unwrap().clone();
-        thread::spawn(move || {
-            let next_track_json = match webinterface::get_next_track(&pt, &mix) {
-                Ok(ntj) => ntj,
-                Err(io_err) => {
-                    sender.send(GuiUpdateMessage::Notify(format!("Could not get next track: `{}`", io_err)));
-                    return;
-                }
-            };
-            let play_state = api::parse_play_state_response(&next_track_json);
-            match play_state.contents {
-                Some(ps) => sender.send(GuiUpdateMessage::PlayTrack(ps.track)),
-                None => sender.send(GuiUpdateMessage::Notify("Next track could not be obtained".to_string()))
-            };
-        });
+        unsafe {
+            gtk_notebook_set_current_page(self.main_notebook as *mut GtkNotebook,
+                                          self.current_notebook_index);
+        }
     }
 
-    fn skip_track(&mut self) {
-        self.player.pause();
-        self.update_play_button_icon();
+    fn show_mix_details(&mut self, i: usize) {
+        if i >= self.mix_entries.len() {
+            warn!("show_mix_details: index {} is out of range, only {} mix_entries",
+                  i, self.mix_entries.len());
+            return;
+        }
+        let details = mixdetails::MixDetails::new(self.main_window, &self.mix_entries[i].mix, i,
+                                                  self.mix_entries[i].cover.as_ref(),
+                                                  self.sender.clone());
+        details.present();
+        self.mix_details = Some(details);
+    }
 
-        let i = self.current_mix_index.unwrap();
-        let mix = self.mix_entries[i].mix.clone();
-        debug!("skipping track of mix with name `{}`", mix.name);
-        let sender = self.sender.clone();
-        let pt = self.play_token.as_ref().unwrap().clone();
-        thread::spawn(move || {
-            let skip_track_json = match webinterface::get_skip_track(&pt, &mix) {
-                Ok(stj) => stj,
-                Err(io_err) => {
-                    sender.send(GuiUpdateMessage::Notify(format!("Could not skip track: `{}`", io_err)));
-                    return;
+    /// Opens the current mix's cover at full size.
+    fn show_cover_viewer(&mut self) {
+        let (name, url) = match self.core.current_mix() {
+            Some(mix) => {
+                let size = device_pixels(self.main_window, 1024);
+                match mix.cover_urls.for_size(size) {
+                    Some(url) => (mix.name.clone(), url.to_string()),
+                    None => return,
                 }
-            };
-            let play_state = api::parse_play_state_response(&skip_track_json);
-            match play_state.contents {
-                Some(ps) => sender.send(GuiUpdateMessage::PlayTrack(ps.track)),
-                None => sender.send(GuiUpdateMessage::Notify("Could not skip track".to_string())),
-            };
+            }
+            None => return,
+        };
+        let viewer = coverviewer::CoverViewer::new(self.main_window, &name[..]);
+        viewer.present();
+        self.cover_viewer = Some(viewer);
+        let generation = self.viewer_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        fetch_cover(&self.cover_cache, url, vec![None],
+                    &self.viewer_generation, &self.sender, move |mut pixbufs| {
+            GuiUpdateMessage::SetViewerPic(generation, pixbufs.remove(0))
         });
     }
 
-    fn set_pic(&mut self, i: usize, pic_data: Vec<u8>) {
-        if i >= self.mix_entries.len() {
+    fn set_pic(&mut self, generation: usize, i: usize, pixbuf: Pixbuf) {
+        if generation != self.mixes_generation.load(Ordering::SeqCst) {
+            debug!("set_pic: dropping a cover for an old mix list");
+        } else if i >= self.mix_entries.len() {
             warn!("set_pic: index {} is out of range, only {} mix_entries",
                   i, self.mix_entries.len());
         } else {
-            self.mix_entries[i].set_pic_from_data(&pic_data[..]);
+            self.mix_entries[i].set_pic(pixbuf);
         }
     }
 
-    fn set_current_pic(&mut self, pic_data: Vec<u8>) {
-        self.current_image.as_mut().unwrap().set_image_from_data(&pic_data[..]);
-    }
-
-    fn start_timers(&mut self) {
-        debug!("starting timers");
-        self.player.start_timers(self.sender.clone());
+    fn set_current_pic(&mut self, generation: usize, pixbuf: Pixbuf, notification: Pixbuf) {
+        if generation != self.current_generation.load(Ordering::SeqCst) {
+            debug!("set_current_pic: dropping the cover of a previous mix");
+            return;
+        }
+        self.current_image.as_mut().unwrap().set_pixbuf(&pixbuf);
+        self.current_cover = Some(notification);
     }
 
-    fn pause_timers(&mut self) {
-        debug!("pausing timers");
-        self.player.pause_timers();
+    fn set_viewer_pic(&mut self, generation: usize, pixbuf: Pixbuf) {
+        if generation != self.viewer_generation.load(Ordering::SeqCst) {
+            debug!("set_viewer_pic: dropping the cover for a previous viewer");
+            return;
+        }
+        match self.cover_viewer {
+            Some(ref viewer) => viewer.set_cover(&pixbuf),
+            None => (),
+        }
     }
 
     fn set_progress(&mut self, progress: Option<(i64, i64)>) {
         debug!("setting progress to {:?}", progress);
-        match progress {
+        let (mut text, fraction) = match progress {
             Some((pos, dur)) => {
                 let fraction = (pos as f64) / ((dur - 1) as f64);
                 let pos_sec = pos / 1000000000;
@@ -766,27 +1231,101 @@ impl Gui {
                 let text = format!("{}:{:02} / {}:{:02}",
                                    pos_sec / 60, pos_sec % 60,
                                    dur_sec / 60, dur_sec % 60);
-                let text_c_str = rffi::CString::new(text).unwrap();
-                unsafe {
-                    gtk_progress_bar_set_text(self.progress_bar as *mut GtkProgressBar,
-                                              text_c_str.as_ptr());
-                    gtk_progress_bar_set_fraction(self.progress_bar as *mut GtkProgressBar,
-                                                  fraction);
+                (text, fraction)
+            }
+            None => (String::new(), 0.)
+        };
+        match self.core.player().buffering_percent() {
+            Some(percent) => text = format!("Buffering... {}%", percent),
+            None => ()
+        }
+        let stalls = self.core.player().stream_health().stalls;
+        if !text.is_empty() && stalls > 0 {
+            text.push_str(&format!(" ({} stall{})", stalls, if stalls == 1 { "" } else { "s" })[..]);
+        }
+        let text_c_str = rffi::CString::new(text).unwrap();
+        unsafe {
+            gtk_progress_bar_set_text(self.progress_bar as *mut GtkProgressBar,
+                                      text_c_str.as_ptr());
+            gtk_progress_bar_set_fraction(self.progress_bar as *mut GtkProgressBar, fraction);
+        }
+    }
+
+    fn set_audio_device(&mut self, index: Option<usize>) {
+        debug!("setting audio device to {:?}", index);
+        self.core.player_mut().set_audio_device(index);
+    }
+
+    /// Runs the action of a shortcut. Returns false if the key should be handled by the
+    /// focused widget instead.
+    fn run_shortcut(&mut self, keyval: guint, state: GdkModifierType) -> bool {
+        let action = match self.key_bindings.lookup(keyval, state) {
+            Some(a) => a,
+            None => return false,
+        };
+        // Typing into the search entry wins over single-key shortcuts.
+        let focus = unsafe { gtk_window_get_focus(self.main_window as *mut GtkWindow) };
+        if focus == self.smart_id_entry && shortcuts::is_unmodified(state) {
+            return false;
+        }
+        debug!("shortcut {:?}", action);
+        match action {
+            shortcuts::Action::TogglePlaying => {
+                if self.core.current_track().is_some() {
+                    self.sender.send(GuiUpdateMessage::Command(app::Command::TogglePlaying));
                 }
             }
-            None => {
-                let empty_c_str = rffi::CString::new("").unwrap();
-                unsafe {
-                    gtk_progress_bar_set_text(self.progress_bar as *mut GtkProgressBar,
-                                              empty_c_str.as_ptr());
-                    gtk_progress_bar_set_fraction(self.progress_bar as *mut GtkProgressBar, 0.);
+            shortcuts::Action::Skip => {
+                if self.core.current_track().is_some() {
+                    self.sender.send(GuiUpdateMessage::Command(app::Command::SkipTrack));
                 }
             }
+            shortcuts::Action::FocusSearch => unsafe {
+                gtk_notebook_set_current_page(self.main_notebook as *mut GtkNotebook,
+                                              self.playlists_notebook_index);
+                gtk_widget_grab_focus(self.smart_id_entry);
+            },
+            shortcuts::Action::ShowPlaylists => unsafe {
+                gtk_notebook_set_current_page(self.main_notebook as *mut GtkNotebook,
+                                              self.playlists_notebook_index);
+            },
+            shortcuts::Action::ShowCurrent => unsafe {
+                gtk_notebook_set_current_page(self.main_notebook as *mut GtkNotebook,
+                                              self.current_notebook_index);
+            },
+            shortcuts::Action::ShowHistory => unsafe {
+                gtk_notebook_set_current_page(self.main_notebook as *mut GtkNotebook,
+                                              self.history_notebook_index);
+            },
+            shortcuts::Action::VolumeUp => self.change_volume(VOLUME_STEP),
+            shortcuts::Action::VolumeDown => self.change_volume(-VOLUME_STEP),
+            shortcuts::Action::ShowHelp => {
+                shortcuts::show_help(self.main_window, &self.key_bindings);
+            }
+        }
+        true
+    }
+
+    fn change_volume(&mut self, delta: f64) {
+        let volume = self.core.player().volume() + delta;
+        let volume = self.core.player_mut().set_volume(volume);
+        self.notify(&format!("Volume: {:.0}%", volume * 100.)[..]);
+    }
+
+    fn set_hide_nsfw(&mut self, hide: bool) {
+        if hide == self.config.hide_nsfw {
+            return;
         }
+        self.config.hide_nsfw = hide;
+        match config::Config::store("hide_nsfw", hide.to_json()) {
+            Ok(()) => (),
+            Err(err) => warn!("could not save the NSFW preference: {}", err),
+        }
+        self.handle(app::Input::Command(app::Command::SetHideNsfw(hide)));
     }
 
     fn update_progress(&mut self) {
-        let progress = self.player.get_progress_info();
+        let progress = self.core.player().get_progress_info();
         self.set_progress(progress);
     }
 
@@ -795,6 +1334,17 @@ impl Gui {
             return true;
         }
 
+        match self.player_receiver.try_recv() {
+            Ok(event) => {
+                self.buffered_msg = Some(GuiUpdateMessage::Player(event));
+                return true;
+            }
+            Err(mpsc::TryRecvError::Empty) => (),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                panic!("wut? noone allowed you to disconnect!")
+            }
+        }
+
         match self.receiver.try_recv() {
             Ok(msg) => {
                 self.buffered_msg = Some(msg);
@@ -818,23 +1368,24 @@ impl Gui {
 
         let msg = self.buffered_msg.take().unwrap();
         match msg {
-            GuiUpdateMessage::FetchPlayToken => self.fetch_play_token(),
-            GuiUpdateMessage::SetPlayToken(pt) => self.set_play_token(pt),
-            GuiUpdateMessage::UpdateMixes(m) => self.set_mixes(m),
-            GuiUpdateMessage::GetMixes(s) => self.get_mixes(s),
-            GuiUpdateMessage::PlayMix(i) => self.play_mix(i),
-            GuiUpdateMessage::PlayTrack(t) => self.play_track(t),
-            GuiUpdateMessage::ReportCurrentTrack => self.report_current_track(),
-            GuiUpdateMessage::TogglePlaying => self.toggle_playing(),
-            GuiUpdateMessage::SetBuffering(b) => self.set_buffering(b),
-            GuiUpdateMessage::NextTrack => self.next_track(),
-            GuiUpdateMessage::SkipTrack => self.skip_track(),
-            GuiUpdateMessage::SetPic(i, d) => self.set_pic(i, d),
-            GuiUpdateMessage::SetCurrentPic(d) => self.set_current_pic(d),
-            GuiUpdateMessage::UpdateProgress => self.update_progress(),
+            GuiUpdateMessage::Command(c) => self.handle(app::Input::Command(c)),
+            GuiUpdateMessage::Network(r) => self.handle(app::Input::Network(r)),
+            GuiUpdateMessage::Player(e) => self.handle(app::Input::Player(e)),
+            GuiUpdateMessage::SetPic(g, i, p) => self.set_pic(g, i, p),
+            GuiUpdateMessage::SetCurrentPic(g, p, n) => self.set_current_pic(g, p, n),
+            GuiUpdateMessage::SetViewerPic(g, p) => self.set_viewer_pic(g, p),
             GuiUpdateMessage::Notify(m) => self.notify(&m[..]),
-            GuiUpdateMessage::StartTimers => self.start_timers(),
-            GuiUpdateMessage::PauseTimers => self.pause_timers(),
+            GuiUpdateMessage::SetAudioDevice(i) => self.set_audio_device(i),
+            GuiUpdateMessage::Query(reply) => {
+                let _ = reply.send(self.core.status());
+            }
+            GuiUpdateMessage::Raise => unsafe {
+                gtk_window_present(self.main_window as *mut GtkWindow);
+            },
+            GuiUpdateMessage::Subscribe(subscriber) => {
+                let _ = subscriber.send(self.core.status());
+                self.status_subscribers.push(subscriber);
+            }
         }
 
         return true;
@@ -896,21 +1447,112 @@ extern "C" fn play_button_clicked(_button: *const GtkButton, user_data: gpointer
         let &(gui_ptr, i): &(*const Gui, usize) = mem::transmute(user_data);
         (&*gui_ptr, i)
     };
-    gui.get_sender().send(GuiUpdateMessage::PlayMix(i));
+    gui.get_sender().send(GuiUpdateMessage::Command(app::Command::PlayMix(i)));
+}
+
+extern "C" fn current_image_clicked(_widget: *mut GtkWidget, event: *mut GdkEventButton,
+                                    user_data: gpointer) -> gboolean {
+    let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
+    let (button, event_type) = unsafe { ((*event).button, (*event)._type) };
+    if button != 1 || event_type != GDK_BUTTON_PRESS {
+        return 0;
+    }
+    gui.show_cover_viewer();
+    1
+}
+
+extern "C" fn mix_entry_clicked(_widget: *mut GtkWidget, event: *mut GdkEventButton,
+                                 user_data: gpointer) -> gboolean {
+    let (gui, i) = unsafe {
+        let &(gui_ptr, i): &(*mut Gui, usize) = mem::transmute(user_data);
+        (&mut *gui_ptr, i)
+    };
+    let (button, event_type) = unsafe { ((*event).button, (*event)._type) };
+    if button != 1 || event_type != GDK_BUTTON_PRESS {
+        return 0;
+    }
+    gui.show_mix_details(i);
+    1
 }
 
 extern "C" fn toggle_button_clicked(_button: *const GtkButton, user_data: gpointer) {
     let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
-    gui.get_sender().send(GuiUpdateMessage::TogglePlaying);
+    gui.get_sender().send(GuiUpdateMessage::Command(app::Command::TogglePlaying));
 }
 
 extern "C" fn skip_button_clicked(_button: *const GtkButton, user_data: gpointer) {
     let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
-    gui.get_sender().send(GuiUpdateMessage::SkipTrack);
+    gui.get_sender().send(GuiUpdateMessage::Command(app::Command::SkipTrack));
+}
+
+extern "C" fn audio_device_combo_changed(combo: *mut GtkComboBox, user_data: gpointer) {
+    let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
+    // Entry 0 is the default sink, the others are in the order of the player's device list.
+    let index = match unsafe { gtk_combo_box_get_active(combo) } {
+        i if i <= 0 => None,
+        i => Some((i - 1) as usize),
+    };
+    gui.get_sender().send(GuiUpdateMessage::SetAudioDevice(index));
+}
+
+extern "C" fn main_window_key_pressed(_widget: *mut GtkWidget, event: *mut GdkEventKey,
+                                       user_data: gpointer) -> gboolean {
+    let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
+    let (keyval, state) = unsafe { ((*event).keyval, (*event).state) };
+    if gui.run_shortcut(keyval, state) { 1 } else { 0 }
+}
+
+extern "C" fn main_window_scale_factor_changed(_widget: *mut GtkWidget, _pspec: gpointer,
+                                               user_data: gpointer) {
+    let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
+    gui.scale_factor_changed();
+}
+
+extern "C" fn hide_nsfw_button_toggled(button: *mut GtkToggleButton, user_data: gpointer) {
+    let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
+    let active = unsafe { gtk_toggle_button_get_active(button) } != 0;
+    gui.set_hide_nsfw(active);
+}
+
+// Track urls may be relative to the 8tracks site.
+fn open_url(url: &str) {
+    let url = if url.starts_with("/") {
+        format!("http://8tracks.com{}", url)
+    } else {
+        url.to_string()
+    };
+    let url_c_str = rffi::CString::new(url.clone()).unwrap();
+    unsafe {
+        let mut err = ptr::null_mut();
+        if gtk_show_uri(ptr::null_mut(), url_c_str.as_ptr(), GDK_CURRENT_TIME as guint32,
+                        &mut err) == 0 {
+            warn!("could not open `{}`: {}", url, dbus::take_error(err));
+        }
+    }
+}
+
+extern "C" fn history_open_clicked(_button: *mut GtkButton, user_data: gpointer) {
+    let entry: &history::Entry = unsafe { &*(user_data as *const history::Entry) };
+    open_url(&entry.track.url[..]);
+}
+
+extern "C" fn history_buy_clicked(_button: *mut GtkButton, user_data: gpointer) {
+    let entry: &history::Entry = unsafe { &*(user_data as *const history::Entry) };
+    open_url(&entry.track.buy_link[..]);
+}
+
+extern "C" fn history_copy_clicked(_button: *mut GtkButton, user_data: gpointer) {
+    let entry: &history::Entry = unsafe { &*(user_data as *const history::Entry) };
+    let text = rffi::CString::new(entry.artist_and_title()).unwrap();
+    unsafe {
+        let clipboard_c_str = rffi::CString::new("CLIPBOARD").unwrap();
+        let clipboard = gtk_clipboard_get(gdk_atom_intern(clipboard_c_str.as_ptr(), 0));
+        gtk_clipboard_set_text(clipboard, text.as_ptr(), -1);
+    }
 }
 
 extern "C" fn smart_id_entry_activated(entry: *mut GtkEntry, user_data: gpointer) {
     let gui: &mut Gui = unsafe { &mut *(user_data as *mut Gui) };
     let id = unsafe { utils::ptr_to_string(gtk_entry_get_text(entry) as *const i8) };
-    gui.get_sender().send(GuiUpdateMessage::GetMixes(id));
+    gui.get_sender().send(GuiUpdateMessage::Command(app::Command::GetMixes(id)));
 }
@@ -0,0 +1,91 @@
+// The tracks we played, newest last. Kept across restarts in
+// $XDG_DATA_HOME/rusttracks/history.json.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+
+use rustc_serialize::json;
+
+use api;
+use paths;
+use utils;
+
+/// Older entries are dropped.
+pub static MAX_ENTRIES: usize = 1000;
+
+#[derive(RustcEncodable, RustcDecodable, Clone)]
+pub struct Entry {
+    pub played_at: api::Timestamp,
+    pub track: api::Track,
+    pub mix_id: u32,
+    pub mix_name: String,
+}
+
+impl Entry {
+    /// "Artist – Title", for the clipboard.
+    pub fn artist_and_title(&self) -> String {
+        format!("{} \u{2013} {}", self.track.performer, self.track.name)
+    }
+}
+
+pub struct History {
+    entries: Vec<Entry>,
+}
+
+impl History {
+    pub fn path() -> PathBuf {
+        let mut path = paths::xdg_app_dir("XDG_DATA_HOME", ".local/share");
+        path.push("history.json");
+        path
+    }
+
+    /// Loads the saved history, an empty one if there is none or it can't be read.
+    pub fn load() -> History {
+        let path = History::path();
+        let mut data = String::new();
+        match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut data)) {
+            Ok(_) => (),
+            Err(err) => {
+                debug!("not reading history `{}`: {}", path.display(), err);
+                return History { entries: Vec::new() };
+            }
+        }
+        match json::decode(&data[..]) {
+            Ok(entries) => History { entries: entries },
+            Err(err) => {
+                warn!("ignoring broken history `{}`: {:?}", path.display(), err);
+                History { entries: Vec::new() }
+            }
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries[..]
+    }
+
+    /// Adds a track that just started and saves the history.
+    pub fn record(&mut self, track: &api::Track, mix: &api::Mix) -> Entry {
+        let entry = Entry {
+            played_at: api::Timestamp::now(),
+            track: track.clone(),
+            mix_id: mix.id,
+            mix_name: mix.name.clone(),
+        };
+        self.entries.push(entry.clone());
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        match self.save() {
+            Ok(()) => (),
+            Err(err) => warn!("could not save the history: {}", err),
+        }
+        entry
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = try!(json::encode(&self.entries).map_err(utils::encoder_error));
+        utils::write_atomically(&History::path(), data.as_bytes())
+    }
+}
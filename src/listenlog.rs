@@ -0,0 +1,197 @@
+// The listening log: every track that was played long enough to be reported to 8tracks, one
+// json object per line in $XDG_DATA_HOME/rusttracks/listens.jsonl. `rusttracks --export FORMAT
+// [FILE]` writes it as csv, json or an Audioscrobbler `.scrobbler.log` for other music tools.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use rustc_serialize::json;
+
+use app;
+use paths;
+use utils;
+
+pub fn path() -> PathBuf {
+    let mut path = paths::xdg_app_dir("XDG_DATA_HOME", ".local/share");
+    path.push("listens.jsonl");
+    path
+}
+
+/// Appends a listen to the log, errors are only logged.
+pub fn record(listen: &app::Listen) {
+    match append(listen) {
+        Ok(()) => (),
+        Err(err) => warn!("could not write to the listening log: {}", err),
+    }
+}
+
+fn append(listen: &app::Listen) -> io::Result<()> {
+    let path = path();
+    match path.parent() {
+        Some(dir) => try!(fs::create_dir_all(dir)),
+        None => (),
+    }
+    let line = try!(json::encode(listen).map_err(utils::encoder_error));
+    let mut file = try!(fs::OpenOptions::new().append(true).create(true).open(&path));
+    writeln!(file, "{}", line)
+}
+
+/// All logged listens, oldest first. Broken lines are skipped.
+pub fn read() -> io::Result<Vec<app::Listen>> {
+    let file = match fs::File::open(&path()) {
+        Ok(f) => f,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut listens = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        match json::decode(&line[..]) {
+            Ok(listen) => listens.push(listen),
+            Err(err) => warn!("skipping broken listening log line: {:?}", err),
+        }
+    }
+    Ok(listens)
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Csv,
+    Json,
+    ScrobblerLog,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "json" => Some(Format::Json),
+            "scrobbler" | "scrobbler.log" => Some(Format::ScrobblerLog),
+            _ => None,
+        }
+    }
+}
+
+pub fn export<W: Write>(listens: &[app::Listen], format: Format, out: &mut W) -> io::Result<()> {
+    match format {
+        Format::Csv => export_csv(listens, out),
+        Format::Json => {
+            let data = try!(json::encode(&listens).map_err(utils::encoder_error));
+            writeln!(out, "{}", data)
+        }
+        Format::ScrobblerLog => export_scrobbler_log(listens, out),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn export_csv<W: Write>(listens: &[app::Listen], out: &mut W) -> io::Result<()> {
+    try!(writeln!(out, "timestamp,artist,title,release,year,track_id,mix_id,\
+                        listened_seconds,duration_seconds,url"));
+    for listen in listens.iter() {
+        let track = &listen.track;
+        let fields = [
+            listen.played_at.iso_string(),
+            track.performer.clone(),
+            track.name.clone(),
+            track.release_name.clone().unwrap_or(String::new()),
+            track.year.map(|y| y.to_string()).unwrap_or(String::new()),
+            track.id.to_string(),
+            listen.mix_id.to_string(),
+            listen.listened.to_string(),
+            listen.duration.map(|d| d.to_string()).unwrap_or(String::new()),
+            track.url.clone(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(&f[..])).collect();
+        try!(writeln!(out, "{}", fields.connect(",")));
+    }
+    Ok(())
+}
+
+// Tabs separate the fields.
+fn scrobbler_field(field: &str) -> String {
+    field.replace("\t", " ").replace("\n", " ")
+}
+
+// See the Audioscrobbler portable player logging spec, version 1.1.
+fn export_scrobbler_log<W: Write>(listens: &[app::Listen], out: &mut W) -> io::Result<()> {
+    try!(writeln!(out, "#AUDIOSCROBBLER/1.1"));
+    try!(writeln!(out, "#TZ/UTC"));
+    try!(writeln!(out, "#CLIENT/RustTracks"));
+    for listen in listens.iter() {
+        let track = &listen.track;
+        // L means listened, S skipped before half of the track.
+        let rating = match listen.duration {
+            Some(d) if listen.listened * 2 < d => "S",
+            _ => "L",
+        };
+        let length = listen.duration.unwrap_or(listen.listened);
+        try!(writeln!(out, "{}\t{}\t{}\t\t{}\t{}\t{}\t",
+                      scrobbler_field(&track.performer[..]),
+                      scrobbler_field(&track.release_name.clone().unwrap_or(String::new())[..]),
+                      scrobbler_field(&track.name[..]),
+                      length, rating, listen.played_at.secs));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use api;
+    use app;
+
+    use super::*;
+
+    fn listen() -> app::Listen {
+        app::Listen {
+            played_at: api::Timestamp { secs: 1398947696 },
+            track: api::Track {
+                id: 7,
+                name: "Song, \"live\"".to_string(),
+                performer: "Band".to_string(),
+                release_name: Some("Album".to_string()),
+                year: Some(2001),
+                track_file_stream_url: String::new(),
+                buy_link: String::new(),
+                faved_by_current_user: false,
+                url: String::new(),
+            },
+            mix_id: 3,
+            listened: 100,
+            duration: Some(240),
+        }
+    }
+
+    fn exported(format: Format) -> String {
+        let mut out = Vec::new();
+        export(&[listen()], format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_quotes_fields() {
+        let csv = exported(Format::Csv);
+        let line = csv.lines().nth(1).unwrap();
+        assert_eq!(line,
+                   "2014-05-01T12:34:56Z,Band,\"Song, \"\"live\"\"\",Album,2001,7,3,100,240,");
+    }
+
+    #[test]
+    fn scrobbler_log_marks_short_listens_as_skipped() {
+        let log = exported(Format::ScrobblerLog);
+        assert!(log.starts_with("#AUDIOSCROBBLER/1.1\n"));
+        let line = log.lines().last().unwrap();
+        assert_eq!(line, "Band\tAlbum\tSong, \"live\"\t\t240\tS\t1398947696\t");
+    }
+}
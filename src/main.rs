@@ -1,4 +1,5 @@
 #![feature(rustc_private)]
+#![feature(static_mutex)]
 #![feature(unsafe_destructor)]
 
 #[macro_use]
@@ -11,22 +12,132 @@ extern crate url;
 extern crate gtk;
 extern crate hyper;
 extern crate timerfd;
+extern crate unix_socket;
 
 use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::process;
 
 mod api;
+mod app;
+mod config;
+mod control;
+mod covercache;
+mod coverviewer;
+mod dbus;
+#[cfg(test)]
+mod fake_player;
+#[cfg(test)]
+mod fixtures;
+mod gst;
 mod gui;
+mod history;
+mod listenlog;
+mod mixdetails;
+mod mpd;
+mod mpris;
+mod network;
+mod notifications;
+mod paths;
 mod player;
+mod remote;
+mod scrobbler;
+mod shortcuts;
+mod tui;
 mod utils;
 mod webinterface;
 
 pub fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let smart_id = take_smart_id(&mut args);
+    match args.iter().position(|a| &a[..] == "--export") {
+        Some(i) => {
+            export_listens(args.get(i + 1).map(|f| &f[..]), args.get(i + 2).map(|p| &p[..]));
+            return;
+        }
+        None => (),
+    }
+    if args.iter().any(|a| &a[..] == "--clear-cover-cache") {
+        clear_cover_cache();
+        return;
+    }
+    if args.iter().any(|a| &a[..] == "--tui") {
+        let args = args.into_iter().filter(|a| &a[..] != "--tui").collect();
+        tui::run(args, smart_id);
+        return;
+    }
+    if control::forward_to_running_instance(smart_id.as_ref().map(|s| &s[..])) {
+        return;
+    }
+
     let mut gui = gui::Gui::new();
-    gui.init(env::args().collect());
+    gui.init(args);
 
     gui.get_sender().send(gui::GuiUpdateMessage::Notify("Welcome to RustTracks!".to_string()));
-    gui.get_sender().send(gui::GuiUpdateMessage::FetchPlayToken);
-    gui.get_sender().send(gui::GuiUpdateMessage::GetMixes("tags:folk:recent".to_string()));
+    gui.get_sender().send(gui::GuiUpdateMessage::Command(app::Command::FetchPlayToken));
+    let command = match smart_id {
+        Some(id) => app::Command::PlaySmartId(id),
+        None => app::Command::GetMixes("tags:folk:recent".to_string()),
+    };
+    gui.get_sender().send(gui::GuiUpdateMessage::Command(command));
 
     gui.run();
 }
+
+/// `--play SMART_ID`: the mixes to play, taken out of `args` so that GTK and GStreamer, which
+/// get the rest, don't see it.
+fn take_smart_id(args: &mut Vec<String>) -> Option<String> {
+    let i = match args.iter().position(|a| &a[..] == "--play") {
+        Some(i) => i,
+        None => return None,
+    };
+    if i + 1 >= args.len() {
+        let _ = writeln!(io::stderr(), "usage: rusttracks [--tui] [--play SMART_ID]");
+        process::exit(2);
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+/// `--clear-cover-cache`: removes all downloaded cover pictures.
+fn clear_cover_cache() {
+    let cache = covercache::CoverCache::open(0);
+    let result = cache.lock().unwrap().clear();
+    match result {
+        Ok(()) => println!("cleared `{}`", covercache::CoverCache::dir().display()),
+        Err(err) => fail(&format!("could not clear the cover cache: {}", err)[..]),
+    }
+}
+
+/// `--export FORMAT [FILE]`: writes the listening log to FILE or stdout.
+fn export_listens(format: Option<&str>, file: Option<&str>) {
+    let format = match format.and_then(listenlog::Format::from_name) {
+        Some(f) => f,
+        None => {
+            let _ = writeln!(io::stderr(), "usage: rusttracks --export csv|json|scrobbler [FILE]");
+            process::exit(2);
+        }
+    };
+    let listens = match listenlog::read() {
+        Ok(l) => l,
+        Err(err) => fail(&format!("could not read the listening log: {}", err)[..]),
+    };
+    let result = match file {
+        Some(path) => fs::File::create(path).and_then(|mut f| {
+            listenlog::export(&listens[..], format, &mut f)
+        }),
+        None => listenlog::export(&listens[..], format, &mut io::stdout()),
+    };
+    match result {
+        Ok(()) => (),
+        Err(err) => fail(&format!("could not export the listening log: {}", err)[..]),
+    }
+}
+
+// Errors go to stderr, stdout may be an export.
+fn fail(message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "rusttracks: {}", message);
+    process::exit(1);
+}
@@ -0,0 +1,315 @@
+// The detail window of a mix, opened by clicking its row in the mix list.
+
+use std::ffi as rffi;
+use std::mem;
+use std::ptr;
+use std::sync::mpsc;
+
+use gtk::ffi::*;
+use gtk::*;
+
+use api;
+use app;
+use gui::{GuiUpdateMessage, Pixbuf};
+use utils;
+
+pub struct MixDetails {
+    window: *mut GtkWidget,
+    // The mix's index in the mix list, for the Play button.
+    index: usize,
+    // The smart ids the tag chips search for, in the order of the chips.
+    tag_smart_ids: Vec<String>,
+    // What the tag chip callbacks get, pointers into this struct and an index into
+    // tag_smart_ids each.
+    tag_table: Vec<(*const MixDetails, usize)>,
+    sender: mpsc::Sender<GuiUpdateMessage>,
+}
+
+impl Drop for MixDetails {
+    fn drop(&mut self) {
+        unsafe {
+            gtk_widget_destroy(self.window);
+        }
+    }
+}
+
+impl MixDetails {
+    /// Opens the window. It is only hidden when the user closes it and goes away with the
+    /// returned value.
+    pub fn new(parent: *mut GtkWidget, mix: &api::Mix, index: usize, cover: Option<&Pixbuf>,
+               sender: mpsc::Sender<GuiUpdateMessage>) -> Box<MixDetails> {
+        let window = unsafe { gtk_window_new(GTK_WINDOW_TOPLEVEL) };
+        let mut details = Box::new(MixDetails {
+            window: window,
+            index: index,
+            tag_smart_ids: mix.tags.iter().map(|t| t.smart_id()).collect(),
+            tag_table: Vec::new(),
+            sender: sender,
+        });
+        let details_ptr = &*details as *const MixDetails;
+        details.tag_table = (0..mix.tags.len()).map(|i| (details_ptr, i)).collect();
+        unsafe {
+            let title_c_str = rffi::CString::new(mix.name.as_bytes()).unwrap();
+            gtk_window_set_title(window as *mut GtkWindow, title_c_str.as_ptr());
+            gtk_window_set_transient_for(window as *mut GtkWindow, parent as *mut GtkWindow);
+            gtk_window_set_type_hint(window as *mut GtkWindow, GDK_WINDOW_TYPE_HINT_DIALOG);
+            gtk_window_set_default_size(window as *mut GtkWindow, 400, 450);
+            gtk_container_set_border_width(window as *mut GtkContainer, 12);
+            let delete_c_str = rffi::CString::new("delete-event").unwrap();
+            g_signal_connect(window as gpointer,
+                             delete_c_str.as_ptr(),
+                             Some(mem::transmute(gtk_widget_hide_on_delete)),
+                             ptr::null_mut());
+
+            let outer_box = gtk_box_new(GTK_ORIENTATION_VERTICAL, 10);
+            gtk_container_add(window as *mut GtkContainer, outer_box);
+
+            // Cover and the facts next to it.
+            let header_box = gtk_box_new(GTK_ORIENTATION_HORIZONTAL, 10);
+            gtk_box_pack_start(outer_box as *mut GtkBox, header_box, 0, 0, 0);
+            let image = gtk_image_new();
+            gtk_misc_set_alignment(image as *mut GtkMisc, 0.5f32, 0f32);
+            gtk_box_pack_start(header_box as *mut GtkBox, image, 0, 0, 0);
+            match cover {
+                Some(c) => c.show_in(image as *mut GtkImage),
+                None => (),
+            }
+
+            let facts_box = gtk_box_new(GTK_ORIENTATION_VERTICAL, 4);
+            gtk_box_pack_start(header_box as *mut GtkBox, facts_box, 1, 1, 0);
+
+            let mut name = format!("<big><b>{}</b></big>", utils::markup_escape(&mix.name[..]));
+            match mix.certification {
+                Some(ref c) if !c.is_empty() => {
+                    name.push_str(" ");
+                    name.push_str(&certification_badge(&c[..])[..]);
+                }
+                _ => (),
+            }
+            if mix.nsfw {
+                name.push_str(" ");
+                name.push_str(&nsfw_badge()[..]);
+            }
+            add_markup_label(facts_box, &name[..]);
+            // Mix lists don't always come with the creator's name.
+            let creator = match mix.user_login {
+                Some(ref login) => format!("by {}", utils::markup_escape(&login[..])),
+                None => format!("by user #{}", mix.user_id),
+            };
+            add_markup_label(facts_box, &creator[..]);
+            add_markup_label(facts_box, &format!("{} · {} tracks",
+                                                 utils::format_duration(mix.duration),
+                                                 mix.tracks_count)[..]);
+            add_markup_label(facts_box, &format!("{} plays · {} likes",
+                                                 mix.plays_count, mix.likes_count)[..]);
+            match mix.first_published_at {
+                Some(ref t) => {
+                    add_markup_label(facts_box, &format!("Published {}", t.date_string())[..]);
+                }
+                None => (),
+            }
+            let web_url_c_str =
+                rffi::CString::new(format!("http://8tracks.com{}", mix.web_path)).unwrap();
+            let open_c_str = rffi::CString::new("Open on 8tracks").unwrap();
+            let link = gtk_link_button_new_with_label(web_url_c_str.as_ptr(), open_c_str.as_ptr());
+            gtk_widget_set_halign(link, GTK_ALIGN_START);
+            gtk_box_pack_start(facts_box as *mut GtkBox, link, 0, 0, 0);
+
+            // Tags as chips, clicking one searches for its mixes.
+            if !mix.tags.is_empty() {
+                let tag_box = gtk_flow_box_new();
+                gtk_flow_box_set_selection_mode(tag_box as *mut GtkFlowBox, GTK_SELECTION_NONE);
+                gtk_flow_box_set_max_children_per_line(tag_box as *mut GtkFlowBox, 30);
+                gtk_box_pack_start(outer_box as *mut GtkBox, tag_box, 0, 0, 0);
+                let clicked_c_str = rffi::CString::new("clicked").unwrap();
+                for (i, tag) in mix.tags.iter().enumerate() {
+                    let tag_c_str = rffi::CString::new(tag.name.as_bytes()).unwrap();
+                    let chip = gtk_button_new_with_label(tag_c_str.as_ptr());
+                    gtk_button_set_relief(chip as *mut GtkButton, GTK_RELIEF_NONE);
+                    let tooltip_c_str =
+                        rffi::CString::new(format!("Search mixes tagged '{}'", tag.name)).unwrap();
+                    gtk_widget_set_tooltip_text(chip, tooltip_c_str.as_ptr());
+                    gtk_container_add(tag_box as *mut GtkContainer, chip);
+                    g_signal_connect(chip as gpointer,
+                                     clicked_c_str.as_ptr(),
+                                     Some(mem::transmute(tag_chip_clicked)),
+                                     mem::transmute::<&(*const MixDetails, usize), gpointer>(
+                                         &details.tag_table[i]));
+                }
+            }
+
+            // The description can be long.
+            let scrolled_window = gtk_scrolled_window_new(ptr::null_mut(), ptr::null_mut());
+            gtk_scrolled_window_set_policy(scrolled_window as *mut GtkScrolledWindow,
+                GTK_POLICY_NEVER, GTK_POLICY_AUTOMATIC);
+            gtk_box_pack_start(outer_box as *mut GtkBox, scrolled_window, 1, 1, 0);
+            let description = if mix.description.trim().is_empty() {
+                "<i>No description.</i>".to_string()
+            } else {
+                render_description(mix.description.trim())
+            };
+            let description_label = gtk_label_new(ptr::null());
+            let description_c_str = rffi::CString::new(description).unwrap();
+            gtk_label_set_markup(description_label as *mut GtkLabel, description_c_str.as_ptr());
+            gtk_label_set_line_wrap(description_label as *mut GtkLabel, 1);
+            gtk_label_set_line_wrap_mode(description_label as *mut GtkLabel, PANGO_WRAP_WORD_CHAR);
+            gtk_label_set_selectable(description_label as *mut GtkLabel, 1);
+            gtk_misc_set_alignment(description_label as *mut GtkMisc, 0f32, 0f32);
+            gtk_container_add(scrolled_window as *mut GtkContainer, description_label);
+
+            let button_box = gtk_box_new(GTK_ORIENTATION_HORIZONTAL, 5);
+            gtk_box_pack_end(outer_box as *mut GtkBox, button_box, 0, 0, 0);
+            let clicked_c_str = rffi::CString::new("clicked").unwrap();
+
+            let close_c_str = rffi::CString::new("Close").unwrap();
+            let close_button = gtk_button_new_with_label(close_c_str.as_ptr());
+            gtk_box_pack_end(button_box as *mut GtkBox, close_button, 0, 0, 0);
+            g_signal_connect(close_button as gpointer,
+                             clicked_c_str.as_ptr(),
+                             Some(mem::transmute(close_button_clicked)),
+                             mem::transmute::<&MixDetails, gpointer>(&*details));
+
+            let play_c_str = rffi::CString::new("Play").unwrap();
+            let play_button = gtk_button_new_with_label(play_c_str.as_ptr());
+            gtk_box_pack_end(button_box as *mut GtkBox, play_button, 0, 0, 0);
+            g_signal_connect(play_button as gpointer,
+                             clicked_c_str.as_ptr(),
+                             Some(mem::transmute(play_button_clicked)),
+                             mem::transmute::<&MixDetails, gpointer>(&*details));
+
+            gtk_widget_show_all(window);
+        }
+        details
+    }
+
+    pub fn present(&self) {
+        unsafe {
+            gtk_window_present(self.window as *mut GtkWindow);
+        }
+    }
+
+    fn hide(&self) {
+        unsafe {
+            gtk_widget_hide(self.window);
+        }
+    }
+}
+
+unsafe fn add_markup_label(container: *mut GtkWidget, markup: &str) {
+    let label = gtk_label_new(ptr::null());
+    let markup_c_str = rffi::CString::new(markup).unwrap();
+    gtk_label_set_markup(label as *mut GtkLabel, markup_c_str.as_ptr());
+    gtk_label_set_line_wrap(label as *mut GtkLabel, 1);
+    gtk_misc_set_alignment(label as *mut GtkMisc, 0f32, 0.5f32);
+    gtk_box_pack_start(container as *mut GtkBox, label, 0, 0, 0);
+}
+
+/// 8tracks certifies popular mixes, e.g. `gold` or `platinum`.
+fn certification_badge(certification: &str) -> String {
+    let background = match certification {
+        "bronze" => "#a97142",
+        "silver" => "#8f8f8f",
+        "gold" => "#c9a227",
+        "platinum" => "#6c7a89",
+        "diamond" => "#3b8fc4",
+        _ => "#555555",
+    };
+    badge(background, &certification.to_uppercase()[..])
+}
+
+/// Marks explicit mixes that aren't hidden.
+pub fn nsfw_badge() -> String {
+    badge("#c0392b", "NSFW")
+}
+
+fn badge(background: &str, text: &str) -> String {
+    format!("<span background=\"{}\" foreground=\"white\" size=\"small\"><b> {} </b></span>",
+            background, utils::markup_escape(text))
+}
+
+fn find_url(text: &str) -> Option<usize> {
+    match (text.find("http://"), text.find("https://")) {
+        (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Pango markup for a mix description: the plain text with its links made clickable.
+fn render_description(text: &str) -> String {
+    let mut markup = String::new();
+    let mut rest = text;
+    loop {
+        let start = match find_url(rest) {
+            Some(s) => s,
+            None => break,
+        };
+        markup.push_str(&utils::markup_escape(&rest[..start])[..]);
+        let end = rest[start..].find(|c: char| c.is_whitespace())
+                               .map(|e| start + e).unwrap_or(rest.len());
+        // Punctuation after a link is part of the sentence.
+        let url = rest[start..end].trim_right_matches(|c: char| ".,;:!?)\"'".contains(c));
+        let escaped_url = utils::markup_escape(url);
+        markup.push_str(&format!("<a href=\"{0}\">{0}</a>", escaped_url)[..]);
+        rest = &rest[start + url.len()..];
+    }
+    markup.push_str(&utils::markup_escape(rest)[..]);
+    markup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_url, render_description};
+
+    #[test]
+    fn the_first_link_is_found() {
+        assert_eq!(find_url("no links here"), None);
+        assert_eq!(find_url("see http://a.com"), Some(4));
+        assert_eq!(find_url("see https://b.com and http://a.com"), Some(4));
+        assert_eq!(find_url("see http://a.com and https://b.com"), Some(4));
+    }
+
+    #[test]
+    fn links_become_clickable() {
+        assert_eq!(render_description("listen: http://a.com/x and https://b.com/y"),
+                   "listen: <a href=\"http://a.com/x\">http://a.com/x</a> and \
+                    <a href=\"https://b.com/y\">https://b.com/y</a>");
+    }
+
+    #[test]
+    fn trailing_punctuation_stays_in_the_sentence() {
+        assert_eq!(render_description("(see https://a.com/x)."),
+                   "(see <a href=\"https://a.com/x\">https://a.com/x</a>).");
+        assert_eq!(render_description("at http://a.com, http://b.com!"),
+                   "at <a href=\"http://a.com\">http://a.com</a>, \
+                    <a href=\"http://b.com\">http://b.com</a>!");
+    }
+
+    #[test]
+    fn text_and_links_are_escaped() {
+        assert_eq!(render_description("rock & <roll> http://a.com/?a&b<"),
+                   "rock &amp; &lt;roll&gt; \
+                    <a href=\"http://a.com/?a&amp;b&lt;\">http://a.com/?a&amp;b&lt;</a>");
+    }
+}
+
+extern "C" fn close_button_clicked(_button: *mut GtkButton, user_data: gpointer) {
+    let details: &MixDetails = unsafe { &*(user_data as *const MixDetails) };
+    details.hide();
+}
+
+extern "C" fn tag_chip_clicked(_button: *mut GtkButton, user_data: gpointer) {
+    let (details, i) = unsafe {
+        let &(details_ptr, i): &(*const MixDetails, usize) = mem::transmute(user_data);
+        (&*details_ptr, i)
+    };
+    let smart_id = details.tag_smart_ids[i].clone();
+    details.sender.send(GuiUpdateMessage::Command(app::Command::GetMixes(smart_id)));
+    details.hide();
+}
+
+extern "C" fn play_button_clicked(_button: *mut GtkButton, user_data: gpointer) {
+    let details: &MixDetails = unsafe { &*(user_data as *const MixDetails) };
+    details.sender.send(GuiUpdateMessage::Command(app::Command::PlayMix(details.index)));
+    details.hide();
+}
@@ -0,0 +1,515 @@
+// A small part of the MPD protocol, so that MPD clients (mpc, ncmpcpp, phone apps) can control
+// us. The loaded mix list is the playlist, one "song" per mix, and playing a position plays that
+// mix. currentsong is the track that's playing right now. search loads the mixes for a smart id
+// like the GUI's search, the results become the playlist once they're there. See
+// https://www.musicpd.org/doc/protocol/ for the protocol itself.
+
+use std::io;
+use std::io::{BufRead, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+use api;
+use app;
+use gui;
+use gui::GuiUpdateMessage;
+
+static GREETING: &'static str = "OK MPD 0.19.0\n";
+
+// Error codes from MPD's ack.h.
+static ACK_ERROR_ARG: u32 = 2;
+static ACK_ERROR_UNKNOWN: u32 = 5;
+static ACK_ERROR_NO_EXIST: u32 = 50;
+static ACK_ERROR_SYSTEM: u32 = 52;
+
+static SUPPORTED_COMMANDS: &'static [&'static str] = &[
+    "close", "commands", "currentsong", "idle", "next", "noidle", "notcommands", "outputs",
+    "pause", "ping", "play", "playid", "playlistinfo", "search", "status", "tagtypes",
+];
+
+pub fn start(address: &str, sender: mpsc::Sender<GuiUpdateMessage>) -> io::Result<()> {
+    let listener = try!(TcpListener::bind(address));
+    info!("MPD server listening on {}", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        match serve_client(stream, sender) {
+                            Ok(()) => (),
+                            Err(err) => debug!("MPD client went away: {}", err),
+                        }
+                    });
+                }
+                Err(err) => warn!("MPD server: could not accept client: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: u32, message: String) -> Ack {
+        Ack { code: code, message: message }
+    }
+}
+
+// What a client's thread waits for: lines from the client, and while it's idle new states from
+// the GUI.
+enum Event {
+    Line(String),
+    Closed,
+    Status(app::Status),
+}
+
+struct Client {
+    sender: mpsc::Sender<GuiUpdateMessage>,
+    writer: TcpStream,
+    events: mpsc::Receiver<Event>,
+    event_sender: mpsc::Sender<Event>,
+    // Whether we get the GUI's status updates, from the first idle on.
+    subscribed: bool,
+    closed: bool,
+    // Filled by the commands, sent once the command (list) succeeded.
+    response: String,
+}
+
+// Runs on its own thread, so that idle can wait for the client and the GUI at the same time.
+fn read_lines(stream: TcpStream, events: mpsc::Sender<Event>) {
+    let mut reader = io::BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) => {
+                debug!("MPD client went away: {}", err);
+                break;
+            }
+        }
+        let line = line.trim_right_matches(|c| c == '\n' || c == '\r').to_string();
+        if events.send(Event::Line(line)).is_err() {
+            return;
+        }
+    }
+    let _ = events.send(Event::Closed);
+}
+
+fn serve_client(stream: TcpStream, sender: mpsc::Sender<GuiUpdateMessage>) -> io::Result<()> {
+    let (event_sender, events) = mpsc::channel();
+    let mut client = Client {
+        sender: sender,
+        writer: try!(stream.try_clone()),
+        events: events,
+        event_sender: event_sender.clone(),
+        subscribed: false,
+        closed: false,
+        response: String::new(),
+    };
+    try!(client.writer.write_all(GREETING.as_bytes()));
+    thread::spawn(move || read_lines(stream, event_sender));
+
+    // Some(list_ok) while we're collecting a command list.
+    let mut command_list: Option<(bool, Vec<String>)> = None;
+    loop {
+        let line = match client.next_line() {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+
+        let (list_ok, lines) = match command_list.take() {
+            Some((list_ok, lines)) => {
+                if line == "command_list_end" {
+                    (list_ok, lines)
+                } else {
+                    let mut lines = lines;
+                    lines.push(line);
+                    command_list = Some((list_ok, lines));
+                    continue;
+                }
+            }
+            None if line == "command_list_begin" => {
+                command_list = Some((false, Vec::new()));
+                continue;
+            }
+            None if line == "command_list_ok_begin" => {
+                command_list = Some((true, Vec::new()));
+                continue;
+            }
+            // Ends the reader thread as well.
+            None if line == "close" => return client.writer.shutdown(Shutdown::Both),
+            None => (false, vec![line]),
+        };
+
+        client.response.clear();
+        let mut failed = false;
+        for (i, line) in lines.iter().enumerate() {
+            match client.execute(&line[..]) {
+                Ok(()) => {
+                    if list_ok {
+                        client.response.push_str("list_OK\n");
+                    }
+                }
+                Err(ack) => {
+                    let name = line.split(' ').next().unwrap_or("");
+                    client.response.push_str(&format!("ACK [{}@{}] {{{}}} {}\n",
+                                                      ack.code, i, name, ack.message)[..]);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if client.closed {
+            return Ok(());
+        }
+        if !failed {
+            client.response.push_str("OK\n");
+        }
+        try!(client.writer.write_all(client.response.as_bytes()));
+    }
+}
+
+/// Splits a command line into its words, handling double quotes and backslash escapes.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek() == Some(&' ') || chars.peek() == Some(&'\t') {
+            chars.next();
+        }
+        let mut arg = String::new();
+        match chars.peek() {
+            None => return Ok(args),
+            Some(&'"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        None => return Err("missing closing '\"'".to_string()),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => return Err("missing closing '\"'".to_string()),
+                        },
+                        Some(c) => arg.push(c),
+                    }
+                }
+            }
+            Some(_) => {
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' || c == '\t' {
+                        break;
+                    }
+                    arg.push(c);
+                    chars.next();
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// Playlist version, so that clients notice when the mix list changed.
+fn playlist_version(mixes: &[api::Mix]) -> u32 {
+    mixes.iter().fold(mixes.len() as u32, |acc, mix| acc.wrapping_mul(31).wrapping_add(mix.id))
+}
+
+fn current_mix_position(status: &app::Status) -> Option<usize> {
+    status.mix.as_ref().and_then(|current| status.mixes.iter().position(|m| m.id == current.id))
+}
+
+impl Client {
+    // The next line from the client, None once it's gone. GUI updates are only of interest while
+    // we're idle.
+    fn next_line(&mut self) -> Option<String> {
+        loop {
+            match self.events.recv() {
+                Ok(Event::Line(line)) => return Some(line),
+                Ok(Event::Status(_)) => (),
+                Ok(Event::Closed) | Err(_) => return None,
+            }
+        }
+    }
+
+    fn line(&mut self, key: &str, value: &str) {
+        // Values can't span lines.
+        let value = value.replace("\n", " ");
+        self.response.push_str(&format!("{}: {}\n", key, value)[..]);
+    }
+
+    fn status(&self) -> Result<app::Status, Ack> {
+        gui::query_status(&self.sender).ok_or_else(|| {
+            Ack::new(ACK_ERROR_SYSTEM, "rusttracks is shutting down".to_string())
+        })
+    }
+
+    fn send(&self, command: app::Command) -> Result<(), Ack> {
+        self.sender.send(GuiUpdateMessage::Command(command)).map_err(|_| {
+            Ack::new(ACK_ERROR_SYSTEM, "rusttracks is shutting down".to_string())
+        })
+    }
+
+    fn execute(&mut self, line: &str) -> Result<(), Ack> {
+        let args = try!(split_args(line).map_err(|e| Ack::new(ACK_ERROR_ARG, e)));
+        let (command, args) = match args.split_first() {
+            Some((c, rest)) => (c.clone(), rest.to_vec()),
+            None => return Err(Ack::new(ACK_ERROR_UNKNOWN, "No command given".to_string())),
+        };
+        debug!("MPD command `{}` {:?}", command, args);
+        match &command[..] {
+            "ping" => Ok(()),
+            "status" => self.cmd_status(),
+            "currentsong" => self.cmd_currentsong(),
+            "playlistinfo" => self.cmd_playlistinfo(),
+            "pause" => self.cmd_pause(&args[..]),
+            "play" => self.cmd_play(&args[..], false),
+            "playid" => self.cmd_play(&args[..], true),
+            "next" => self.send(app::Command::SkipTrack),
+            "search" => self.cmd_search(&args[..]),
+            "idle" => self.cmd_idle(),
+            "noidle" => Ok(()),
+            "outputs" => Ok(()),
+            "tagtypes" => {
+                for tag in ["Artist", "Album", "Title", "Date"].iter() {
+                    self.line("tagtype", tag);
+                }
+                Ok(())
+            }
+            "commands" => {
+                for c in SUPPORTED_COMMANDS.iter() {
+                    self.line("command", c);
+                }
+                Ok(())
+            }
+            "notcommands" => Ok(()),
+            _ => Err(Ack::new(ACK_ERROR_UNKNOWN, format!("unknown command \"{}\"", command))),
+        }
+    }
+
+    fn cmd_status(&mut self) -> Result<(), Ack> {
+        let status = try!(self.status());
+        let state = match (&status.track, status.playing) {
+            (&None, _) => "stop",
+            (&Some(_), true) => "play",
+            (&Some(_), false) => "pause",
+        };
+        self.line("volume", "100");
+        self.line("repeat", "0");
+        self.line("random", "0");
+        self.line("single", "0");
+        self.line("consume", "0");
+        self.line("playlist", &playlist_version(&status.mixes[..]).to_string()[..]);
+        self.line("playlistlength", &status.mixes.len().to_string()[..]);
+        self.line("state", state);
+        match (current_mix_position(&status), &status.mix) {
+            (Some(pos), &Some(ref mix)) => {
+                self.line("song", &pos.to_string()[..]);
+                self.line("songid", &mix.id.to_string()[..]);
+            }
+            _ => (),
+        }
+        match (&status.track, status.progress) {
+            (&Some(_), Some((pos, dur))) => {
+                let (pos, dur) = (pos as f64 / 1e9, dur as f64 / 1e9);
+                self.line("time", &format!("{}:{}", pos as u64, dur as u64)[..]);
+                self.line("elapsed", &format!("{:.3}", pos)[..]);
+                self.line("duration", &format!("{:.3}", dur)[..]);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn track_lines(&mut self, track: &api::Track, status: &app::Status) {
+        self.line("file", &track.url[..]);
+        self.line("Title", &track.name[..]);
+        self.line("Artist", &track.performer[..]);
+        match track.release_name {
+            Some(ref rn) => self.line("Album", &rn[..]),
+            None => (),
+        }
+        match track.year {
+            Some(year) => self.line("Date", &year.to_string()[..]),
+            None => (),
+        }
+        match status.progress {
+            Some((_, dur)) => self.line("Time", &(dur / 1000000000).to_string()[..]),
+            None => (),
+        }
+        match (current_mix_position(status), &status.mix) {
+            (Some(pos), &Some(ref mix)) => {
+                self.line("Pos", &pos.to_string()[..]);
+                self.line("Id", &mix.id.to_string()[..]);
+            }
+            _ => (),
+        }
+    }
+
+    fn mix_lines(&mut self, mix: &api::Mix, pos: Option<usize>) {
+        self.line("file", &format!("http://8tracks.com{}", mix.web_path)[..]);
+        self.line("Title", &mix.name[..]);
+        self.line("Album", &mix.name[..]);
+        self.line("Time", &mix.duration.to_string()[..]);
+        match pos {
+            Some(pos) => self.line("Pos", &pos.to_string()[..]),
+            None => (),
+        }
+        self.line("Id", &mix.id.to_string()[..]);
+    }
+
+    fn cmd_currentsong(&mut self) -> Result<(), Ack> {
+        let status = try!(self.status());
+        match status.track {
+            Some(ref track) => self.track_lines(track, &status),
+            None => (),
+        }
+        Ok(())
+    }
+
+    fn cmd_playlistinfo(&mut self) -> Result<(), Ack> {
+        let status = try!(self.status());
+        for (pos, mix) in status.mixes.iter().enumerate() {
+            self.mix_lines(mix, Some(pos));
+        }
+        Ok(())
+    }
+
+    fn cmd_pause(&mut self, args: &[String]) -> Result<(), Ack> {
+        let status = try!(self.status());
+        let toggle = match args.get(0).map(|a| &a[..]) {
+            None => true,
+            Some("1") => status.playing,
+            Some("0") => !status.playing,
+            Some(other) => {
+                return Err(Ack::new(ACK_ERROR_ARG, format!("Boolean (0/1) expected: {}", other)));
+            }
+        };
+        if toggle { self.send(app::Command::TogglePlaying) } else { Ok(()) }
+    }
+
+    fn cmd_play(&mut self, args: &[String], by_id: bool) -> Result<(), Ack> {
+        let status = try!(self.status());
+        let arg = match args.get(0) {
+            Some(a) => a,
+            // Without an argument play resumes.
+            None if status.track.is_some() && !status.playing => {
+                return self.send(app::Command::TogglePlaying);
+            }
+            None => return Ok(()),
+        };
+        let number: u32 = try!(arg.parse().map_err(|_| {
+            Ack::new(ACK_ERROR_ARG, format!("Integer expected: {}", arg))
+        }));
+        let pos = if by_id {
+            status.mixes.iter().position(|m| m.id == number)
+        } else if (number as usize) < status.mixes.len() {
+            Some(number as usize)
+        } else {
+            None
+        };
+        match pos {
+            Some(pos) => self.send(app::Command::PlayMix(pos)),
+            None => Err(Ack::new(ACK_ERROR_NO_EXIST, "No such song".to_string())),
+        }
+    }
+
+    /// Searches 8tracks for mixes, they become the new playlist once they're loaded. Clients
+    /// learn about it from idle, like about any other playlist change.
+    fn cmd_search(&mut self, args: &[String]) -> Result<(), Ack> {
+        if args.len() < 2 {
+            return Err(Ack::new(ACK_ERROR_ARG, "too few arguments for \"search\"".to_string()));
+        }
+        let what = args[1].replace(" ", "_");
+        let smart_id = match &args[0].to_lowercase()[..] {
+            "genre" => format!("tags:{}", what),
+            "artist" => format!("artist:{}", what),
+            _ => format!("keyword:{}", what),
+        };
+        self.send(app::Command::GetMixes(smart_id))
+    }
+
+    /// Waits until the player or the playlist changed, or the client sends noidle.
+    fn cmd_idle(&mut self) -> Result<(), Ack> {
+        let before = try!(self.status());
+        if !self.subscribed {
+            let (status_sender, status_receiver) = mpsc::channel();
+            try!(self.sender.send(GuiUpdateMessage::Subscribe(status_sender)).map_err(|_| {
+                Ack::new(ACK_ERROR_SYSTEM, "rusttracks is shutting down".to_string())
+            }));
+            // Ends with the client, when its events can't be sent anymore. The GUI then drops
+            // the subscription with the next update.
+            let events = self.event_sender.clone();
+            thread::spawn(move || {
+                for status in status_receiver.iter() {
+                    if events.send(Event::Status(status)).is_err() {
+                        break;
+                    }
+                }
+            });
+            self.subscribed = true;
+        }
+        let track_id = |s: &app::Status| s.track.as_ref().map(|t| t.id);
+        loop {
+            let now = match self.events.recv() {
+                Ok(Event::Status(status)) => status,
+                // Anything but noidle is a protocol error, just stop idling on it as well.
+                Ok(Event::Line(_)) => break,
+                Ok(Event::Closed) | Err(_) => {
+                    self.closed = true;
+                    break;
+                }
+            };
+            if track_id(&now) != track_id(&before) || now.playing != before.playing {
+                self.line("changed", "player");
+            }
+            if playlist_version(&now.mixes[..]) != playlist_version(&before.mixes[..]) {
+                self.line("changed", "playlist");
+            }
+            if !self.response.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_args;
+
+    fn words(words: &[&str]) -> Result<Vec<String>, String> {
+        Ok(words.iter().map(|w| w.to_string()).collect())
+    }
+
+    #[test]
+    fn words_are_split_on_blanks() {
+        assert_eq!(split_args("play 3"), words(&["play", "3"]));
+        assert_eq!(split_args("  pause\t 1  "), words(&["pause", "1"]));
+        assert_eq!(split_args(""), words(&[]));
+    }
+
+    #[test]
+    fn quoted_words_keep_their_blanks() {
+        assert_eq!(split_args("search genre \"indie folk\""),
+                   words(&["search", "genre", "indie folk"]));
+        assert_eq!(split_args("search any \"\""), words(&["search", "any", ""]));
+    }
+
+    #[test]
+    fn backslashes_escape_in_quotes() {
+        assert_eq!(split_args(r#"search any "say \"hi\" \\o/""#),
+                   words(&["search", "any", r#"say "hi" \o/"#]));
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert!(split_args("search any \"indie").is_err());
+        assert!(split_args("search any \"indie\\").is_err());
+    }
+}
@@ -0,0 +1,532 @@
+// MPRIS2 on the session bus, so that desktop media controls and media keys work. Implements
+// org.mpris.MediaPlayer2 and org.mpris.MediaPlayer2.Player with GDBus. All callbacks run on the
+// default main context, i.e. the GUI thread; commands go back through the GUI's channel and the
+// GUI pushes a fresh PlayerState after every view update.
+
+use std::ffi as rffi;
+use std::mem;
+use std::ptr;
+use std::sync::mpsc;
+
+use gtk::ffi::*;
+use gtk::*;
+
+use api;
+use app;
+use dbus::{take_error, c_string, bool_variant, string_variant, strv_variant, dict_variant};
+use gui::GuiUpdateMessage;
+use player::PlaybackBackend;
+use utils;
+
+static BUS_NAME: &'static str = "org.mpris.MediaPlayer2.rusttracks";
+static OBJECT_PATH: &'static str = "/org/mpris/MediaPlayer2";
+static ROOT_INTERFACE: &'static str = "org.mpris.MediaPlayer2";
+static PLAYER_INTERFACE: &'static str = "org.mpris.MediaPlayer2.Player";
+
+static INTROSPECTION_XML: &'static str = "
+<node>
+  <interface name='org.mpris.MediaPlayer2'>
+    <method name='Raise'/>
+    <method name='Quit'/>
+    <property name='CanQuit' type='b' access='read'/>
+    <property name='CanRaise' type='b' access='read'/>
+    <property name='HasTrackList' type='b' access='read'/>
+    <property name='Identity' type='s' access='read'/>
+    <property name='SupportedUriSchemes' type='as' access='read'/>
+    <property name='SupportedMimeTypes' type='as' access='read'/>
+  </interface>
+  <interface name='org.mpris.MediaPlayer2.Player'>
+    <method name='Next'/>
+    <method name='Previous'/>
+    <method name='Pause'/>
+    <method name='PlayPause'/>
+    <method name='Stop'/>
+    <method name='Play'/>
+    <method name='Seek'>
+      <arg direction='in' name='Offset' type='x'/>
+    </method>
+    <method name='SetPosition'>
+      <arg direction='in' name='TrackId' type='o'/>
+      <arg direction='in' name='Position' type='x'/>
+    </method>
+    <method name='OpenUri'>
+      <arg direction='in' name='Uri' type='s'/>
+    </method>
+    <signal name='Seeked'>
+      <arg name='Position' type='x'/>
+    </signal>
+    <property name='PlaybackStatus' type='s' access='read'/>
+    <property name='Rate' type='d' access='read'/>
+    <property name='Metadata' type='a{sv}' access='read'/>
+    <property name='Volume' type='d' access='read'/>
+    <property name='Position' type='x' access='read'/>
+    <property name='MinimumRate' type='d' access='read'/>
+    <property name='MaximumRate' type='d' access='read'/>
+    <property name='CanGoNext' type='b' access='read'/>
+    <property name='CanGoPrevious' type='b' access='read'/>
+    <property name='CanPlay' type='b' access='read'/>
+    <property name='CanPause' type='b' access='read'/>
+    <property name='CanSeek' type='b' access='read'/>
+    <property name='CanControl' type='b' access='read'/>
+  </interface>
+</node>";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+impl PlaybackStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Metadata {
+    pub track_id: u32,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub year: Option<u32>,
+    pub url: String,
+    pub art_url: Option<String>,
+    // In microseconds, unknown until the stream told us.
+    pub length: Option<i64>,
+}
+
+impl Metadata {
+    pub fn new(track: &api::Track, mix: Option<&api::Mix>, length: Option<i64>) -> Metadata {
+        Metadata {
+            track_id: track.id,
+            title: track.name.clone(),
+            artist: track.performer.clone(),
+            album: track.release_name.clone(),
+            year: track.year,
+            url: track.url.clone(),
+            art_url: mix.and_then(|m| m.cover_urls.for_size(500)).map(|u| u.to_string()),
+            length: length,
+        }
+    }
+
+    fn object_path(&self) -> String {
+        format!("/org/rusttracks/track/{}", self.track_id)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PlayerState {
+    pub status: PlaybackStatus,
+    pub metadata: Option<Metadata>,
+    pub can_go_next: bool,
+}
+
+impl PlayerState {
+    pub fn new() -> PlayerState {
+        PlayerState {
+            status: PlaybackStatus::Stopped,
+            metadata: None,
+            can_go_next: false,
+        }
+    }
+
+    pub fn from_core<P: PlaybackBackend>(core: &app::AppCore<P>) -> PlayerState {
+        let track = match core.current_track() {
+            Some(t) => t,
+            None => return PlayerState::new(),
+        };
+        // get_progress_info is in nanoseconds, MPRIS wants microseconds.
+        let progress = core.player().get_progress_info();
+        let length = progress.and_then(|(_, dur)| if dur > 0 { Some(dur / 1000) } else { None });
+        PlayerState {
+            status: if core.is_playing() { PlaybackStatus::Playing } else { PlaybackStatus::Paused },
+            metadata: Some(Metadata::new(track, core.current_mix(), length)),
+            can_go_next: core.skip_allowed(),
+        }
+    }
+}
+
+/// Where the current track is, in microseconds, 0 without one.
+pub fn position<P: PlaybackBackend>(player: &P) -> i64 {
+    player.get_progress_info().map(|(pos, _)| pos / 1000).unwrap_or(0)
+}
+
+struct MprisInner {
+    sender: mpsc::Sender<GuiUpdateMessage>,
+    state: PlayerState,
+    // Asked whenever a client reads Position, the state would be stale.
+    position: Box<Fn() -> i64>,
+
+    node_info: *mut GDBusNodeInfo,
+    vtable: GDBusInterfaceVTable,
+    // Only set while we own the bus name.
+    connection: *mut GDBusConnection,
+    registration_ids: Vec<guint>,
+}
+
+pub struct Mpris {
+    // Boxed because GDBus holds a pointer to it.
+    inner: Box<MprisInner>,
+    owner_id: guint,
+}
+
+impl Mpris {
+    /// Starts acquiring the bus name, returns None if the interface description is broken.
+    /// `position` is called on the GUI thread whenever a client reads the Position property.
+    pub fn new(sender: mpsc::Sender<GuiUpdateMessage>, position: Box<Fn() -> i64>)
+               -> Option<Mpris> {
+        let node_info = unsafe {
+            let xml = rffi::CString::new(INTROSPECTION_XML).unwrap();
+            let mut err = ptr::null_mut();
+            let node_info = g_dbus_node_info_new_for_xml(xml.as_ptr(), &mut err);
+            if node_info.is_null() {
+                warn!("could not parse the MPRIS interface description: {}", take_error(err));
+                return None;
+            }
+            node_info
+        };
+
+        let mut inner = Box::new(MprisInner {
+            sender: sender,
+            state: PlayerState::new(),
+            position: position,
+            node_info: node_info,
+            vtable: Struct__GDBusInterfaceVTable {
+                method_call: Some(mpris_method_call),
+                get_property: Some(mpris_get_property),
+                set_property: None,
+                padding: [ptr::null_mut(); 8],
+            },
+            connection: ptr::null_mut(),
+            registration_ids: Vec::new(),
+        });
+
+        let owner_id = unsafe {
+            let name = rffi::CString::new(BUS_NAME).unwrap();
+            g_bus_own_name(G_BUS_TYPE_SESSION,
+                           name.as_ptr(),
+                           G_BUS_NAME_OWNER_FLAGS_NONE,
+                           Some(mpris_bus_acquired),
+                           None,
+                           Some(mpris_name_lost),
+                           mem::transmute::<&mut MprisInner, gpointer>(&mut *inner),
+                           None)
+        };
+
+        Some(Mpris {
+            inner: inner,
+            owner_id: owner_id,
+        })
+    }
+
+    /// Takes the new state and tells the bus about the properties that changed.
+    pub fn update(&mut self, state: PlayerState) {
+        let inner = &mut *self.inner;
+        let mut changed = Vec::new();
+        {
+            let old = &inner.state;
+            if old.status != state.status {
+                changed.push("PlaybackStatus");
+                changed.push("CanPlay");
+                changed.push("CanPause");
+            }
+            if old.metadata != state.metadata {
+                changed.push("Metadata");
+            }
+            if old.can_go_next != state.can_go_next {
+                changed.push("CanGoNext");
+            }
+        }
+        // Position changes all the time, clients are expected to poll it.
+        inner.state = state;
+        if !changed.is_empty() {
+            inner.emit_properties_changed(&changed[..]);
+        }
+    }
+}
+
+impl Drop for Mpris {
+    fn drop(&mut self) {
+        unsafe {
+            let inner = &mut *self.inner;
+            if !inner.connection.is_null() {
+                for id in inner.registration_ids.iter() {
+                    g_dbus_connection_unregister_object(inner.connection, *id);
+                }
+            }
+            g_bus_unown_name(self.owner_id);
+            g_dbus_node_info_unref(inner.node_info);
+        }
+    }
+}
+
+impl MprisInner {
+    fn register(&mut self, connection: *mut GDBusConnection) {
+        self.connection = connection;
+        let path = rffi::CString::new(OBJECT_PATH).unwrap();
+        for interface in [ROOT_INTERFACE, PLAYER_INTERFACE].iter() {
+            let name = rffi::CString::new(*interface).unwrap();
+            unsafe {
+                let info = g_dbus_node_info_lookup_interface(self.node_info, name.as_ptr());
+                let mut err = ptr::null_mut();
+                let id = g_dbus_connection_register_object(
+                    connection, path.as_ptr(), info, &self.vtable,
+                    mem::transmute::<&mut MprisInner, gpointer>(self), None, &mut err);
+                if id == 0 {
+                    warn!("could not register MPRIS interface {}: {}",
+                          interface, take_error(err));
+                } else {
+                    self.registration_ids.push(id);
+                }
+            }
+        }
+    }
+
+    fn send(&self, command: app::Command) {
+        self.sender.send(GuiUpdateMessage::Command(command));
+    }
+
+    fn method_call(&self, interface: &str, method: &str) {
+        debug!("MPRIS call {}.{}", interface, method);
+        if interface != PLAYER_INTERFACE {
+            // Raise and Quit, we claim to be able to do neither.
+            return;
+        }
+        let playing = self.state.status == PlaybackStatus::Playing;
+        match method {
+            "PlayPause" => self.send(app::Command::TogglePlaying),
+            "Play" if !playing => self.send(app::Command::TogglePlaying),
+            "Pause" if playing => self.send(app::Command::TogglePlaying),
+            "Next" if self.state.can_go_next => self.send(app::Command::SkipTrack),
+            "Stop" => self.send(app::Command::Stop),
+            // Previous, Seek, SetPosition and OpenUri aren't supported and have no effect.
+            _ => (),
+        }
+    }
+
+    unsafe fn get_property(&self, interface: &str, property: &str) -> Option<*mut GVariant> {
+        let state = &self.state;
+        let has_track = state.metadata.is_some();
+        let value = match (interface == ROOT_INTERFACE, property) {
+            (true, "CanQuit") => bool_variant(false),
+            (true, "CanRaise") => bool_variant(false),
+            (true, "HasTrackList") => bool_variant(false),
+            (true, "Identity") => string_variant("RustTracks"),
+            (true, "SupportedUriSchemes") => strv_variant(&[]),
+            (true, "SupportedMimeTypes") => strv_variant(&[]),
+            (false, "PlaybackStatus") => string_variant(state.status.as_str()),
+            (false, "Rate") => g_variant_new_double(1.0),
+            (false, "Metadata") => metadata_variant(state.metadata.as_ref()),
+            (false, "Volume") => g_variant_new_double(1.0),
+            (false, "Position") => g_variant_new_int64((self.position)()),
+            (false, "MinimumRate") => g_variant_new_double(1.0),
+            (false, "MaximumRate") => g_variant_new_double(1.0),
+            (false, "CanGoNext") => bool_variant(state.can_go_next),
+            (false, "CanGoPrevious") => bool_variant(false),
+            (false, "CanPlay") => bool_variant(has_track),
+            (false, "CanPause") => bool_variant(has_track),
+            (false, "CanSeek") => bool_variant(false),
+            (false, "CanControl") => bool_variant(true),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn emit_properties_changed(&self, properties: &[&str]) {
+        if self.connection.is_null() {
+            return;
+        }
+        unsafe {
+            let entries = properties.iter().filter_map(|p| {
+                self.get_property(PLAYER_INTERFACE, p).map(|v| (*p, v))
+            }).collect();
+            let mut args = [
+                string_variant(PLAYER_INTERFACE),
+                dict_variant(entries),
+                strv_variant(&[]),
+            ];
+            let params = g_variant_new_tuple(args.as_mut_ptr(), args.len() as gsize);
+            let path = rffi::CString::new(OBJECT_PATH).unwrap();
+            let interface = rffi::CString::new("org.freedesktop.DBus.Properties").unwrap();
+            let signal = rffi::CString::new("PropertiesChanged").unwrap();
+            let mut err = ptr::null_mut();
+            if g_dbus_connection_emit_signal(self.connection, ptr::null(), path.as_ptr(),
+                                             interface.as_ptr(), signal.as_ptr(),
+                                             params, &mut err) == 0 {
+                warn!("could not emit MPRIS PropertiesChanged: {}", take_error(err));
+            }
+        }
+    }
+}
+
+unsafe fn metadata_variant(metadata: Option<&Metadata>) -> *mut GVariant {
+    let m = match metadata {
+        Some(m) => m,
+        // No track, MPRIS wants the NoTrack path then.
+        None => {
+            let no_track = c_string("/org/mpris/MediaPlayer2/TrackList/NoTrack");
+            return dict_variant(vec![
+                ("mpris:trackid", g_variant_new_object_path(no_track.as_ptr())),
+            ]);
+        }
+    };
+    let track_path = c_string(&m.object_path()[..]);
+    let mut entries = vec![
+        ("mpris:trackid", g_variant_new_object_path(track_path.as_ptr())),
+        ("xesam:title", string_variant(&m.title[..])),
+        ("xesam:artist", strv_variant(&[&m.artist[..]])),
+        ("xesam:url", string_variant(&m.url[..])),
+    ];
+    match m.album {
+        Some(ref album) => entries.push(("xesam:album", string_variant(&album[..]))),
+        None => (),
+    }
+    match m.year {
+        Some(year) => {
+            let date = format!("{:04}-01-01T00:00:00Z", year);
+            entries.push(("xesam:contentCreated", string_variant(&date[..])));
+        }
+        None => (),
+    }
+    match m.art_url {
+        Some(ref url) => entries.push(("mpris:artUrl", string_variant(&url[..]))),
+        None => (),
+    }
+    match m.length {
+        Some(length) => entries.push(("mpris:length", g_variant_new_int64(length))),
+        None => (),
+    }
+    dict_variant(entries)
+}
+
+extern "C" fn mpris_bus_acquired(connection: *mut GDBusConnection, _name: *const gchar,
+                                 user_data: gpointer) {
+    let inner = unsafe { &mut *(user_data as *mut MprisInner) };
+    inner.register(connection);
+}
+
+extern "C" fn mpris_name_lost(connection: *mut GDBusConnection, _name: *const gchar,
+                              user_data: gpointer) {
+    let inner = unsafe { &mut *(user_data as *mut MprisInner) };
+    if connection.is_null() {
+        warn!("no session bus, media controls won't work");
+    } else {
+        warn!("could not get the MPRIS bus name {}, is another instance running?", BUS_NAME);
+    }
+    inner.connection = ptr::null_mut();
+    inner.registration_ids.clear();
+}
+
+extern "C" fn mpris_method_call(_connection: *mut GDBusConnection, _sender: *const gchar,
+                                _object_path: *const gchar, interface_name: *const gchar,
+                                method_name: *const gchar, _parameters: *mut GVariant,
+                                invocation: *mut GDBusMethodInvocation, user_data: gpointer) {
+    let inner = unsafe { &*(user_data as *const MprisInner) };
+    let interface = unsafe { utils::ptr_to_string(interface_name as *const i8) };
+    let method = unsafe { utils::ptr_to_string(method_name as *const i8) };
+    inner.method_call(&interface[..], &method[..]);
+    unsafe {
+        g_dbus_method_invocation_return_value(invocation, ptr::null_mut());
+    }
+}
+
+extern "C" fn mpris_get_property(_connection: *mut GDBusConnection, _sender: *const gchar,
+                                 _object_path: *const gchar, interface_name: *const gchar,
+                                 property_name: *const gchar, error: *mut *mut GError,
+                                 user_data: gpointer) -> *mut GVariant {
+    let inner = unsafe { &*(user_data as *const MprisInner) };
+    let interface = unsafe { utils::ptr_to_string(interface_name as *const i8) };
+    let property = unsafe { utils::ptr_to_string(property_name as *const i8) };
+    match unsafe { inner.get_property(&interface[..], &property[..]) } {
+        Some(value) => value,
+        None => {
+            let message = c_string(&format!("Unknown property {}", property)[..]);
+            unsafe {
+                g_set_error_literal(error, g_dbus_error_quark(),
+                                    G_DBUS_ERROR_UNKNOWN_PROPERTY as gint, message.as_ptr());
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use app::{AppCore, Command, Input};
+    use fake_player::FakePlayer;
+    use fixtures::Harness;
+
+    use super::*;
+
+    // Harness playing track 10 of mix 1, with skipping allowed or not.
+    fn playing(skip_allowed: bool) -> Harness {
+        let mut h = Harness::new();
+        h.start_mix_with_skip(0, 10, skip_allowed);
+        h
+    }
+
+    #[test]
+    fn nothing_loaded_is_stopped() {
+        let (sender, _receiver) = mpsc::channel();
+        let core = AppCore::new(FakePlayer::new(sender), 1);
+        assert_eq!(PlayerState::from_core(&core), PlayerState::new());
+        assert_eq!(PlayerState::new().status, PlaybackStatus::Stopped);
+        assert_eq!(position(core.player()), 0);
+    }
+
+    #[test]
+    fn state_follows_playing_pausing_and_stopping() {
+        let mut h = playing(true);
+        assert_eq!(PlayerState::from_core(&h.core).status, PlaybackStatus::Playing);
+
+        h.input(Input::Command(Command::TogglePlaying));
+        h.pump();
+        assert_eq!(PlayerState::from_core(&h.core).status, PlaybackStatus::Paused);
+
+        h.input(Input::Command(Command::Stop));
+        h.pump();
+        let state = PlayerState::from_core(&h.core);
+        assert_eq!(state.status, PlaybackStatus::Stopped);
+        assert_eq!(state.metadata, None);
+    }
+
+    #[test]
+    fn can_go_next_follows_skip_allowed() {
+        assert!(PlayerState::from_core(&playing(true).core).can_go_next);
+        assert!(!PlayerState::from_core(&playing(false).core).can_go_next);
+    }
+
+    #[test]
+    fn metadata_has_the_track_and_the_mix_cover() {
+        let h = playing(true);
+        let metadata = PlayerState::from_core(&h.core).metadata.unwrap();
+        assert_eq!(metadata, Metadata {
+            track_id: 10,
+            title: "Track 10".to_string(),
+            artist: "Somebody".to_string(),
+            album: Some("Some Album".to_string()),
+            year: Some(1999),
+            url: "http://example.com/tracks/10".to_string(),
+            art_url: Some("http://example.com/1/sq500.jpg".to_string()),
+            // The fake player's tracks are three minutes long.
+            length: Some(3 * 60 * 1000 * 1000),
+        });
+        assert_eq!(metadata.object_path(), "/org/rusttracks/track/10");
+    }
+
+    #[test]
+    fn position_is_read_from_the_player() {
+        let mut h = playing(true);
+        assert_eq!(position(h.core.player()), 0);
+        h.advance(1500);
+        assert_eq!(position(h.core.player()), 1500 * 1000);
+    }
+}
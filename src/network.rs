@@ -0,0 +1,113 @@
+// Runs the network requests of the AppCore, shared by all front ends.
+
+use std::thread;
+
+use hyper;
+use rustc_serialize::json;
+
+use api;
+use app;
+use scrobbler;
+use webinterface;
+
+/// Does the work for `request` on a new thread and hands the result to `done` there.
+pub fn run<F>(request: app::Request, done: F)
+        where F: FnOnce(app::NetworkResult) + Send + 'static {
+    thread::spawn(move || {
+        match fetch(request) {
+            Some(result) => done(result),
+            None => (),
+        }
+    });
+}
+
+fn fetch(request: app::Request) -> Option<app::NetworkResult> {
+    let result = match request {
+        app::Request::PlayToken => play_token(),
+        app::Request::MixSet(smart_id, safe_browse) => mix_set(&smart_id[..], safe_browse),
+        app::Request::Play(pt, mix) => {
+            play_state("Could not start playing mix", webinterface::get_play_state(&pt, &mix))
+        }
+        app::Request::Next(pt, mix) => {
+            play_state("Could not get next track", webinterface::get_next_track(&pt, &mix))
+        }
+        app::Request::Skip(pt, mix) => {
+            play_state("Could not skip track", webinterface::get_skip_track(&pt, &mix))
+        }
+        app::Request::Report(pt, track_id, mix_id) => {
+            webinterface::report_track(&pt, track_id, mix_id);
+            return None;
+        }
+        app::Request::Like(track_id) => like(track_id),
+        app::Request::NowPlaying(endpoint, track) => {
+            scrobbler::now_playing(&endpoint, &track);
+            return None;
+        }
+        app::Request::Scrobble(endpoint, listen) => {
+            scrobbler::scrobble(&endpoint, &listen);
+            return None;
+        }
+    };
+    Some(result)
+}
+
+fn play_token() -> app::NetworkResult {
+    let pt_json = match webinterface::get_play_token() {
+        Ok(ptj) => ptj,
+        Err(io_err) => {
+            return app::NetworkResult::Error(
+                format!("Playtoken could not be obtained: `{}`", io_err));
+        }
+    };
+    let pt = api::parse_play_token_response(&pt_json);
+    match pt.contents {
+        Some(pt) => app::NetworkResult::PlayToken(pt),
+        None => app::NetworkResult::Error("Playtoken could not be obtained".to_string()),
+    }
+}
+
+fn mix_set(smart_id: &str, safe_browse: bool) -> app::NetworkResult {
+    debug!("getting mixes for smart id '{}'", smart_id);
+    let mix_set_json = match webinterface::get_mix_set(smart_id, safe_browse) {
+        Ok(msj) => msj,
+        Err(io_err) => {
+            return app::NetworkResult::Error(format!("Could not get mix list: `{}`", io_err));
+        }
+    };
+    let mix_set = api::parse_mix_set_response(&mix_set_json);
+    match mix_set.contents {
+        Some(ms) => app::NetworkResult::Mixes(ms.mixes),
+        None => app::NetworkResult::Error("Mix list could not be obtained".to_string()),
+    }
+}
+
+fn play_state(error_msg: &str, response: hyper::HttpResult<json::Json>) -> app::NetworkResult {
+    let play_state_json = match response {
+        Ok(psj) => psj,
+        Err(io_err) => {
+            return app::NetworkResult::Error(format!("{}: `{}`", error_msg, io_err));
+        }
+    };
+    let play_state = api::parse_play_state_response(&play_state_json);
+    match play_state.contents {
+        Some(ps) => app::NetworkResult::PlayState(ps),
+        None => app::NetworkResult::Error(error_msg.to_string()),
+    }
+}
+
+fn like(track_id: u32) -> app::NetworkResult {
+    let json = match webinterface::fav_track(track_id) {
+        Ok(j) => j,
+        Err(io_err) => {
+            return app::NetworkResult::Error(format!("Could not like track: `{}`", io_err));
+        }
+    };
+    let response = api::parse_status_response(&json);
+    if response.status.starts_with("200") {
+        app::NetworkResult::Liked(track_id)
+    } else {
+        // Favorites need a logged in user.
+        let reason = response.errors.unwrap_or(response.status);
+        app::NetworkResult::Error(format!("Could not like track: {}", reason))
+    }
+}
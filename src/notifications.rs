@@ -0,0 +1,200 @@
+// Desktop notifications through org.freedesktop.Notifications. Every new notification replaces
+// the previous one, and its Skip and Like actions come back as commands on the GUI's channel.
+// Liking without an 8tracks login fails, the core shows the server's error then.
+
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::mpsc;
+
+use gtk::ffi::*;
+use gtk::*;
+
+use app;
+use dbus::{take_error, c_string, bool_variant, string_variant, strv_variant, dict_variant};
+use gui::GuiUpdateMessage;
+use utils;
+
+static NOTIFICATIONS_NAME: &'static str = "org.freedesktop.Notifications";
+static NOTIFICATIONS_PATH: &'static str = "/org/freedesktop/Notifications";
+
+static SKIP_ACTION: &'static str = "skip";
+static LIKE_ACTION: &'static str = "like";
+
+struct NotifierInner {
+    sender: mpsc::Sender<GuiUpdateMessage>,
+    connection: *mut GDBusConnection,
+    // Id of the notification we showed last, 0 if there is none.
+    last_id: u32,
+}
+
+pub struct Notifier {
+    // Boxed because GDBus holds a pointer to it.
+    inner: Box<NotifierInner>,
+    subscription_id: guint,
+    // Cancelled when we go away, so that no reply callback gets at `inner` after that.
+    cancellable: *mut GCancellable,
+}
+
+impl Notifier {
+    /// Returns None if there is no session bus.
+    pub fn new(sender: mpsc::Sender<GuiUpdateMessage>) -> Option<Notifier> {
+        let connection = unsafe {
+            let mut err = ptr::null_mut();
+            let connection = g_bus_get_sync(G_BUS_TYPE_SESSION, ptr::null_mut(), &mut err);
+            if connection.is_null() {
+                warn!("no session bus, not showing notifications: {}", take_error(err));
+                return None;
+            }
+            connection
+        };
+
+        let mut inner = Box::new(NotifierInner {
+            sender: sender,
+            connection: connection,
+            last_id: 0,
+        });
+
+        let subscription_id = unsafe {
+            let name = c_string(NOTIFICATIONS_NAME);
+            let path = c_string(NOTIFICATIONS_PATH);
+            let signal = c_string("ActionInvoked");
+            g_dbus_connection_signal_subscribe(connection, name.as_ptr(), name.as_ptr(),
+                                               signal.as_ptr(), path.as_ptr(), ptr::null(),
+                                               G_DBUS_SIGNAL_FLAGS_NONE,
+                                               Some(notification_action_invoked),
+                                               mem::transmute::<&mut NotifierInner, gpointer>(
+                                                   &mut *inner),
+                                               None)
+        };
+
+        Some(Notifier {
+            inner: inner,
+            subscription_id: subscription_id,
+            cancellable: unsafe { g_cancellable_new() },
+        })
+    }
+
+    /// Shows a notification in place of the last one. `image` is copied, the caller keeps its
+    /// reference.
+    pub fn show(&mut self, summary: &str, body: &str, image: Option<*mut GdkPixbuf>) {
+        let inner = &mut *self.inner;
+        unsafe {
+            let mut hints = vec![("category", string_variant("x-gnome.music"))];
+            match image {
+                Some(pixbuf) => hints.push(("image-data", image_data_variant(pixbuf))),
+                None => (),
+            }
+            let mut args = [
+                string_variant("RustTracks"),
+                g_variant_new_uint32(inner.last_id),
+                string_variant("audio-x-generic"),
+                string_variant(summary),
+                string_variant(body),
+                strv_variant(&[SKIP_ACTION, "Skip", LIKE_ACTION, "Like"]),
+                dict_variant(hints),
+                g_variant_new_int32(-1),
+            ];
+            let params = g_variant_new_tuple(args.as_mut_ptr(), args.len() as gsize);
+            let name = c_string(NOTIFICATIONS_NAME);
+            let path = c_string(NOTIFICATIONS_PATH);
+            let method = c_string("Notify");
+            let reply_type = c_string("(u)");
+            g_dbus_connection_call(inner.connection, name.as_ptr(), path.as_ptr(),
+                                   name.as_ptr(), method.as_ptr(), params,
+                                   reply_type.as_ptr() as *const GVariantType,
+                                   G_DBUS_CALL_FLAGS_NONE, -1, self.cancellable,
+                                   Some(notification_shown),
+                                   mem::transmute::<&mut NotifierInner, gpointer>(inner));
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe {
+            g_cancellable_cancel(self.cancellable);
+            g_object_unref(self.cancellable as gpointer);
+            g_dbus_connection_signal_unsubscribe(self.inner.connection, self.subscription_id);
+            g_object_unref(self.inner.connection as gpointer);
+        }
+    }
+}
+
+// The raw pixels in the (iiibiiay) format of the image-data hint.
+unsafe fn image_data_variant(pixbuf: *mut GdkPixbuf) -> *mut GVariant {
+    let width = gdk_pixbuf_get_width(&*pixbuf);
+    let height = gdk_pixbuf_get_height(&*pixbuf);
+    let rowstride = gdk_pixbuf_get_rowstride(&*pixbuf);
+    let has_alpha = gdk_pixbuf_get_has_alpha(&*pixbuf) != 0;
+    let bits_per_sample = gdk_pixbuf_get_bits_per_sample(&*pixbuf);
+    let channels = gdk_pixbuf_get_n_channels(&*pixbuf);
+    let mut length = 0;
+    let pixels = gdk_pixbuf_get_pixels_with_length(&*pixbuf, &mut length);
+    let data = slice::from_raw_parts(pixels as *const u8, length as usize);
+    let mut fields = [
+        g_variant_new_int32(width),
+        g_variant_new_int32(height),
+        g_variant_new_int32(rowstride),
+        bool_variant(has_alpha),
+        g_variant_new_int32(bits_per_sample),
+        g_variant_new_int32(channels),
+        g_variant_new_fixed_array(b"y\0".as_ptr() as *const GVariantType,
+                                  data.as_ptr() as gconstpointer, data.len() as gsize, 1),
+    ];
+    g_variant_new_tuple(fields.as_mut_ptr(), fields.len() as gsize)
+}
+
+extern "C" fn notification_shown(source: *mut GObject, result: *mut GAsyncResult,
+                                 user_data: gpointer) {
+    unsafe {
+        let mut err = ptr::null_mut();
+        let reply = g_dbus_connection_call_finish(source as *mut GDBusConnection, result,
+                                                  &mut err);
+        if reply.is_null() && g_error_matches(err, g_io_error_quark(), G_IO_ERROR_CANCELLED) != 0 {
+            // The Notifier is gone, and `user_data` with it.
+            g_error_free(err);
+            return;
+        }
+        let inner = &mut *(user_data as *mut NotifierInner);
+        if reply.is_null() {
+            warn!("could not show notification: {}", take_error(err));
+            inner.last_id = 0;
+            return;
+        }
+        let id = g_variant_get_child_value(reply, 0);
+        inner.last_id = g_variant_get_uint32(id);
+        g_variant_unref(id);
+        g_variant_unref(reply);
+    }
+}
+
+extern "C" fn notification_action_invoked(_connection: *mut GDBusConnection,
+                                          _sender_name: *const gchar,
+                                          _object_path: *const gchar,
+                                          _interface_name: *const gchar,
+                                          _signal_name: *const gchar,
+                                          parameters: *mut GVariant,
+                                          user_data: gpointer) {
+    let inner = unsafe { &*(user_data as *const NotifierInner) };
+    let (id, action) = unsafe {
+        let id = g_variant_get_child_value(parameters, 0);
+        let action = g_variant_get_child_value(parameters, 1);
+        let result = (g_variant_get_uint32(id),
+                      utils::ptr_to_string(g_variant_get_string(action, ptr::null_mut()) as *const i8));
+        g_variant_unref(id);
+        g_variant_unref(action);
+        result
+    };
+    if id == 0 || id != inner.last_id {
+        // Someone else's notification.
+        return;
+    }
+    debug!("notification action `{}` invoked", action);
+    let command = match &action[..] {
+        a if a == SKIP_ACTION => app::Command::SkipTrack,
+        a if a == LIKE_ACTION => app::Command::LikeTrack,
+        _ => return,
+    };
+    inner.sender.send(GuiUpdateMessage::Command(command));
+}
@@ -0,0 +1,27 @@
+// Where our files live. Also built into rusttracks-ctl, so that both agree on the control
+// socket; keep it to std.
+
+use std::env;
+use std::path::PathBuf;
+
+/// Returns our directory below the XDG base directory given by `var` (e.g. `XDG_CONFIG_HOME`),
+/// falling back to `fallback` relative to the home directory if it isn't set.
+pub fn xdg_app_dir(var: &str, fallback: &str) -> PathBuf {
+    let mut dir = match env::var(var) {
+        Ok(ref d) if !d.is_empty() => PathBuf::from(d),
+        _ => {
+            let mut home = env::home_dir().unwrap_or_else(|| PathBuf::from("."));
+            home.push(fallback);
+            home
+        }
+    };
+    dir.push("rusttracks");
+    dir
+}
+
+/// The control socket of a running rusttracks.
+pub fn control_socket() -> PathBuf {
+    let mut path = xdg_app_dir("XDG_RUNTIME_DIR", ".cache");
+    path.push("control.sock");
+    path
+}
@@ -1,54 +1,319 @@
-use std::ffi as rffi;
-use std::mem;
-use std::ptr;
+use std::cell::Cell;
+use std::rc::Rc;
 use std::sync::mpsc;
 
-use log;
-
-use gtk::*;
 use gtk::ffi::*;
 
 use timerfd;
 
-use gui;
-use utils;
+use gst;
 
 static PLAYBIN_ELEMENT_NAME: &'static str = "rusttracks-playbin";
 
+/// Everything the player has to tell its front end. The events are sent over the channel given
+/// to `Player::init` from the GLib main loop; the front end decides what to make of them, but
+/// some have to be handed back to the player (see the individual variants).
+#[derive(Debug)]
+pub enum PlayerEvent {
+    /// Playback of the stream with the given `stream_generation` failed, the stream is dead.
+    Error(u32, String),
+    EndOfStream,
+    /// The pipeline prerolled, call `Player::async_done`.
+    AsyncDone,
+    /// The pipeline started playing, call `Player::start_timers`.
+    Playing,
+    /// The pipeline was paused, call `Player::pause_timers`.
+    Paused,
+    /// Buffer fill level in percent, call `Player::set_buffering`.
+    Buffering(i32),
+    /// New information about the stream, to be merged into what we already know.
+    StreamInfo(StreamInfo),
+    /// The track has been playing for 30 seconds and should be reported.
+    ReportDue,
+    /// Sent every second while playing.
+    Progress,
+    /// Sent every second while the stream should be playing, call `Player::check_stall`.
+    WatchdogTick,
+}
+
 struct ReportCallback {
-    sender: mpsc::Sender<gui::GuiUpdateMessage>,
+    sender: mpsc::Sender<PlayerEvent>,
 }
 
 impl ReportCallback {
-    fn new(sender: mpsc::Sender<gui::GuiUpdateMessage>) -> ReportCallback {
+    fn new(sender: mpsc::Sender<PlayerEvent>) -> ReportCallback {
         ReportCallback { sender: sender }
     }
 }
 
 impl timerfd::TimerGSourceCallback for ReportCallback {
     fn callback(&mut self, _timer: &mut timerfd::Timer) -> bool {
-        self.sender.send(gui::GuiUpdateMessage::ReportCurrentTrack);
+        self.sender.send(PlayerEvent::ReportDue);
         false
     }
 }
 
 struct ProgressCallback {
-    sender: mpsc::Sender<gui::GuiUpdateMessage>,
+    sender: mpsc::Sender<PlayerEvent>,
 }
 
 impl ProgressCallback {
-    fn new(sender: mpsc::Sender<gui::GuiUpdateMessage>) -> ProgressCallback {
+    fn new(sender: mpsc::Sender<PlayerEvent>) -> ProgressCallback {
         ProgressCallback { sender: sender }
     }
 }
 
 impl timerfd::TimerGSourceCallback for ProgressCallback {
     fn callback(&mut self, _timer: &mut timerfd::Timer) -> bool {
-        self.sender.send(gui::GuiUpdateMessage::UpdateProgress);
+        self.sender.send(PlayerEvent::Progress);
+        true
+    }
+}
+
+struct WatchdogCallback {
+    sender: mpsc::Sender<PlayerEvent>,
+}
+
+impl WatchdogCallback {
+    fn new(sender: mpsc::Sender<PlayerEvent>) -> WatchdogCallback {
+        WatchdogCallback { sender: sender }
+    }
+}
+
+impl timerfd::TimerGSourceCallback for WatchdogCallback {
+    fn callback(&mut self, _timer: &mut timerfd::Timer) -> bool {
+        self.sender.send(PlayerEvent::WatchdogTick);
         true
     }
 }
 
+/// What the watchdog did about the current stream, see `Player::check_stall`.
+#[derive(PartialEq, Eq, Debug)]
+pub enum StallAction {
+    Nothing,
+    Restarted,
+    GiveUp,
+}
+
+/// Technical information and embedded tags of the current stream, as far as GStreamer told us
+/// about them. Tag messages usually only carry a few of these at a time, see `merge`.
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    pub codec: Option<String>,
+    // In bits per second.
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<u32>,
+}
+
+impl StreamInfo {
+    pub fn new() -> StreamInfo {
+        StreamInfo {
+            codec: None,
+            bitrate: None,
+            sample_rate: None,
+            channels: None,
+            title: None,
+            artist: None,
+            album: None,
+            year: None,
+        }
+    }
+
+    /// Takes over all fields that are set in `other`.
+    pub fn merge(&mut self, other: StreamInfo) {
+        fn take<T>(mine: &mut Option<T>, theirs: Option<T>) {
+            if theirs.is_some() {
+                *mine = theirs;
+            }
+        }
+        take(&mut self.codec, other.codec);
+        take(&mut self.bitrate, other.bitrate);
+        take(&mut self.sample_rate, other.sample_rate);
+        take(&mut self.channels, other.channels);
+        take(&mut self.title, other.title);
+        take(&mut self.artist, other.artist);
+        take(&mut self.album, other.album);
+        take(&mut self.year, other.year);
+    }
+}
+
+/// Buffering statistics of the current stream. A stall is a buffering period after playback
+/// has started, the initial buffering is not counted.
+#[derive(Clone, Debug)]
+pub struct StreamHealth {
+    pub stalls: u32,
+    // In microseconds, not including the current stall.
+    pub stalled_time: i64,
+    playback_started: bool,
+    stall_started_at: Option<i64>,
+}
+
+impl StreamHealth {
+    pub fn new() -> StreamHealth {
+        StreamHealth {
+            stalls: 0,
+            stalled_time: 0,
+            playback_started: false,
+            stall_started_at: None,
+        }
+    }
+
+    fn buffering_started(&mut self, now: i64) {
+        if self.playback_started && self.stall_started_at.is_none() {
+            self.stalls += 1;
+            self.stall_started_at = Some(now);
+        }
+    }
+
+    fn buffering_finished(&mut self, now: i64) {
+        self.playback_started = true;
+        match self.stall_started_at.take() {
+            Some(started_at) => self.stalled_time += now - started_at,
+            None => ()
+        }
+    }
+
+    /// Total stalled time in microseconds, including a stall that is still going on.
+    pub fn total_stalled_time(&self, now: i64) -> i64 {
+        match self.stall_started_at {
+            Some(started_at) => self.stalled_time + now - started_at,
+            None => self.stalled_time,
+        }
+    }
+}
+
+/// Decides what to do about a stream that makes no progress. It only looks at what it's told, so
+/// that `fake_player::FakePlayer` runs the same logic as the real player. Times are monotonic
+/// microseconds, positions nanoseconds.
+pub struct StallWatchdog {
+    // The last position the stream told us about and the buffer level at the last check.
+    last_position: Option<i64>,
+    last_buffering_percent: Option<i32>,
+    // When the position or the buffer level last changed.
+    last_activity: i64,
+    // Since when the position moved on at every check, None while it doesn't.
+    progressing_since: Option<i64>,
+    restarts: u32,
+    timeout: i64,
+    max_restarts: u32,
+}
+
+impl StallWatchdog {
+    pub fn new() -> StallWatchdog {
+        StallWatchdog {
+            last_position: None,
+            last_buffering_percent: None,
+            last_activity: 0,
+            progressing_since: None,
+            restarts: 0,
+            timeout: 15 * 1000000,
+            max_restarts: 2,
+        }
+    }
+
+    /// A stream that makes no progress for `timeout_secs` seconds is restarted at most
+    /// `max_restarts` times before the watchdog gives up on it.
+    pub fn set_policy(&mut self, timeout_secs: u32, max_restarts: u32) {
+        self.timeout = timeout_secs as i64 * 1000000;
+        self.max_restarts = max_restarts;
+    }
+
+    /// Forgets everything about the previous stream.
+    pub fn new_stream(&mut self) {
+        self.last_position = None;
+        self.last_buffering_percent = None;
+        self.progressing_since = None;
+        self.restarts = 0;
+    }
+
+    /// The stream isn't supposed to be playing, e.g. it's paused, so that's no stall.
+    pub fn idle(&mut self, now: i64) {
+        self.last_activity = now;
+        self.progressing_since = None;
+    }
+
+    /// Where a restarted stream should continue.
+    pub fn resume_position(&self) -> Option<i64> {
+        self.last_position
+    }
+
+    /// Called periodically while the stream should be playing. On `Restarted` the caller has to
+    /// restart the stream at `resume_position`.
+    pub fn check(&mut self, now: i64, position: Option<i64>, buffering_percent: Option<i32>)
+                 -> StallAction {
+        // A pipeline that doesn't know its position (yet) hasn't made any progress.
+        let moved = position.is_some() && position != self.last_position;
+        if moved {
+            self.last_position = position;
+            match self.progressing_since {
+                // Earlier stalls are only forgiven once the stream played on for a while, one
+                // that keeps stalling and recovering briefly is still given up on.
+                Some(since) if now - since >= self.timeout => self.restarts = 0,
+                Some(_) => (),
+                None => self.progressing_since = Some(now),
+            }
+        } else {
+            self.progressing_since = None;
+        }
+        if moved || buffering_percent != self.last_buffering_percent {
+            self.last_buffering_percent = buffering_percent;
+            self.last_activity = now;
+            return StallAction::Nothing;
+        }
+
+        if now - self.last_activity < self.timeout {
+            return StallAction::Nothing;
+        }
+
+        if self.restarts >= self.max_restarts {
+            warn!("stream stalled, giving up after {} restarts", self.restarts);
+            return StallAction::GiveUp;
+        }
+        self.restarts += 1;
+        self.last_activity = now;
+        self.last_buffering_percent = None;
+        warn!("stream stalled, restarting at {:?} (restart {}/{})",
+              self.last_position, self.restarts, self.max_restarts);
+        StallAction::Restarted
+    }
+}
+
+/// The playback operations the rest of rustTracks relies on. Implemented by the real
+/// playbin-backed `Player`, and by `fake_player::FakePlayer` in tests. Implementations report
+/// back through the `PlayerEvent` channel they were created with.
+pub trait PlaybackBackend {
+    /// Stops whatever is playing and loads `uri`, `play` has to be called to start it.
+    fn set_uri(&mut self, uri: &str);
+    fn play(&mut self);
+    fn pause(&mut self);
+    fn stop(&mut self);
+    fn toggle(&mut self);
+    fn set_buffering(&mut self, percent: i32);
+    fn is_playing(&self) -> bool;
+    /// The buffer fill level if we're waiting for the buffer to fill up, None otherwise.
+    fn buffering_percent(&self) -> Option<i32>;
+    fn stream_health(&self) -> &StreamHealth;
+    fn start_timers(&mut self);
+    fn pause_timers(&mut self);
+    /// Position and duration of the current track in nanoseconds.
+    fn get_progress_info(&self) -> Option<(i64, i64)>;
+    /// Called when the pipeline has finished an asynchronous state change, i.e. it has
+    /// prerolled and can be seeked.
+    fn async_done(&mut self);
+    /// Called periodically while the stream should be playing. If neither the position nor the
+    /// buffer level changed for too long, the stream is restarted at the last known position,
+    /// and after too many restarts we give up and let the caller skip the track.
+    fn check_stall(&mut self) -> StallAction;
+    /// Changes whenever a stream is loaded, including restarts of the same uri. Errors carry the
+    /// generation they happened in, so that late errors of a replaced stream can be told apart.
+    fn stream_generation(&self) -> u32;
+}
+
 #[derive(PartialEq,Eq)]
 enum PlayState {
     Uninit,
@@ -60,60 +325,185 @@ enum PlayState {
 
 pub struct Player {
     state: PlayState,
-    gui_sender: Option<Box<mpsc::Sender<gui::GuiUpdateMessage>>>,
+    event_sender: Option<mpsc::Sender<PlayerEvent>>,
+
+    playbin: Option<gst::Element>,
+    bus_watch: Option<gst::BusWatch>,
+
+    // playbin's audio sink, lets us change the output without stopping the pipeline.
+    audio_sink: Option<gst::SwitchSink>,
+    // Audio sinks found by the last call to refresh_audio_devices.
+    audio_devices: Vec<gst::Device>,
+    // Position (in ns) to seek to as soon as the pipeline has prerolled.
+    pending_seek: Option<i64>,
 
-    playbin: *mut GstElement,
+    uri: Option<String>,
+    // Shared with the bus watch, which tags errors with it.
+    generation: Rc<Cell<u32>>,
+    // Some(percent) while buffering.
+    buffering_percent: Option<i32>,
+    health: StreamHealth,
 
     report_timer: Option<timerfd::TimerGSource>,
     progress_timer: Option<timerfd::TimerGSource>,
+    watchdog_timer: Option<timerfd::TimerGSource>,
+
+    watchdog: StallWatchdog,
 }
 
 impl Player {
     pub fn new() -> Player {
         Player {
             state: PlayState::Uninit,
-            gui_sender: None,
-            playbin: ptr::null_mut(),
+            event_sender: None,
+            playbin: None,
+            bus_watch: None,
+            audio_sink: None,
+            audio_devices: Vec::new(),
+            pending_seek: None,
+            uri: None,
+            generation: Rc::new(Cell::new(0)),
+            buffering_percent: None,
+            health: StreamHealth::new(),
             report_timer: None,
             progress_timer: None,
+            watchdog_timer: None,
+            watchdog: StallWatchdog::new(),
         }
     }
 
-    pub fn init(&mut self, args: Vec<String>, gui_sender: mpsc::Sender<gui::GuiUpdateMessage>) -> Vec<String> {
-        let args2 = unsafe {
-            gst_init_with_args(args)
+    /// A stream that makes no progress for `timeout_secs` seconds is restarted at most
+    /// `max_restarts` times before the watchdog gives up on it.
+    pub fn set_stall_policy(&mut self, timeout_secs: u32, max_restarts: u32) {
+        self.watchdog.set_policy(timeout_secs, max_restarts);
+    }
+
+    pub fn init(&mut self, args: Vec<String>, event_sender: mpsc::Sender<PlayerEvent>) -> Vec<String> {
+        let args2 = gst::init(args);
+        let playbin = match gst::Element::factory_make("playbin", Some(PLAYBIN_ELEMENT_NAME)) {
+            Some(p) => p,
+            None => panic!("failed to create playbin"),
         };
-        self.gui_sender = Some(Box::new(gui_sender));
-        unsafe {
-            let literal_playbin = rffi::CString::new("playbin").unwrap();
-            let elem_name = rffi::CString::new(PLAYBIN_ELEMENT_NAME).unwrap();
-            self.playbin = gst_element_factory_make(
-                literal_playbin.as_ptr(), elem_name.as_ptr());
-            if self.playbin.is_null() {
-                panic!("failed to create playbin");
-            }
 
-            let bus = gst_pipeline_get_bus(self.playbin as *mut GstPipeline);
-            gst_bus_add_watch(bus, Some(bus_callback),
-                              mem::transmute::<&mpsc::Sender<gui::GuiUpdateMessage>, gpointer>(
-                                  &**self.gui_sender.as_ref().unwrap()));
+        self.audio_sink = gst::Element::factory_make("autoaudiosink", None)
+            .and_then(gst::SwitchSink::new);
+        match self.audio_sink {
+            Some(ref sink) => playbin.set_element_property("audio-sink", sink.element()),
+            None => warn!("could not create the audio sink bin, switching devices won't work"),
         }
+
+        let bus = playbin.bus().expect("playbin has no bus");
+        let sender = event_sender.clone();
+        let watched_playbin = playbin.clone();
+        let generation = self.generation.clone();
+        self.bus_watch = Some(bus.add_watch(move |msg| {
+            bus_callback(&sender, &watched_playbin, generation.get(), msg);
+            true
+        }));
+
+        self.event_sender = Some(event_sender);
+        self.playbin = Some(playbin);
         self.state = PlayState::NoUri;
         args2
     }
 
-    pub fn set_uri(&mut self, uri: &str) {
+    fn playbin(&self) -> &gst::Element {
+        self.playbin.as_ref().expect("player is not initialized")
+    }
+
+    // Stops whatever is playing and loads `uri`, keeping the health statistics and the watchdog.
+    fn load_uri(&mut self, uri: &str) {
         self.stop();
-        unsafe {
-            let literal_uri = rffi::CString::new("uri").unwrap();
-            let uri_c_str = rffi::CString::new(uri).unwrap();
-            g_object_set(self.playbin as gpointer,
-                         literal_uri.as_ptr(), uri_c_str.as_ptr(), ptr::null::<gchar>());
-        }
+        self.pending_seek = None;
+        self.uri = Some(uri.to_string());
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.buffering_percent = None;
+        self.playbin().set_string_property("uri", uri);
         self.state = PlayState::WaitToPlay;
     }
 
-    pub fn play(&mut self) {
+    fn log_stream_health(&self) {
+        let now = unsafe { g_get_monotonic_time() };
+        info!("stream health for `{}`: {} stalls, {} ms stalled",
+              self.uri.as_ref().map(|u| &u[..]).unwrap_or("<none>"),
+              self.health.stalls, self.health.total_stalled_time(now) / 1000);
+    }
+
+    pub fn stop_timers(&mut self) {
+        self.report_timer = None;
+        self.progress_timer = None;
+    }
+
+    fn start_watchdog(&mut self) {
+        self.watchdog.idle(unsafe { g_get_monotonic_time() });
+        if self.watchdog_timer.is_none() {
+            let sender = self.event_sender.as_ref().unwrap().clone();
+            let wc = Box::new(WatchdogCallback::new(sender));
+            let mut wt = timerfd::TimerGSource::new(wc as Box<timerfd::TimerGSourceCallback+Send>);
+            wt.attach(unsafe { g_main_context_default() });
+            wt.mut_timer().set_interval(1, 1 * 1000);
+            wt.mut_timer().start();
+            self.watchdog_timer = Some(wt);
+        }
+    }
+
+    /// The playback volume, 0.0 is silent and 1.0 is the stream's own level.
+    pub fn volume(&self) -> f64 {
+        self.playbin().double_property("volume")
+    }
+
+    /// Sets the playback volume, clamped to 0.0 to 1.0. Returns the new volume.
+    pub fn set_volume(&mut self, volume: f64) -> f64 {
+        let volume = volume.max(0.).min(1.);
+        self.playbin().set_double_property("volume", volume);
+        volume
+    }
+
+    /// Asks the device monitor for all audio sinks and returns their display names. The indices
+    /// of the returned list are the ones expected by `set_audio_device`.
+    pub fn refresh_audio_devices(&mut self) -> Vec<String> {
+        self.audio_devices = gst::Device::audio_sinks();
+        self.audio_devices.iter().map(|d| d.display_name()).collect()
+    }
+
+    /// Switches the output to the device with the given index, or back to the default sink if
+    /// `None`. The pipeline keeps running, the new sink takes over with the next buffer.
+    pub fn set_audio_device(&mut self, index: Option<usize>) {
+        if self.state == PlayState::Uninit {
+            panic!("player is not initialized");
+        }
+
+        let sink = match index {
+            Some(i) if i < self.audio_devices.len() => self.audio_devices[i].create_element(),
+            Some(i) => {
+                warn!("audio device index {} is out of range, only {} devices",
+                      i, self.audio_devices.len());
+                return;
+            }
+            None => gst::Element::factory_make("autoaudiosink", None),
+        };
+        let sink = match sink {
+            Some(s) => s,
+            None => {
+                warn!("could not create audio sink");
+                return;
+            }
+        };
+        match self.audio_sink {
+            Some(ref audio_sink) => audio_sink.switch_to(sink),
+            None => warn!("no audio sink bin, can't switch devices"),
+        }
+    }
+}
+
+impl PlaybackBackend for Player {
+    fn set_uri(&mut self, uri: &str) {
+        self.load_uri(uri);
+        self.health = StreamHealth::new();
+        self.watchdog.new_stream();
+    }
+
+    fn play(&mut self) {
         match self.state {
             PlayState::Uninit => panic!("player is not initialized"),
             PlayState::NoUri => panic!("no uri set"),
@@ -123,13 +513,12 @@ impl Player {
             }
             _ => ()
         }
-        unsafe {
-            gst_element_set_state(self.playbin, GST_STATE_PLAYING);
-        }
+        self.playbin().set_state(gst::State::Playing);
         self.state = PlayState::Play;
+        self.start_watchdog();
     }
 
-    pub fn pause(&mut self) {
+    fn pause(&mut self) {
         match self.state {
             PlayState::Uninit => panic!("player is not initialized"),
             // NoUri -> we're not playing anyway.
@@ -137,13 +526,12 @@ impl Player {
             PlayState::NoUri | PlayState::Pause => return,
             _ => ()
         }
-        unsafe {
-            gst_element_set_state(self.playbin, GST_STATE_PAUSED);
-        }
+        self.playbin().set_state(gst::State::Paused);
         self.state = PlayState::Pause;
+        self.watchdog_timer = None;
     }
 
-    pub fn stop(&mut self) {
+    fn stop(&mut self) {
         match self.state {
             PlayState::Uninit => panic!("player is not initialized"),
             PlayState::NoUri => {
@@ -153,13 +541,13 @@ impl Player {
             _ => ()
         }
         self.stop_timers();
-        unsafe {
-            gst_element_set_state(self.playbin, GST_STATE_READY);
-        }
+        self.watchdog_timer = None;
+        self.log_stream_health();
+        self.playbin().set_state(gst::State::Ready);
         self.state = PlayState::NoUri;
     }
 
-    pub fn toggle(&mut self) {
+    fn toggle(&mut self) {
         match self.state {
             PlayState::Uninit => panic!("player is not initialized"),
             // NoUri -> don't do anything
@@ -169,21 +557,25 @@ impl Player {
         }
     }
 
-    pub fn set_buffering(&mut self, is_buffering: bool) {
+    fn set_buffering(&mut self, percent: i32) {
+        let is_buffering = percent < 100;
+        let now = unsafe { g_get_monotonic_time() };
         match self.state {
             PlayState::Uninit => panic!("player is not initialized"),
             PlayState::NoUri => panic!("Uri is not set"),
+            _ => ()
+        }
+        self.buffering_percent = if is_buffering { Some(percent) } else { None };
+        match self.state {
             PlayState::Play if is_buffering => {
-                unsafe {
-                    gst_element_set_state(self.playbin, GST_STATE_PAUSED);
-                }
+                self.playbin().set_state(gst::State::Paused);
                 self.state = PlayState::WaitToPlay;
+                self.health.buffering_started(now);
             }
             PlayState::WaitToPlay if !is_buffering => {
-                unsafe {
-                    gst_element_set_state(self.playbin, GST_STATE_PLAYING);
-                }
+                self.playbin().set_state(gst::State::Playing);
                 self.state = PlayState::Play;
+                self.health.buffering_finished(now);
             }
             _ => {
                 // Nothing to do
@@ -191,11 +583,24 @@ impl Player {
         }
     }
 
-    pub fn is_playing(&self) -> bool {
+    fn is_playing(&self) -> bool {
         self.state == PlayState::Play || self.state == PlayState::WaitToPlay
     }
 
-    pub fn start_timers(&mut self, sender: mpsc::Sender<gui::GuiUpdateMessage>) {
+    fn buffering_percent(&self) -> Option<i32> {
+        if self.state == PlayState::WaitToPlay {
+            self.buffering_percent
+        } else {
+            None
+        }
+    }
+
+    fn stream_health(&self) -> &StreamHealth {
+        &self.health
+    }
+
+    fn start_timers(&mut self) {
+        let sender = self.event_sender.as_ref().unwrap().clone();
         let context = unsafe { g_main_context_default() };
 
         if self.report_timer.is_none() {
@@ -217,7 +622,7 @@ impl Player {
         self.progress_timer.as_mut().unwrap().mut_timer().start();
     }
 
-    pub fn pause_timers(&mut self) {
+    fn pause_timers(&mut self) {
         match self.report_timer {
             Some(ref mut rt) => rt.mut_timer().stop(),
             None => ()
@@ -228,163 +633,143 @@ impl Player {
         }
     }
 
-    pub fn stop_timers(&mut self) {
-        self.report_timer = None;
-        self.progress_timer = None;
+    fn get_progress_info(&self) -> Option<(i64, i64)> {
+        let playbin = self.playbin();
+        match (playbin.query_position(), playbin.query_duration()) {
+            (Some(position), Some(duration)) => Some((position, duration)),
+            _ => None
+        }
     }
 
-    pub fn get_progress_info(&self) -> Option<(i64, i64)> {
-        let mut current_position = 0;
-        let mut current_duration = 0;
-
-        let success_position = unsafe {
-            gst_element_query_position(
-                self.playbin, GST_FORMAT_TIME, &mut current_position)
-        };
+    fn async_done(&mut self) {
+        match self.pending_seek.take() {
+            Some(position) => {
+                debug!("seeking to pending position {}", position);
+                if !self.playbin().seek(position) {
+                    warn!("seeking to {} failed", position);
+                }
+            }
+            None => ()
+        }
+    }
 
-        let success_duration = unsafe {
-            gst_element_query_duration(
-                self.playbin, GST_FORMAT_TIME, &mut current_duration)
-        };
+    fn check_stall(&mut self) -> StallAction {
+        let now = unsafe { g_get_monotonic_time() };
+        if !self.is_playing() {
+            self.watchdog.idle(now);
+            return StallAction::Nothing;
+        }
 
-        if success_duration != 0 && success_position != 0 {
-            Some((current_position, current_duration))
-        } else {
-            None
+        let position = self.get_progress_info().map(|(pos, _)| pos);
+        let buffering_percent = self.buffering_percent();
+        let action = self.watchdog.check(now, position, buffering_percent);
+        match action {
+            StallAction::Nothing => (),
+            StallAction::Restarted => {
+                // Same stream, same health statistics and watchdog.
+                let uri = self.uri.clone().unwrap();
+                self.load_uri(&uri[..]);
+                self.pending_seek = self.watchdog.resume_position();
+                self.play();
+            }
+            StallAction::GiveUp => self.watchdog_timer = None,
         }
+        action
+    }
+
+    fn stream_generation(&self) -> u32 {
+        self.generation.get()
     }
 }
 
 impl Drop for Player {
     fn drop(&mut self) {
+        self.audio_devices.clear();
+        self.audio_sink = None;
         if self.state != PlayState::Uninit {
+            // Removing the watch drops its reference on the playbin.
+            self.bus_watch = None;
+            match self.playbin.take() {
+                Some(playbin) => playbin.set_state(gst::State::Null),
+                None => ()
+            }
             unsafe {
-                if !self.playbin.is_null() {
-                    gst_element_set_state(self.playbin, GST_STATE_NULL);
-                    gst_object_unref(self.playbin as gpointer);
-                }
-                gst_deinit();
+                gst::deinit();
             }
         }
     }
 }
 
-extern "C" fn bus_callback(_bus: *mut GstBus, msg: *mut GstMessage, data: gpointer) -> gboolean {
-    unsafe {
-    let gui_sender = &*(data as *const mpsc::Sender<gui::GuiUpdateMessage>);
+fn stream_info_from_tags(tags: &gst::TagList) -> StreamInfo {
+    let mut info = StreamInfo::new();
+    info.codec = tags.get_string("audio-codec");
+    info.bitrate = tags.get_uint("bitrate").or_else(|| tags.get_uint("nominal-bitrate"));
+    info.title = tags.get_string("title");
+    info.artist = tags.get_string("artist");
+    info.album = tags.get_string("album");
+    info.year = tags.get_year();
+    info
+}
 
-    let name = {
-        let gst_obj = (*msg).src;
-        if gst_obj.is_null() {
-            "null-source".to_string()
-        } else {
-            let name_ptr = gst_object_get_name(gst_obj);
-            if name_ptr.is_null() {
-                "null-name".to_string()
-            } else {
-                let name = utils::ptr_to_string(name_ptr as *const i8).to_string();
-                g_free(name_ptr as gpointer);
-                name
-            }
+fn bus_callback(event_sender: &mpsc::Sender<PlayerEvent>, playbin: &gst::Element,
+                generation: u32, msg: gst::Message) {
+    match msg {
+        gst::Message::Error { src, message, debug } => {
+            error!("ERROR from element {}: {}", src, message);
+            error!("Debugging info: {}", debug.unwrap_or_default());
+            event_sender.send(PlayerEvent::Error(generation, message));
         }
-    };
-
-    match (*msg)._type {
-        GST_MESSAGE_ERROR => {
-            let mut err = ptr::null_mut();
-            let mut dbg_info = ptr::null_mut();
-
-            gst_message_parse_error(msg, &mut err, &mut dbg_info);
-
-
-            let err_msg = utils::ptr_to_string((*err).message as *const i8);
-            let info = utils::ptr_to_string(dbg_info as *const i8);
-
-            error!("ERROR from element {}: {}", name, err_msg);
-            error!("Debugging info: {}", info);
-
-            gui_sender.send(gui::GuiUpdateMessage::Notify(format!("Playback error: `{}`", err_msg)));
-
-            g_error_free(err);
-            g_free(dbg_info as gpointer);
+        gst::Message::Warning { src, message, debug } => {
+            warn!("WARNING from element {}: {}", src, message);
+            warn!("Debugging info: {}", debug.unwrap_or_default());
         }
-        GST_MESSAGE_WARNING => {
-            if log_enabled!(log::WARN) {
-                let mut err = ptr::null_mut();
-                let mut dbg_info = ptr::null_mut();
-
-                gst_message_parse_error(msg, &mut err, &mut dbg_info);
-
-                let err_msg = utils::ptr_to_string((*err).message as *const i8);
-                let info = utils::ptr_to_string(dbg_info as *const i8);
-
-                warn!("WARNING from element {}: {}", name, err_msg);
-                warn!("Debugging info: {}", info);
-
-                g_error_free(err);
-                g_free(dbg_info as gpointer);
-            }
+        gst::Message::Info { src, message, debug } => {
+            info!("INFO from element {}: {}", src, message);
+            info!("Debugging info: {}", debug.unwrap_or_default());
         }
-        GST_MESSAGE_INFO => {
-            if log_enabled!(log::INFO) {
-                let mut err = ptr::null_mut();
-                let mut dbg_info = ptr::null_mut();
-
-                gst_message_parse_error(msg, &mut err, &mut dbg_info);
-
-                let err_msg = utils::ptr_to_string((*err).message as *const i8);
-                let info = utils::ptr_to_string(dbg_info as *const i8);
-
-                info!("INFO from element {}: {}", name, err_msg);
-                info!("Debugging info: {}", info);
-
-                g_error_free(err);
-                g_free(dbg_info as gpointer);
-            }
+        gst::Message::Eos { src } => {
+            debug!("EOS from element {}", src);
+            event_sender.send(PlayerEvent::EndOfStream);
         }
-        GST_MESSAGE_EOS => {
-            debug!("EOS from element {}", name);
-            gui_sender.send(gui::GuiUpdateMessage::NextTrack);
-        }
-        GST_MESSAGE_STATE_CHANGED => {
-            if &name[..] == PLAYBIN_ELEMENT_NAME {
-                let mut new_state = 0;
-                gst_message_parse_state_changed(msg, ptr::null_mut(),
-                    &mut new_state, ptr::null_mut());
-                if log_enabled!(log::DEBUG) {
-                    let new_state_name = gst_element_state_get_name(new_state);
-                    let name = utils::ptr_to_string(new_state_name as *const i8);
-                    debug!("new playbin state: {}", name);
-                }
-                match new_state {
-                    GST_STATE_PLAYING => {
-                        gui_sender.send(gui::GuiUpdateMessage::StartTimers);
-                    }
-                    GST_STATE_PAUSED => {
-                        gui_sender.send(gui::GuiUpdateMessage::PauseTimers);
-                    }
-                    _ => {
-                        // Do nothing, the timers will be overwritten anyways
+        gst::Message::AsyncDone { src } => {
+            debug!("ASYNC_DONE from element {}", src);
+            event_sender.send(PlayerEvent::AsyncDone);
+        }
+        gst::Message::StateChanged { ref src, new, .. } if &src[..] == PLAYBIN_ELEMENT_NAME => {
+            debug!("new playbin state: {:?}", new);
+            match new {
+                gst::State::Playing => {
+                    match playbin.current_audio_format() {
+                        Some(format) => {
+                            let mut info = StreamInfo::new();
+                            info.sample_rate = format.sample_rate;
+                            info.channels = format.channels;
+                            event_sender.send(PlayerEvent::StreamInfo(info));
+                        }
+                        None => ()
                     }
+                    event_sender.send(PlayerEvent::Playing);
+                }
+                gst::State::Paused => {
+                    event_sender.send(PlayerEvent::Paused);
+                }
+                _ => {
+                    // Do nothing, the timers will be overwritten anyways
                 }
             }
         }
-        GST_MESSAGE_BUFFERING => {
-            let mut percent = 0;
-            gst_message_parse_buffering(msg, &mut percent);
-            info!("BUFFERING from element `{}`, {}%", name, percent);
-            gui_sender.send(gui::GuiUpdateMessage::SetBuffering(percent < 100));
-        }
-        _ => {
-            if log_enabled!(log::DEBUG) {
-                let msg_type_cstr = gst_message_type_get_name((*msg)._type);
-                let msg_type_name = utils::ptr_to_string(msg_type_cstr as *const i8);
-                debug!("message of type `{}` from element `{}`", msg_type_name, name);
-            }
+        gst::Message::StateChanged { .. } => (),
+        gst::Message::Tag { src, tags } => {
+            let info = stream_info_from_tags(&tags);
+            debug!("TAG from element `{}`: {:?}", src, info);
+            event_sender.send(PlayerEvent::StreamInfo(info));
+        }
+        gst::Message::Buffering { src, percent } => {
+            info!("BUFFERING from element `{}`, {}%", src, percent);
+            event_sender.send(PlayerEvent::Buffering(percent));
+        }
+        gst::Message::Other { src, type_name } => {
+            debug!("message of type `{}` from element `{}`", type_name, src);
         }
-    }
-
-    // Returning 0 removes this callback
-    return 1;
     }
 }
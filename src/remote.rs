@@ -0,0 +1,423 @@
+// The HTTP remote control, for phones and other machines on the LAN. A tiny HTTP/1.1 server on
+// plain TcpStreams, every connection gets its own thread and is closed after one response.
+//
+//   GET  /                       the bundled web page
+//   GET  /api/now-playing        what's playing, like the control socket's now_playing
+//   POST /api/toggle             pause or resume
+//   POST /api/skip               skip the current track
+//   GET  /api/mixes              the loaded mix list
+//   POST /api/mixes?smart_id=ID  searches and loads the mixes for a smart id
+//   POST /api/mixes/ID/play      plays the loaded mix with that id
+//   GET  /api/events             server-sent events, `track` and `playback` with the new state,
+//                                `mixes` with the new mix list
+//
+// There is no authentication: anybody who can reach the address can control the player, so
+// only bind it to addresses on networks you trust. Requests from web pages on other origins are
+// refused, so that sites opened in a browser on the LAN can't drive it. So are requests for any
+// host but an IP address, localhost, this machine's name or the one in the configured address,
+// a site that rebinds its own name to our address still sends that name.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+use rustc_serialize::json;
+use rustc_serialize::json::ToJson;
+
+use libc;
+use url;
+
+use api;
+use app;
+use control;
+use gui;
+use gui::GuiUpdateMessage;
+
+static INDEX_HTML: &'static str = include_str!("remote.html");
+
+extern {
+    fn gethostname(name: *mut libc::c_char, len: libc::size_t) -> libc::c_int;
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe {
+        gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() as libc::size_t)
+    };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+pub fn start(address: &str, sender: mpsc::Sender<GuiUpdateMessage>) -> io::Result<()> {
+    let listener = try!(TcpListener::bind(address));
+    info!("HTTP remote control listening on http://{}/", address);
+    let mut names = vec![host_name(address).to_string()];
+    match hostname() {
+        Some(name) => {
+            names.push(format!("{}.local", name));
+            names.push(name);
+        }
+        None => (),
+    }
+    let names: Vec<String> = names.into_iter()
+        .map(|n| n.to_lowercase()).filter(|n| !n.is_empty()).collect();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    let names = names.clone();
+                    thread::spawn(move || {
+                        match serve_client(stream, sender, &names[..]) {
+                            Ok(()) => (),
+                            Err(err) => debug!("HTTP client went away: {}", err),
+                        }
+                    });
+                }
+                Err(err) => warn!("HTTP remote: could not accept client: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(PartialEq, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    host: Option<String>,
+    origin: Option<String>,
+}
+
+enum Response {
+    Ok(&'static str, String),
+    Json(json::Json),
+    Error(&'static str, String),
+    Events,
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut request_line = String::new();
+    if try!(reader.read_line(&mut request_line)) == 0 {
+        return Ok(None);
+    }
+    let mut content_length = 0;
+    let mut host = None;
+    let mut origin = None;
+    loop {
+        let mut header = String::new();
+        if try!(reader.read_line(&mut header)) == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        match &name[..] {
+            "content-length" => content_length = value.parse().unwrap_or(0),
+            "host" => host = Some(value.to_string()),
+            "origin" => origin = Some(value.to_string()),
+            _ => (),
+        }
+    }
+    // We don't take bodies, but the client may still send one.
+    try!(io::copy(&mut reader.by_ref().take(content_length), &mut io::sink()));
+
+    let mut words = request_line.split_whitespace();
+    let method = words.next().unwrap_or("").to_string();
+    let target = words.next().unwrap_or("/");
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], url::form_urlencoded::parse(target[i + 1..].as_bytes())),
+        None => (target, Vec::new()),
+    };
+    Ok(Some(Request {
+        method: method,
+        path: path.to_string(),
+        query: query,
+        host: host,
+        origin: origin,
+    }))
+}
+
+// `host` without the port, IPv6 addresses are in brackets.
+fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        match host.find(']') {
+            Some(i) => &host[1..i],
+            None => "",
+        }
+    } else {
+        host.split(':').next().unwrap_or("")
+    }
+}
+
+// Whether `host`, a Host header, names us rather than some domain that resolves to us. `names`
+// are our own, in lower case.
+fn allowed_host(host: &str, names: &[String]) -> bool {
+    let name = host_name(host).trim_right_matches('.').to_lowercase();
+    if name.is_empty() {
+        return false;
+    }
+    name.parse::<IpAddr>().is_ok() || name == "localhost" || names.contains(&name)
+}
+
+// Browsers send an Origin with cross-site requests, it has to name the host they were sent to.
+// Clients that aren't browsers usually send none.
+fn same_origin(request: &Request) -> bool {
+    let origin = match request.origin {
+        Some(ref o) => o,
+        None => return true,
+    };
+    let origin_host = origin.trim_left_matches("http://").trim_left_matches("https://");
+    match request.host {
+        Some(ref host) => origin_host.to_lowercase() == host.to_lowercase(),
+        None => false,
+    }
+}
+
+fn serve_client(stream: TcpStream, sender: mpsc::Sender<GuiUpdateMessage>, names: &[String])
+                -> io::Result<()> {
+    let mut writer = try!(stream.try_clone());
+    let mut reader = io::BufReader::new(stream);
+    let request = match try!(read_request(&mut reader)) {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    debug!("HTTP remote: {} {}", request.method, request.path);
+
+    let (status, content_type, body) = match handle_request(&request, &sender, names) {
+        Response::Ok(content_type, body) => ("200 OK", content_type, body),
+        Response::Json(json) => ("200 OK", "application/json", json.to_string()),
+        Response::Error(status, message) => {
+            let mut obj = BTreeMap::new();
+            obj.insert("error".to_string(), message.to_json());
+            (status, "application/json", json::Json::Object(obj).to_string())
+        }
+        Response::Events => return send_events(writer, sender),
+    };
+    try!(write!(writer, "HTTP/1.1 {}\r\nContent-Type: {}; charset=utf-8\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n",
+                status, content_type, body.len()));
+    writer.write_all(body.as_bytes())
+}
+
+fn ok_json() -> Response {
+    let mut obj = BTreeMap::new();
+    obj.insert("ok".to_string(), true.to_json());
+    Response::Json(json::Json::Object(obj))
+}
+
+fn shutting_down() -> Response {
+    Response::Error("503 Service Unavailable", "rusttracks is shutting down".to_string())
+}
+
+fn send_command(sender: &mpsc::Sender<GuiUpdateMessage>, command: app::Command) -> Response {
+    match sender.send(GuiUpdateMessage::Command(command)) {
+        Ok(()) => ok_json(),
+        Err(_) => shutting_down(),
+    }
+}
+
+fn mixes_json(mixes: &[api::Mix]) -> json::Json {
+    json::Json::Array(mixes.iter().map(|m| control::mix_json(m)).collect())
+}
+
+// The id in `api/mixes/ID/play`.
+fn mix_to_play(path: &str) -> Option<&str> {
+    let prefix = "api/mixes/";
+    let suffix = "/play";
+    if path.len() > prefix.len() + suffix.len() &&
+            path.starts_with(prefix) && path.ends_with(suffix) {
+        Some(&path[prefix.len()..path.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+fn handle_request(request: &Request, sender: &mpsc::Sender<GuiUpdateMessage>, names: &[String])
+                  -> Response {
+    match request.host {
+        Some(ref host) if allowed_host(host, names) => (),
+        _ => return Response::Error("403 Forbidden", "unknown host".to_string()),
+    }
+    if !same_origin(request) {
+        return Response::Error("403 Forbidden", "cross-origin requests are refused".to_string());
+    }
+    let path = request.path.trim_matches('/');
+    match (&request.method[..], path) {
+        ("GET", "") => Response::Ok("text/html", INDEX_HTML.to_string()),
+        ("GET", "api/now-playing") => match gui::query_status(sender) {
+            Some(status) => Response::Json(json::Json::Object(control::status_json(&status))),
+            None => shutting_down(),
+        },
+        ("POST", "api/toggle") => send_command(sender, app::Command::TogglePlaying),
+        ("POST", "api/skip") => send_command(sender, app::Command::SkipTrack),
+        ("GET", "api/mixes") => match gui::query_status(sender) {
+            Some(status) => Response::Json(mixes_json(&status.mixes[..])),
+            None => shutting_down(),
+        },
+        // The core fetches them like for a search in the GUI, they arrive as a `mixes` event.
+        ("POST", "api/mixes") => {
+            match request.query.iter().find(|&&(ref k, _)| k == "smart_id") {
+                Some(&(_, ref id)) => send_command(sender, app::Command::GetMixes(id.clone())),
+                None => Response::Error("400 Bad Request", "missing smart_id".to_string()),
+            }
+        }
+        ("GET", "api/events") => Response::Events,
+        ("POST", p) if mix_to_play(p).is_some() => play_mix(sender, mix_to_play(p).unwrap()),
+        _ => Response::Error("404 Not Found", "not found".to_string()),
+    }
+}
+
+fn play_mix(sender: &mpsc::Sender<GuiUpdateMessage>, id: &str) -> Response {
+    let id: u32 = match id.parse() {
+        Ok(id) => id,
+        Err(_) => return Response::Error("400 Bad Request", "bad mix id".to_string()),
+    };
+    let status = match gui::query_status(sender) {
+        Some(s) => s,
+        None => return shutting_down(),
+    };
+    match status.mixes.iter().position(|m| m.id == id) {
+        Some(i) => send_command(sender, app::Command::PlayMix(i)),
+        None => Response::Error("404 Not Found", format!("mix {} is not in the mix list", id)),
+    }
+}
+
+fn send_events(mut writer: TcpStream, sender: mpsc::Sender<GuiUpdateMessage>) -> io::Result<()> {
+    let (status_sender, status_receiver) = mpsc::channel();
+    if sender.send(GuiUpdateMessage::Subscribe(status_sender)).is_err() {
+        return Ok(());
+    }
+    try!(write!(writer, "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                         Cache-Control: no-cache\r\nConnection: close\r\n\r\n"));
+    try!(writer.flush());
+
+    let track_id = |s: &app::Status| s.track.as_ref().map(|t| t.id);
+    let mix_ids = |s: &app::Status| s.mixes.iter().map(|m| m.id).collect::<Vec<_>>();
+    let mut last: Option<app::Status> = None;
+    // Ends when the GUI goes away or the client disconnects (the write fails).
+    for status in status_receiver.iter() {
+        let event = match last {
+            Some(ref l) if track_id(l) == track_id(&status) => {
+                if l.playing == status.playing {
+                    None
+                } else {
+                    Some("playback")
+                }
+            }
+            _ => Some("track"),
+        };
+        match event {
+            Some(event) => {
+                let data = json::Json::Object(control::status_json(&status));
+                try!(write!(writer, "event: {}\ndata: {}\n\n", event, data));
+            }
+            None => (),
+        }
+        match last {
+            Some(ref l) if mix_ids(l) != mix_ids(&status) => {
+                let data = mixes_json(&status.mixes[..]);
+                try!(write!(writer, "event: mixes\ndata: {}\n\n", data));
+            }
+            _ => (),
+        }
+        try!(writer.flush());
+        last = Some(status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{Request, allowed_host, host_name, read_request, mix_to_play, same_origin};
+
+    fn read(data: &str) -> Option<Request> {
+        read_request(&mut io::Cursor::new(data.as_bytes().to_vec())).unwrap()
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        let request = read("POST /api/mixes?smart_id=tags%3Afolk HTTP/1.1\r\n\
+                            Host: box:8080\r\nOrigin: http://box:8080\r\n\r\n").unwrap();
+        assert_eq!(request, Request {
+            method: "POST".to_string(),
+            path: "/api/mixes".to_string(),
+            query: vec![("smart_id".to_string(), "tags:folk".to_string())],
+            host: Some("box:8080".to_string()),
+            origin: Some("http://box:8080".to_string()),
+        });
+    }
+
+    #[test]
+    fn bodies_are_skipped() {
+        let mut reader = io::Cursor::new(b"POST /api/skip HTTP/1.1\r\nContent-Length: 5\r\n\r\n\
+                                           hello\
+                                           GET / HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(read_request(&mut reader).unwrap().unwrap().path, "/api/skip");
+        assert_eq!(read_request(&mut reader).unwrap().unwrap().method, "GET");
+    }
+
+    #[test]
+    fn incomplete_requests_are_dropped() {
+        assert_eq!(read(""), None);
+        assert_eq!(read("GET / HTTP/1.1\r\nHost: box\r\n"), None);
+    }
+
+    #[test]
+    fn mix_ids_are_taken_from_play_paths() {
+        assert_eq!(mix_to_play("api/mixes/42/play"), Some("42"));
+        assert_eq!(mix_to_play("api/mixes/abc/play"), Some("abc"));
+        assert_eq!(mix_to_play("api/mixes//play"), None);
+        assert_eq!(mix_to_play("api/mixes/play"), None);
+        assert_eq!(mix_to_play("api/mixes/42"), None);
+        assert_eq!(mix_to_play("api/tracks/42/play"), None);
+    }
+
+    #[test]
+    fn only_requests_from_the_same_origin_are_taken() {
+        let mut request = read("POST /api/skip HTTP/1.1\r\nHost: Box:8080\r\n\r\n").unwrap();
+        assert!(same_origin(&request));
+        request.origin = Some("http://box:8080".to_string());
+        assert!(same_origin(&request));
+        request.origin = Some("http://evil.example.com".to_string());
+        assert!(!same_origin(&request));
+        request.host = None;
+        request.origin = Some("http://box:8080".to_string());
+        assert!(!same_origin(&request));
+    }
+
+    #[test]
+    fn only_requests_for_our_own_names_are_taken() {
+        let names = vec!["box".to_string(), "box.local".to_string()];
+        assert!(allowed_host("192.168.1.20:8080", &names[..]));
+        assert!(allowed_host("[::1]:8080", &names[..]));
+        assert!(allowed_host("localhost:8080", &names[..]));
+        assert!(allowed_host("Box:8080", &names[..]));
+        assert!(allowed_host("box.local.", &names[..]));
+        assert!(!allowed_host("rebind.example.com:8080", &names[..]));
+        assert!(!allowed_host("box.example.com", &names[..]));
+        assert!(!allowed_host("[::1", &names[..]));
+        assert!(!allowed_host(":8080", &names[..]));
+    }
+
+    #[test]
+    fn ports_and_brackets_are_stripped_from_hosts() {
+        assert_eq!(host_name("box:8080"), "box");
+        assert_eq!(host_name("box"), "box");
+        assert_eq!(host_name("[fe80::1]:8080"), "fe80::1");
+        assert_eq!(host_name("[fe80::1"), "");
+    }
+}
@@ -0,0 +1,321 @@
+// Scrobbling to a ListenBrainz compatible server, configured with `scrobble_endpoint` (e.g.
+// `https://api.listenbrainz.org`) and `scrobble_token` in the config file. The core decides when
+// to send a now playing update and when a track counts as a scrobble; this module does the
+// sending. Scrobbles that couldn't be submitted wait in
+// $XDG_DATA_HOME/rusttracks/scrobble-queue.json and go out with the next successful one. Those
+// the server refused are moved to scrobble-rejected.jsonl, retrying them would only block the
+// queue.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{StaticMutex, MUTEX_INIT};
+
+use rustc_serialize::json;
+use rustc_serialize::json::ToJson;
+
+use hyper;
+use hyper::header;
+
+use url;
+
+use api;
+use app;
+use config;
+use paths;
+use utils;
+
+// The server takes at most this many listens per submission.
+static BATCH_SIZE: usize = 100;
+
+// Every scrobble runs on its own network thread, this keeps them from reading and writing the
+// queue at the same time and submitting the same listens twice.
+static QUEUE_LOCK: StaticMutex = MUTEX_INIT;
+
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    /// Base url, `/1/submit-listens` is appended.
+    pub url: String,
+    pub token: String,
+}
+
+impl Endpoint {
+    /// None if scrobbling isn't configured.
+    pub fn from_config(config: &config::Config) -> Option<Endpoint> {
+        match (&config.scrobble_endpoint, &config.scrobble_token) {
+            (&Some(ref url), &Some(ref token)) => Some(Endpoint {
+                url: url.trim_right_matches('/').to_string(),
+                token: token.clone(),
+            }),
+            (&Some(_), &None) => {
+                warn!("scrobble_endpoint is set but scrobble_token isn't, not scrobbling");
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TokenHeader(String);
+
+impl header::Header for TokenHeader {
+    fn header_name() -> &'static str { "Authorization" }
+    fn parse_header(_: &[Vec<u8>]) -> Option<TokenHeader> {
+        None
+    }
+}
+
+impl header::HeaderFormat for TokenHeader {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Token {}", self.0)
+    }
+}
+
+fn track_metadata(track: &api::Track, duration: Option<u32>) -> json::Json {
+    let mut info = BTreeMap::new();
+    info.insert("submission_client".to_string(), "rusttracks".to_json());
+    if !track.url.is_empty() {
+        info.insert("origin_url".to_string(), track.url.to_json());
+    }
+    match duration {
+        Some(d) => { info.insert("duration".to_string(), d.to_json()); }
+        None => (),
+    }
+
+    let mut metadata = BTreeMap::new();
+    metadata.insert("artist_name".to_string(), track.performer.to_json());
+    metadata.insert("track_name".to_string(), track.name.to_json());
+    match track.release_name {
+        Some(ref rn) => { metadata.insert("release_name".to_string(), rn.to_json()); }
+        None => (),
+    }
+    metadata.insert("additional_info".to_string(), json::Json::Object(info));
+    json::Json::Object(metadata)
+}
+
+fn listen_json(listen: &app::Listen) -> json::Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("listened_at".to_string(), listen.played_at.secs.to_json());
+    obj.insert("track_metadata".to_string(), track_metadata(&listen.track, listen.duration));
+    json::Json::Object(obj)
+}
+
+fn submission_body(listen_type: &str, payload: Vec<json::Json>) -> json::Json {
+    let mut body = BTreeMap::new();
+    body.insert("listen_type".to_string(), listen_type.to_json());
+    body.insert("payload".to_string(), json::Json::Array(payload));
+    json::Json::Object(body)
+}
+
+enum SubmitError {
+    // The server couldn't be reached or is having trouble, worth trying again later.
+    Retry(String),
+    // The server doesn't want these listens, e.g. because they're malformed.
+    Rejected(String),
+}
+
+// Too many requests and server errors are temporary, other client errors aren't. A bad token
+// is fixed in the config, the listens are kept until then.
+fn is_retryable(status: u16) -> bool {
+    status == 401 || status == 403 || status == 429 || status >= 500
+}
+
+fn submit(endpoint: &Endpoint, listen_type: &str, payload: Vec<json::Json>)
+          -> Result<(), SubmitError> {
+    let body = submission_body(listen_type, payload).to_string();
+
+    let u = match url::Url::parse(&format!("{}/1/submit-listens", endpoint.url)[..]) {
+        Ok(u) => u,
+        Err(err) => {
+            return Err(SubmitError::Retry(format!("bad scrobble endpoint `{}`: {:?}",
+                                                  endpoint.url, err)));
+        }
+    };
+    debug!("submitting {} to `{}`", listen_type, u);
+    let mut client = hyper::Client::new();
+    let mut response = try!(client.post(u)
+                            .header(TokenHeader(endpoint.token.clone()))
+                            .header(header::ContentType("application/json".parse().unwrap()))
+                            .body(&body[..])
+                            .send()
+                            .map_err(|err| SubmitError::Retry(format!("{}", err))));
+    if response.status == hyper::status::StatusCode::Ok {
+        return Ok(());
+    }
+    let mut data = String::new();
+    let _ = response.read_to_string(&mut data);
+    let message = format!("{}: {}", response.status, data.trim());
+    let status = response.status.to_u16();
+    if status == 401 || status == 403 {
+        warn!("the scrobble server doesn't accept our token, check scrobble_token in the config");
+    }
+    if is_retryable(status) {
+        Err(SubmitError::Retry(message))
+    } else {
+        Err(SubmitError::Rejected(message))
+    }
+}
+
+/// Tells the server what we're playing now. Not retried, it's stale soon anyway.
+pub fn now_playing(endpoint: &Endpoint, track: &api::Track) {
+    let mut obj = BTreeMap::new();
+    obj.insert("track_metadata".to_string(), track_metadata(track, None));
+    match submit(endpoint, "playing_now", vec![json::Json::Object(obj)]) {
+        Ok(()) => (),
+        Err(SubmitError::Retry(err)) | Err(SubmitError::Rejected(err)) => {
+            info!("could not send now playing: {}", err)
+        }
+    }
+}
+
+/// Submits `listen` together with everything still queued. What fails for a temporary reason
+/// stays queued, what the server rejects is set aside.
+pub fn scrobble(endpoint: &Endpoint, listen: &app::Listen) {
+    let _guard = QUEUE_LOCK.lock().unwrap();
+    let mut pending = read_queue();
+    pending.push(listen.clone());
+    while !pending.is_empty() {
+        let batch_len = if pending.len() < BATCH_SIZE { pending.len() } else { BATCH_SIZE };
+        let listen_type = if batch_len == 1 { "single" } else { "import" };
+        let payload = pending[..batch_len].iter().map(listen_json).collect();
+        match submit(endpoint, listen_type, payload) {
+            Ok(()) => (),
+            Err(SubmitError::Retry(err)) => {
+                warn!("could not scrobble, {} listens stay queued: {}", pending.len(), err);
+                break;
+            }
+            Err(SubmitError::Rejected(err)) => {
+                warn!("the scrobble server rejected {} listens, setting them aside in `{}`: {}",
+                      batch_len, rejected_path().display(), err);
+                match park(&pending[..batch_len]) {
+                    Ok(()) => (),
+                    Err(err) => warn!("could not save the rejected listens: {}", err),
+                }
+            }
+        }
+        pending = pending[batch_len..].to_vec();
+    }
+    match write_queue(&pending[..]) {
+        Ok(()) => (),
+        Err(err) => warn!("could not save the scrobble queue: {}", err),
+    }
+}
+
+fn queue_path() -> PathBuf {
+    let mut path = paths::xdg_app_dir("XDG_DATA_HOME", ".local/share");
+    path.push("scrobble-queue.json");
+    path
+}
+
+fn rejected_path() -> PathBuf {
+    let mut path = paths::xdg_app_dir("XDG_DATA_HOME", ".local/share");
+    path.push("scrobble-rejected.jsonl");
+    path
+}
+
+// Appends listens the server didn't take, one json object per line like the listening log.
+fn park(listens: &[app::Listen]) -> io::Result<()> {
+    let path = rejected_path();
+    match path.parent() {
+        Some(dir) => try!(fs::create_dir_all(dir)),
+        None => (),
+    }
+    let mut file = try!(fs::OpenOptions::new().append(true).create(true).open(&path));
+    for listen in listens.iter() {
+        let line = try!(json::encode(listen).map_err(utils::encoder_error));
+        try!(writeln!(file, "{}", line));
+    }
+    Ok(())
+}
+
+fn read_queue() -> Vec<app::Listen> {
+    let path = queue_path();
+    let mut data = String::new();
+    match fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut data)) {
+        Ok(_) => (),
+        Err(_) => return Vec::new(),
+    }
+    match json::decode(&data[..]) {
+        Ok(queue) => queue,
+        Err(err) => {
+            warn!("ignoring broken scrobble queue `{}`: {:?}", path.display(), err);
+            Vec::new()
+        }
+    }
+}
+
+fn write_queue(queue: &[app::Listen]) -> io::Result<()> {
+    let path = queue_path();
+    if queue.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+    }
+    let data = try!(json::encode(&queue).map_err(utils::encoder_error));
+    utils::write_atomically(&path, data.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+
+    use api;
+    use app;
+
+    use super::{is_retryable, listen_json, submission_body};
+
+    #[test]
+    fn submissions_have_the_listenbrainz_shape() {
+        let listen = app::Listen {
+            played_at: api::Timestamp { secs: 1398947696 },
+            track: api::Track {
+                id: 7,
+                name: "Song".to_string(),
+                performer: "Band".to_string(),
+                release_name: Some("Album".to_string()),
+                year: None,
+                track_file_stream_url: String::new(),
+                buy_link: String::new(),
+                faved_by_current_user: false,
+                url: "/tracks/7".to_string(),
+            },
+            mix_id: 3,
+            listened: 200,
+            duration: Some(240),
+        };
+        let body = submission_body("single", vec![listen_json(&listen)]);
+        let expected = json::Json::from_str(r#"{
+            "listen_type": "single",
+            "payload": [{
+                "listened_at": 1398947696,
+                "track_metadata": {
+                    "artist_name": "Band",
+                    "track_name": "Song",
+                    "release_name": "Album",
+                    "additional_info": {
+                        "submission_client": "rusttracks",
+                        "origin_url": "/tracks/7",
+                        "duration": 240
+                    }
+                }
+            }]
+        }"#).unwrap();
+        // Compared as text, numbers we encode are signed where the parser makes them unsigned.
+        assert_eq!(body.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn only_temporary_errors_are_retried() {
+        assert!(is_retryable(429));
+        assert!(is_retryable(503));
+        assert!(is_retryable(401));
+        assert!(is_retryable(403));
+        assert!(!is_retryable(400));
+        assert!(!is_retryable(404));
+    }
+}
@@ -0,0 +1,257 @@
+// Window-wide keyboard shortcuts. Bindings are GTK accelerator strings like `<Control>f`, the
+// defaults can be replaced per action with the `key_bindings` object in the config file, e.g.
+//
+//   "key_bindings": { "skip": ["n", "Right", "<Control>n"], "volume_up": [] }
+//
+// where an empty list disables the action's shortcuts.
+
+use std::collections::BTreeMap;
+use std::ffi as rffi;
+use std::mem;
+use std::ptr;
+
+use gtk::ffi::*;
+use gtk::*;
+
+use utils;
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Action {
+    TogglePlaying,
+    Skip,
+    FocusSearch,
+    ShowPlaylists,
+    ShowCurrent,
+    ShowHistory,
+    VolumeUp,
+    VolumeDown,
+    ShowHelp,
+}
+
+static ACTIONS: [Action; 9] = [
+    Action::TogglePlaying,
+    Action::Skip,
+    Action::FocusSearch,
+    Action::ShowPlaylists,
+    Action::ShowCurrent,
+    Action::ShowHistory,
+    Action::VolumeUp,
+    Action::VolumeDown,
+    Action::ShowHelp,
+];
+
+impl Action {
+    /// The name used in the config file.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::TogglePlaying => "toggle",
+            Action::Skip => "skip",
+            Action::FocusSearch => "search",
+            Action::ShowPlaylists => "playlists",
+            Action::ShowCurrent => "current",
+            Action::ShowHistory => "history",
+            Action::VolumeUp => "volume_up",
+            Action::VolumeDown => "volume_down",
+            Action::ShowHelp => "help",
+        }
+    }
+
+    /// What the help overlay says about it.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Action::TogglePlaying => "Play or pause",
+            Action::Skip => "Skip the current track",
+            Action::FocusSearch => "Search mixes",
+            Action::ShowPlaylists => "Show the mix list",
+            Action::ShowCurrent => "Show the current mix",
+            Action::ShowHistory => "Show the track history",
+            Action::VolumeUp => "Volume up",
+            Action::VolumeDown => "Volume down",
+            Action::ShowHelp => "Show the keyboard shortcuts",
+        }
+    }
+
+    fn default_accelerators(&self) -> &'static [&'static str] {
+        match *self {
+            Action::TogglePlaying => &["space"],
+            Action::Skip => &["n", "Right"],
+            Action::FocusSearch => &["<Control>f"],
+            Action::ShowPlaylists => &["<Control>1"],
+            Action::ShowCurrent => &["<Control>2"],
+            Action::ShowHistory => &["<Control>3"],
+            Action::VolumeUp => &["plus", "equal", "KP_Add"],
+            Action::VolumeDown => &["minus", "KP_Subtract"],
+            Action::ShowHelp => &["question", "F1"],
+        }
+    }
+}
+
+struct Binding {
+    keyval: guint,
+    modifiers: GdkModifierType,
+    action: Action,
+}
+
+pub struct KeyBindings {
+    bindings: Vec<Binding>,
+}
+
+impl KeyBindings {
+    /// The default bindings, with the actions in `overrides` (config name to accelerators)
+    /// bound to those accelerators instead.
+    pub fn new(overrides: &BTreeMap<String, Vec<String>>) -> KeyBindings {
+        for name in overrides.keys() {
+            if !ACTIONS.iter().any(|a| a.name() == &name[..]) {
+                warn!("ignoring key bindings for unknown action `{}`", name);
+            }
+        }
+
+        let mut bindings = Vec::new();
+        for &action in ACTIONS.iter() {
+            let accelerators: Vec<String> = match overrides.get(action.name()) {
+                Some(accels) => accels.clone(),
+                None => action.default_accelerators().iter().map(|a| a.to_string()).collect(),
+            };
+            for accel in accelerators.iter() {
+                match parse_accelerator(&accel[..]) {
+                    Some((keyval, modifiers)) => bindings.push(Binding {
+                        keyval: keyval,
+                        modifiers: modifiers,
+                        action: action,
+                    }),
+                    None => warn!("ignoring invalid key binding `{}` for `{}`",
+                                  accel, action.name()),
+                }
+            }
+        }
+        KeyBindings { bindings: bindings }
+    }
+
+    /// The action bound to a key press. Shift only has to match if the binding asks for it,
+    /// `+` is shifted on most layouts.
+    pub fn lookup(&self, keyval: guint, state: GdkModifierType) -> Option<Action> {
+        let keyval = unsafe { gdk_keyval_to_lower(keyval) };
+        let state = state & unsafe { gtk_accelerator_get_default_mod_mask() };
+        self.bindings.iter().find(|b| {
+            let state = if b.modifiers & GDK_SHIFT_MASK != 0 {
+                state
+            } else {
+                state & !GDK_SHIFT_MASK
+            };
+            b.keyval == keyval && b.modifiers == state
+        }).map(|b| b.action)
+    }
+
+    /// The accelerators of `action` in human readable form, e.g. `Ctrl+F`.
+    pub fn labels(&self, action: Action) -> Vec<String> {
+        self.bindings.iter().filter(|b| b.action == action).map(|b| unsafe {
+            let label = gtk_accelerator_get_label(b.keyval, b.modifiers);
+            let s = utils::ptr_to_string(label as *const i8);
+            g_free(label as gpointer);
+            s
+        }).collect()
+    }
+
+    pub fn actions() -> &'static [Action] {
+        &ACTIONS
+    }
+}
+
+/// True if no modifier but Shift is held. Such keys belong to a focused text entry (typing,
+/// moving the cursor) rather than to a shortcut.
+pub fn is_unmodified(state: GdkModifierType) -> bool {
+    let state = state & unsafe { gtk_accelerator_get_default_mod_mask() };
+    state & !GDK_SHIFT_MASK == 0
+}
+
+fn parse_accelerator(accel: &str) -> Option<(guint, GdkModifierType)> {
+    let accel_c_str = match rffi::CString::new(accel) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    let mut keyval = 0;
+    let mut modifiers = 0;
+    unsafe {
+        gtk_accelerator_parse(accel_c_str.as_ptr(), &mut keyval, &mut modifiers);
+        if keyval == 0 && modifiers == 0 {
+            return None;
+        }
+        Some((gdk_keyval_to_lower(keyval), modifiers))
+    }
+}
+
+/// Opens a window listing all shortcuts, closed with Escape or its Close button.
+pub fn show_help(parent: *mut GtkWidget, bindings: &KeyBindings) {
+    unsafe {
+        let window = gtk_window_new(GTK_WINDOW_TOPLEVEL);
+        let title_c_str = rffi::CString::new("Keyboard Shortcuts").unwrap();
+        gtk_window_set_title(window as *mut GtkWindow, title_c_str.as_ptr());
+        gtk_window_set_transient_for(window as *mut GtkWindow, parent as *mut GtkWindow);
+        gtk_window_set_modal(window as *mut GtkWindow, 1);
+        gtk_window_set_resizable(window as *mut GtkWindow, 0);
+        gtk_window_set_type_hint(window as *mut GtkWindow, GDK_WINDOW_TYPE_HINT_DIALOG);
+        gtk_container_set_border_width(window as *mut GtkContainer, 12);
+
+        let outer_box = gtk_box_new(GTK_ORIENTATION_VERTICAL, 12);
+        gtk_container_add(window as *mut GtkContainer, outer_box);
+
+        let grid = gtk_grid_new();
+        gtk_grid_set_row_spacing(grid as *mut GtkGrid, 6);
+        gtk_grid_set_column_spacing(grid as *mut GtkGrid, 24);
+        gtk_box_pack_start(outer_box as *mut GtkBox, grid, 1, 1, 0);
+
+        let mut row = 0;
+        for &action in KeyBindings::actions().iter() {
+            let labels = bindings.labels(action);
+            if labels.is_empty() {
+                continue;
+            }
+            let description_c_str = rffi::CString::new(action.description()).unwrap();
+            let description = gtk_label_new(description_c_str.as_ptr());
+            gtk_misc_set_alignment(description as *mut GtkMisc, 0f32, 0.5f32);
+            gtk_grid_attach(grid as *mut GtkGrid, description, 0, row, 1, 1);
+
+            let keys_c_str = rffi::CString::new(labels.connect(", ")).unwrap();
+            let keys = gtk_label_new(keys_c_str.as_ptr());
+            gtk_misc_set_alignment(keys as *mut GtkMisc, 0f32, 0.5f32);
+            gtk_widget_set_sensitive(keys, 0);
+            gtk_grid_attach(grid as *mut GtkGrid, keys, 1, row, 1, 1);
+            row += 1;
+        }
+
+        let close_c_str = rffi::CString::new("Close").unwrap();
+        let close_button = gtk_button_new_with_label(close_c_str.as_ptr());
+        gtk_widget_set_halign(close_button, GTK_ALIGN_END);
+        gtk_box_pack_end(outer_box as *mut GtkBox, close_button, 0, 0, 0);
+
+        let clicked_c_str = rffi::CString::new("clicked").unwrap();
+        g_signal_connect(close_button as gpointer,
+                         clicked_c_str.as_ptr(),
+                         Some(mem::transmute(help_close_clicked)),
+                         window as gpointer);
+        let key_press_c_str = rffi::CString::new("key-press-event").unwrap();
+        g_signal_connect(window as gpointer,
+                         key_press_c_str.as_ptr(),
+                         Some(mem::transmute(help_key_pressed)),
+                         ptr::null_mut());
+
+        gtk_widget_show_all(window);
+    }
+}
+
+extern "C" fn help_close_clicked(_button: *mut GtkButton, window: gpointer) {
+    unsafe {
+        gtk_widget_destroy(window as *mut GtkWidget);
+    }
+}
+
+extern "C" fn help_key_pressed(window: *mut GtkWidget, event: *mut GdkEventKey,
+                               _user_data: gpointer) -> gboolean {
+    unsafe {
+        if (*event).keyval == GDK_KEY_Escape as guint {
+            gtk_widget_destroy(window);
+            return 1;
+        }
+    }
+    0
+}
@@ -0,0 +1,457 @@
+// The terminal front end, started with `--tui`. Drives the same AppCore and Player as the GTK
+// window, draws with ANSI escape codes and reads keys from stdin with the terminal put into
+// non-canonical mode by stty. The screen is only cleared when the terminal was resized, other
+// redraws overwrite it in place.
+//
+// Keys: j/k or arrows move, g/G first/last mix, Enter or l plays, Space toggles, n skips,
+// / searches, q quits.
+
+use std::io;
+use std::io::{Read, Write};
+use std::iter;
+use std::process;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use gtk::ffi::*;
+
+use api;
+use app;
+use config;
+use listenlog;
+use network;
+use player;
+use player::{PlaybackBackend, PlayerEvent};
+use scrobbler;
+use utils;
+
+static SIGWINCH: libc::c_int = 28;
+
+extern {
+    fn signal(signum: libc::c_int, handler: extern "C" fn(libc::c_int)) -> libc::size_t;
+}
+
+// Set by the SIGWINCH handler, the main loop then asks stty for the new size.
+static RESIZED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn terminal_resized(_signum: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+enum Message {
+    Key(Key),
+    Network(app::NetworkResult),
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+}
+
+// Puts the terminal into non-canonical, no echo mode and restores it when dropped.
+struct RawTerminal {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = process::Command::new("stty")
+        .args(args)
+        .stdin(process::Stdio::inherit())
+        .output();
+    match output {
+        Ok(ref o) if o.status.success() => {
+            Some(String::from_utf8_lossy(&o.stdout[..]).trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+impl RawTerminal {
+    fn new() -> Option<RawTerminal> {
+        let saved = match stty(&["-g"]) {
+            Some(s) => s,
+            None => return None,
+        };
+        // Reads return after a tenth of a second without input, see read_keys.
+        stty(&["-icanon", "-echo", "min", "0", "time", "1"]);
+        // Alternate screen, hide the cursor.
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        Some(RawTerminal { saved: saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        stty(&[&self.saved[..]]);
+    }
+}
+
+fn terminal_size() -> (usize, usize) {
+    let size = stty(&["size"]).and_then(|s| {
+        let mut parts = s.split(' ').filter_map(|p| p.parse().ok());
+        match (parts.next(), parts.next()) {
+            (Some(rows), Some(cols)) => Some((rows, cols)),
+            _ => None,
+        }
+    });
+    size.unwrap_or((24, 80))
+}
+
+// A byte from stdin, None if nothing came within the terminal's read timeout.
+fn read_byte<R: Read>(stdin: &mut R) -> io::Result<Option<u8>> {
+    let mut buf = [0];
+    match try!(stdin.read(&mut buf)) {
+        0 => Ok(None),
+        _ => Ok(Some(buf[0])),
+    }
+}
+
+// Reads stdin on its own thread and turns the bytes into keys.
+fn read_keys(sender: mpsc::Sender<Message>) {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    // A byte that came after a lone Escape, it's the next key.
+    let mut pending = None;
+    loop {
+        let byte = match pending.take() {
+            Some(b) => b,
+            None => match read_byte(&mut stdin) {
+                Ok(Some(b)) => b,
+                Ok(None) => continue,
+                Err(_) => return,
+            },
+        };
+        let key = match byte {
+            b'\n' | b'\r' => Key::Enter,
+            127 | 8 => Key::Backspace,
+            27 => match read_byte(&mut stdin) {
+                // Arrow keys are ESC [ A and friends, sent all at once. A lone ESC is followed
+                // by nothing until the read times out, or by a key typed afterwards.
+                Ok(Some(b'[')) => match read_byte(&mut stdin) {
+                    Ok(Some(b'A')) => Key::Up,
+                    Ok(Some(b'B')) => Key::Down,
+                    Ok(Some(b'C')) => Key::Char('l'),
+                    Ok(_) => continue,
+                    Err(_) => return,
+                },
+                Ok(Some(b)) => {
+                    pending = Some(b);
+                    Key::Escape
+                }
+                Ok(None) => Key::Escape,
+                Err(_) => return,
+            },
+            b if b < 128 => Key::Char(b as char),
+            _ => continue,
+        };
+        if sender.send(Message::Key(key)).is_err() {
+            return;
+        }
+    }
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
+
+struct Tui {
+    core: app::AppCore<player::Player>,
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Message>,
+    player_receiver: mpsc::Receiver<PlayerEvent>,
+
+    smart_id: String,
+    selected: usize,
+    // First mix shown, for scrolling.
+    scroll: usize,
+    // Some while the search prompt is open.
+    search: Option<String>,
+    status_line: String,
+    // Rows and columns, updated when the terminal is resized.
+    size: (usize, usize),
+    // The next draw starts from a cleared screen.
+    clear: bool,
+    dirty: bool,
+    quit: bool,
+}
+
+impl Tui {
+    fn handle(&mut self, input: app::Input) {
+        for output in self.core.handle(input) {
+            match output {
+                app::Output::Request(r) => {
+                    let sender = self.sender.clone();
+                    network::run(r, move |result| {
+                        let _ = sender.send(Message::Network(result));
+                    });
+                }
+                app::Output::View(app::ViewUpdate::Notify(m)) => self.status_line = m,
+                app::Output::View(app::ViewUpdate::MixesChanged) => {
+                    self.selected = 0;
+                    self.scroll = 0;
+                }
+                app::Output::View(_) => (),
+                app::Output::Listened(l) => listenlog::record(&l),
+            }
+            self.dirty = true;
+        }
+    }
+
+    fn command(&mut self, command: app::Command) {
+        self.handle(app::Input::Command(command));
+    }
+
+    fn key(&mut self, key: Key) {
+        self.dirty = true;
+        if self.search.is_some() {
+            self.search_key(key);
+            return;
+        }
+        let count = self.core.mixes().len();
+        match key {
+            Key::Char('q') => self.quit = true,
+            Key::Char('j') | Key::Down if self.selected + 1 < count => self.selected += 1,
+            Key::Char('k') | Key::Up if self.selected > 0 => self.selected -= 1,
+            Key::Char('g') => self.selected = 0,
+            Key::Char('G') if count > 0 => self.selected = count - 1,
+            Key::Char('l') | Key::Enter if count > 0 => {
+                let i = self.selected;
+                self.command(app::Command::PlayMix(i));
+            }
+            Key::Char(' ') => self.command(app::Command::TogglePlaying),
+            Key::Char('n') => self.command(app::Command::SkipTrack),
+            Key::Char('/') => self.search = Some(String::new()),
+            _ => (),
+        }
+    }
+
+    fn search_key(&mut self, key: Key) {
+        let mut text = self.search.take().unwrap();
+        match key {
+            Key::Enter => {
+                if !text.is_empty() {
+                    // Plain words are tags, anything with a colon is a smart id.
+                    let smart_id = if text.contains(':') {
+                        text
+                    } else {
+                        format!("tags:{}", text.replace(" ", "_"))
+                    };
+                    self.smart_id = smart_id.clone();
+                    self.command(app::Command::GetMixes(smart_id));
+                }
+                return;
+            }
+            Key::Escape => return,
+            Key::Backspace => {
+                text.pop();
+            }
+            Key::Char(c) if !c.is_control() => text.push(c),
+            _ => (),
+        }
+        self.search = Some(text);
+    }
+
+    fn draw(&mut self) {
+        let (rows, cols) = self.size;
+        // Header, separator, three lines now playing, status and help.
+        let list_rows = if rows > 8 { rows - 7 } else { 1 };
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + list_rows {
+            self.scroll = self.selected + 1 - list_rows;
+        }
+
+        // Every line clears what's left of the old one behind it.
+        let mut out = String::new();
+        if self.clear {
+            out.push_str("\x1b[2J");
+            self.clear = false;
+        }
+        out.push_str("\x1b[H");
+        let header = format!(" RustTracks - {}", self.smart_id);
+        out.push_str(&format!("\x1b[7m{:<width$}\x1b[0m\x1b[K\r\n", truncate(&header[..], cols),
+                              width = cols)[..]);
+
+        let mixes = self.core.mixes();
+        for row in 0..list_rows {
+            let i = self.scroll + row;
+            if i < mixes.len() {
+                out.push_str(&self.mix_line(&mixes[i], i == self.selected, cols)[..]);
+            }
+            out.push_str("\x1b[K\r\n");
+        }
+
+        out.push_str(&iter::repeat('-').take(cols).collect::<String>()[..]);
+        out.push_str("\x1b[K\r\n");
+        for line in self.now_playing_lines(cols).iter() {
+            out.push_str(&truncate(&line[..], cols)[..]);
+            out.push_str("\x1b[K\r\n");
+        }
+
+        match self.search {
+            Some(ref text) => out.push_str(&truncate(&format!("/{}_", text)[..], cols)[..]),
+            None => out.push_str(&truncate(&self.status_line[..], cols)[..]),
+        }
+        out.push_str("\x1b[K\r\n");
+        let help = "j/k move  Enter play  Space pause  n skip  / search  q quit";
+        out.push_str(&format!("\x1b[2m{}\x1b[0m\x1b[K\x1b[J", truncate(help, cols))[..]);
+
+        print!("{}", out);
+        let _ = io::stdout().flush();
+        self.dirty = false;
+    }
+
+    fn mix_line(&self, mix: &api::Mix, selected: bool, cols: usize) -> String {
+        let stats = format!(" {:>6} likes {:>7} plays {:>8} {:>3} tracks",
+                            mix.likes_count, mix.plays_count,
+                            utils::format_duration(mix.duration), mix.tracks_count);
+        let name_width = if cols > stats.len() + 3 { cols - stats.len() - 3 } else { 0 };
+        let marker = if selected { "> " } else { "  " };
+        let name = if mix.nsfw { format!("[NSFW] {}", mix.name) } else { mix.name.clone() };
+        let line = format!("{}{:<width$}{}", marker, truncate(&name[..], name_width),
+                           stats, width = name_width);
+        if selected {
+            format!("\x1b[1m{}\x1b[0m", truncate(&line[..], cols))
+        } else {
+            truncate(&line[..], cols)
+        }
+    }
+
+    fn now_playing_lines(&self, cols: usize) -> Vec<String> {
+        let track = match self.core.current_track() {
+            Some(t) => t,
+            None => return vec!["Nothing is playing".to_string(), String::new(), String::new()],
+        };
+        let state = if self.core.is_playing() { "Playing" } else { "Paused" };
+        let mut lines = vec![format!("{}: '{}' by {}", state, track.name, track.performer)];
+
+        let mut second = String::new();
+        match track.release_name.clone().or(self.core.stream_info().album.clone()) {
+            Some(rn) => second.push_str(&rn[..]),
+            None => (),
+        }
+        match track.year.or(self.core.stream_info().year) {
+            Some(year) => second.push_str(&format!(" ({})", year)[..]),
+            None => (),
+        }
+        match self.core.current_mix() {
+            Some(mix) => second.push_str(&format!("  Mix: {}", mix.name)[..]),
+            None => (),
+        }
+        lines.push(second.trim().to_string());
+
+        let progress = match self.core.player().buffering_percent() {
+            Some(percent) => format!("Buffering... {}%", percent),
+            None => match self.core.player().get_progress_info() {
+                Some((pos, dur)) if dur > 0 => {
+                    let (pos_sec, dur_sec) = (pos / 1000000000, dur / 1000000000);
+                    let times = format!(" {}:{:02} / {}:{:02}",
+                                        pos_sec / 60, pos_sec % 60, dur_sec / 60, dur_sec % 60);
+                    let bar_width = if cols > times.len() + 2 { cols - times.len() - 2 } else { 0 };
+                    let filled = (bar_width as i64 * pos / dur) as usize;
+                    let bar: String = (0..bar_width).map(|i| if i < filled { '=' } else { ' ' })
+                                                    .collect();
+                    format!("[{}]{}", bar, times)
+                }
+                _ => String::new(),
+            },
+        };
+        lines.push(progress);
+        lines
+    }
+
+    fn run(&mut self) {
+        let _raw = match RawTerminal::new() {
+            Some(r) => r,
+            // Nothing was played yet, there's nothing to clean up.
+            None => {
+                let _ = writeln!(io::stderr(), "rusttracks: --tui needs a terminal");
+                process::exit(1);
+            }
+        };
+        let key_sender = self.sender.clone();
+        thread::spawn(move || read_keys(key_sender));
+        unsafe {
+            signal(SIGWINCH, terminal_resized);
+        }
+
+        while !self.quit {
+            if RESIZED.swap(false, Ordering::SeqCst) {
+                self.size = terminal_size();
+                self.clear = true;
+                self.dirty = true;
+            }
+            let mut busy = false;
+            // The player's bus watch and timers live in the default main context.
+            unsafe {
+                while g_main_context_iteration(g_main_context_default(), 0) != 0 {
+                    busy = true;
+                }
+            }
+            while let Ok(event) = self.player_receiver.try_recv() {
+                self.handle(app::Input::Player(event));
+                busy = true;
+            }
+            while let Ok(message) = self.receiver.try_recv() {
+                match message {
+                    Message::Key(k) => self.key(k),
+                    Message::Network(r) => self.handle(app::Input::Network(r)),
+                }
+                busy = true;
+            }
+            if self.dirty {
+                self.draw();
+            }
+            if !busy {
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+        // Through the core, so that the current track still makes it into the listening log.
+        self.handle(app::Input::Command(app::Command::Stop));
+    }
+}
+
+/// Runs the terminal front end until the user quits.
+pub fn run(args: Vec<String>, smart_id: Option<String>) {
+    let config = config::Config::load();
+    let (sender, receiver) = mpsc::channel();
+    let (player_sender, player_receiver) = mpsc::channel();
+
+    let mut player = player::Player::new();
+    player.init(args, player_sender);
+    player.set_stall_policy(config.stall_timeout, config.stall_restarts);
+
+    let smart_id = smart_id.unwrap_or_else(|| "tags:folk:recent".to_string());
+    let mut tui = Tui {
+        core: app::AppCore::new(player, config.stream_retries),
+        sender: sender,
+        receiver: receiver,
+        player_receiver: player_receiver,
+        smart_id: smart_id.clone(),
+        selected: 0,
+        scroll: 0,
+        search: None,
+        status_line: "Welcome to RustTracks!".to_string(),
+        size: terminal_size(),
+        clear: true,
+        dirty: true,
+        quit: false,
+    };
+    tui.core.set_scrobble_endpoint(scrobbler::Endpoint::from_config(&config));
+    tui.command(app::Command::SetHideNsfw(config.hide_nsfw));
+    tui.command(app::Command::FetchPlayToken);
+    tui.command(app::Command::GetMixes(smart_id));
+    tui.run();
+}
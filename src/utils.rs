@@ -2,10 +2,39 @@
 
 use std::borrow::ToOwned;
 use std::ffi;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str;
 
 use libc;
 
+use rustc_serialize::json;
+
+/// Replaces the file at `path` with `data`, creating its directory if needed. The data goes to
+/// a temporary file next to it first, which is then renamed over it, so that a crash can't leave
+/// half a file behind.
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => try!(fs::create_dir_all(dir)),
+        None => (),
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = try!(fs::File::create(&tmp_path));
+        try!(file.write_all(data));
+    }
+    fs::rename(&tmp_path, path)
+}
+
+/// For `try!` in functions returning io::Result.
+pub fn encoder_error(err: json::EncoderError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", err))
+}
+
 // Please note that the lifetime of the returned string is a lie. It's probably safer to convert it
 // to a String (or use ptr_to_string directly) if you want to do more than just display it
 // immediately.
@@ -16,3 +45,29 @@ pub unsafe fn ptr_to_str(p: *const libc::c_char) -> &'static str {
 pub unsafe fn ptr_to_string(p: *const libc::c_char) -> String {
     ptr_to_str(p).to_owned()
 }
+
+/// Formats a duration in seconds like `1h 05m` or `42m`.
+pub fn format_duration(seconds: u32) -> String {
+    let minutes = (seconds + 30) / 60;
+    if minutes >= 60 {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// Escapes `text` for use in Pango markup.
+pub fn markup_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
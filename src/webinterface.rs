@@ -1,4 +1,5 @@
 use std::fmt;
+use std::io;
 use std::io::Read;
 use std::str;
 
@@ -46,9 +47,11 @@ impl header::HeaderFormat for ApiKeyHeader {
 }
 
 
-fn make_mixes_url(smart_id: &str) -> url::Url {
-    url::Url::parse(&format!("http://8tracks.com/mix_sets/{}.json?include=mixes[likes_count]",
-                             smart_id)[..]).unwrap()
+fn make_mixes_url(smart_id: &str, safe_browse: bool) -> url::Url {
+    // Smart ids without safe browsing support just ignore the flag.
+    url::Url::parse(&format!("http://8tracks.com/mix_sets/{}.json?include=mixes[likes_count]{}",
+                             smart_id, if safe_browse { "&safe_browse=1" } else { "" })[..])
+        .unwrap()
 }
 
 fn make_play_token_url() -> url::Url {
@@ -75,6 +78,10 @@ fn make_report_url(pt: &api::PlayToken, track_id: u32, mix_id: u32) -> url::Url
                              pt.s, track_id, mix_id)[..]).unwrap()
 }
 
+fn make_fav_track_url(track_id: u32) -> url::Url {
+    url::Url::parse(&format!("http://8tracks.com/tracks/{}/fav.json", track_id)[..]).unwrap()
+}
+
 pub fn get_data_from_url_str(s: &str) -> hyper::HttpResult<Vec<u8>> {
     let u = url::Url::parse(s).unwrap();
     get_data_from_url(u)
@@ -101,8 +108,28 @@ fn get_json_from_url(u: url::Url) -> hyper::HttpResult<json::Json> {
     Ok(json::Json::from_str(s).unwrap())
 }
 
-pub fn get_mix_set(smart_id: &str) -> hyper::HttpResult<json::Json> {
-    get_json_from_url(make_mixes_url(smart_id))
+fn post_to_url(u: url::Url) -> hyper::HttpResult<json::Json> {
+    debug!("posting to `{}`", u);
+    let mut client = hyper::Client::new();
+    let mut response = try!(client.post(u)
+                            .header(ApiVersionHeader)
+                            .header(ApiKeyHeader)
+                            .send());
+    let mut data = String::new();
+    match response.read_to_string(&mut data) {
+        Ok(_) => (),
+        Err(io_err) => return Err(hyper::HttpError::HttpIoError(io_err)),
+    }
+    debug!("got data: {}", data);
+    // Error pages aren't always json.
+    json::Json::from_str(&data[..]).map_err(|err| {
+        hyper::HttpError::HttpIoError(io::Error::new(io::ErrorKind::InvalidData,
+                                                     format!("not json: {}", err)))
+    })
+}
+
+pub fn get_mix_set(smart_id: &str, safe_browse: bool) -> hyper::HttpResult<json::Json> {
+    get_json_from_url(make_mixes_url(smart_id, safe_browse))
 }
 
 pub fn get_play_token() -> hyper::HttpResult<json::Json> {
@@ -126,3 +153,7 @@ pub fn report_track(pt: &api::PlayToken, track_id: u32, mix_id: u32) {
     let resp = get_json_from_url(make_report_url(pt, track_id, mix_id));
     debug!("reported track, response was {:?}", resp);
 }
+
+pub fn fav_track(track_id: u32) -> hyper::HttpResult<json::Json> {
+    post_to_url(make_fav_track_url(track_id))
+}